@@ -0,0 +1,678 @@
+// Decides what a failed background write means for the DB and gets it out
+// of that state again.
+//
+// Every error from a flush, compaction, WAL sync or MANIFEST write is handed
+// to set_bg_error, which maps it to a Severity; a log_writer::Writer given a
+// handler does this for its own appends and syncs. From a hard error on, the DB
+// is read only: check_write returns the error to every writer until the DB
+// is resumed. Errors caused by running out of space are expected to go away
+// once space is freed, so set_bg_error starts a thread that retries them
+// with the function given to set_resume_fn.
+
+use crate::util::status::{Code, Error, Severity, SubCode};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// The background operation an error came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackgroundErrorReason {
+    Flush,
+    Compaction,
+    WalSync,
+    ManifestWrite,
+}
+
+// Told about background errors and recovery from them. All methods default
+// to doing nothing.
+pub trait EventListener: Send + Sync {
+    fn on_background_error(
+        &self,
+        _reason: BackgroundErrorReason,
+        _error: &Error,
+        _severity: Severity,
+    ) {
+    }
+
+    // Called before an automatic recovery starts. Setting *auto_recovery to
+    // false leaves the DB stopped until it is resumed by hand.
+    fn on_error_recovery_begin(
+        &self,
+        _reason: BackgroundErrorReason,
+        _error: &Error,
+        _auto_recovery: &mut bool,
+    ) {
+    }
+
+    // Called once the DB accepts writes again after an error.
+    fn on_error_recovery_completed(&self, _error: &Error) {}
+}
+
+#[derive(Clone)]
+pub struct ErrorHandlerOptions {
+    // Treat errors that may mean lost or corrupted data as unrecoverable
+    // instead of stopping only the background work that hit them.
+    pub paranoid_checks: bool,
+    // How many times the recovery thread retries an error that can clear up
+    // by itself before leaving the DB stopped.
+    pub max_bgerror_resume_count: u32,
+    // Microseconds the recovery thread waits before each of those retries.
+    pub bgerror_resume_retry_interval: u64,
+    pub listeners: Vec<Arc<dyn EventListener>>,
+}
+
+impl Default for ErrorHandlerOptions {
+    fn default() -> ErrorHandlerOptions {
+        ErrorHandlerOptions {
+            paranoid_checks: true,
+            max_bgerror_resume_count: 10,
+            bgerror_resume_retry_interval: 1_000_000,
+            listeners: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Debug for ErrorHandlerOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ErrorHandlerOptions")
+            .field("paranoid_checks", &self.paranoid_checks)
+            .field("max_bgerror_resume_count", &self.max_bgerror_resume_count)
+            .field(
+                "bgerror_resume_retry_interval",
+                &self.bgerror_resume_retry_interval,
+            )
+            .field("listeners", &self.listeners.len())
+            .finish()
+    }
+}
+
+// The severity of error when it comes from reason.
+pub fn error_severity(
+    reason: BackgroundErrorReason,
+    error: &Error,
+    paranoid_checks: bool,
+) -> Severity {
+    match (error.code(), error.subcode()) {
+        (Code::KCorruption, _) if paranoid_checks => Severity::UnrecoverableError,
+        (Code::KCorruption, _) => Severity::HardError,
+        // A failed compaction leaves its inputs in place, so writes can go on
+        // until the disk is really full.
+        (Code::KIOError, SubCode::NoSpace) if reason == BackgroundErrorReason::Compaction => {
+            Severity::SoftError
+        }
+        (Code::KIOError, SubCode::NoSpace) => Severity::HardError,
+        // A MANIFEST that may be half written can't be appended to again.
+        (Code::KIOError, _) if reason == BackgroundErrorReason::ManifestWrite => {
+            Severity::FatalError
+        }
+        // Synced WAL data may be gone, so later writes could be acknowledged
+        // and then lost.
+        (Code::KIOError, _) if reason == BackgroundErrorReason::WalSync => Severity::HardError,
+        (Code::KIOError, _) if reason == BackgroundErrorReason::Flush => Severity::HardError,
+        (Code::KIOError, _) if paranoid_checks => Severity::HardError,
+        _ => Severity::SoftError,
+    }
+}
+
+// Redoes the work a background error interrupted, e.g. syncs the WAL or
+// reruns the flush.
+pub type ResumeFn = Arc<dyn Fn() -> Result<(), Error> + Send + Sync>;
+
+#[derive(Debug)]
+struct BgError {
+    // Tells this error apart from any later one set in its place.
+    seq_: u64,
+    error_: Error,
+    reason_: BackgroundErrorReason,
+    severity_: Severity,
+    auto_recovery_: bool,
+}
+
+struct HandlerState {
+    bg_error_: Option<BgError>,
+    resume_: Option<ResumeFn>,
+    // Whether the recovery thread is running.
+    recovering_: bool,
+    // Set once the handler is dropped; the recovery thread then stops.
+    closing_: bool,
+}
+
+// What the ErrorHandler shares with its recovery thread.
+struct Shared {
+    options_: ErrorHandlerOptions,
+    state_: Mutex<HandlerState>,
+    // Wakes the recovery thread early when the handler is dropped.
+    closing_cv_: Condvar,
+    next_seq_: AtomicU64,
+    recovery_thread_: Mutex<Option<JoinHandle<()>>>,
+}
+
+pub struct ErrorHandler {
+    shared_: Arc<Shared>,
+}
+
+impl fmt::Debug for ErrorHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.shared_.state_.lock().unwrap();
+        f.debug_struct("ErrorHandler")
+            .field("options", &self.shared_.options_)
+            .field("bg_error", &state.bg_error_)
+            .field("recovering", &state.recovering_)
+            .finish()
+    }
+}
+
+impl Drop for ErrorHandler {
+    fn drop(&mut self) {
+        self.shared_.state_.lock().unwrap().closing_ = true;
+        self.shared_.closing_cv_.notify_all();
+        let handle = self.shared_.recovery_thread_.lock().unwrap().take();
+        if let Some(handle) = handle {
+            // A listener run by the recovery thread may drop the last handle.
+            if handle.thread().id() != thread::current().id() {
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+impl ErrorHandler {
+    pub fn new(options: ErrorHandlerOptions) -> ErrorHandler {
+        ErrorHandler {
+            shared_: Arc::new(Shared {
+                options_: options,
+                state_: Mutex::new(HandlerState {
+                    bg_error_: None,
+                    resume_: None,
+                    recovering_: false,
+                    closing_: false,
+                }),
+                closing_cv_: Condvar::new(),
+                next_seq_: AtomicU64::new(0),
+                recovery_thread_: Mutex::new(None),
+            }),
+        }
+    }
+
+    // Set the function the recovery thread retries. Without one, errors are
+    // only cleared by resume.
+    pub fn set_resume_fn(&self, resume: ResumeFn) {
+        self.shared_.state_.lock().unwrap().resume_ = Some(resume);
+    }
+
+    // Record error from a background operation and return its severity. An
+    // error is kept only if it is more severe than the one already kept. A
+    // kept error that can clear up by itself is retried in the background;
+    // this call doesn't wait for it.
+    pub fn set_bg_error(&self, error: Error, reason: BackgroundErrorReason) -> Severity {
+        self.shared_.set_bg_error(error, reason)
+    }
+
+    pub fn get_bg_error(&self) -> Option<Error> {
+        let state = self.shared_.state_.lock().unwrap();
+        state.bg_error_.as_ref().map(|e| e.error_.clone())
+    }
+
+    pub fn severity(&self) -> Severity {
+        let state = self.shared_.state_.lock().unwrap();
+        match state.bg_error_ {
+            Some(ref e) => e.severity_,
+            None => Severity::NoError,
+        }
+    }
+
+    // Whether flushes and compactions should stop being scheduled.
+    pub fn is_bg_work_stopped(&self) -> bool {
+        self.severity() != Severity::NoError
+    }
+
+    // Whether the DB is read only.
+    pub fn is_db_stopped(&self) -> bool {
+        self.severity() >= Severity::HardError
+    }
+
+    // Whether the recovery thread is still retrying the error.
+    pub fn is_auto_recovery_pending(&self) -> bool {
+        let state = self.shared_.state_.lock().unwrap();
+        state.bg_error_.as_ref().is_some_and(|e| e.auto_recovery_)
+    }
+
+    // Writers call this first; it fails with the background error while the
+    // DB is read only.
+    pub fn check_write(&self) -> Result<(), Error> {
+        let state = self.shared_.state_.lock().unwrap();
+        match state.bg_error_ {
+            Some(ref e) if e.severity_ >= Severity::HardError => Err(e.error_.clone()),
+            _ => Ok(()),
+        }
+    }
+
+    // Try once to clear the background error by calling resume, without
+    // waiting for the recovery thread. Fatal and unrecoverable errors are
+    // returned as is.
+    pub fn resume<F>(&self, resume: F) -> Result<(), Error>
+    where
+        F: FnOnce() -> Result<(), Error>,
+    {
+        let (seq, error, reason) = {
+            let state = self.shared_.state_.lock().unwrap();
+            match state.bg_error_ {
+                None => return Ok(()),
+                Some(ref e) if e.severity_ >= Severity::FatalError => {
+                    return Err(e.error_.clone());
+                }
+                Some(ref e) => (e.seq_, e.error_.clone(), e.reason_),
+            }
+        };
+        self.shared_.finish_resume(resume(), seq, &error, reason)
+    }
+}
+
+impl Shared {
+    fn set_bg_error(self: &Arc<Self>, error: Error, reason: BackgroundErrorReason) -> Severity {
+        let severity = error_severity(reason, &error, self.options_.paranoid_checks);
+        for listener in &self.options_.listeners {
+            listener.on_background_error(reason, &error, severity);
+        }
+        let seq = self.next_seq_.fetch_add(1, Ordering::Relaxed);
+        let can_resume = {
+            let mut state = self.state_.lock().unwrap();
+            if let Some(ref current) = state.bg_error_ {
+                if current.severity_ >= severity {
+                    return current.severity_;
+                }
+            }
+            state.bg_error_ = Some(BgError {
+                seq_: seq,
+                error_: error.clone(),
+                reason_: reason,
+                severity_: severity,
+                auto_recovery_: false,
+            });
+            state.resume_.is_some()
+        };
+        let mut auto_recovery =
+            can_resume && error.subcode() == SubCode::NoSpace && severity <= Severity::HardError;
+        if auto_recovery {
+            // Listeners are called without the lock so they can call back
+            // into the handler.
+            for listener in &self.options_.listeners {
+                listener.on_error_recovery_begin(reason, &error, &mut auto_recovery);
+            }
+            let mut state = self.state_.lock().unwrap();
+            let current = state.bg_error_.as_mut().filter(|e| e.seq_ == seq);
+            if let Some(current) = current {
+                current.auto_recovery_ = auto_recovery;
+                // A running thread picks up the new error by itself.
+                if auto_recovery && !state.recovering_ && !state.closing_ {
+                    state.recovering_ = true;
+                    let shared = self.clone();
+                    let handle = thread::spawn(move || shared.recovery_loop());
+                    *self.recovery_thread_.lock().unwrap() = Some(handle);
+                }
+            }
+        }
+        severity
+    }
+
+    // Retry the kept error while it is marked for automatic recovery, up to
+    // max_bgerror_resume_count times in all.
+    fn recovery_loop(self: Arc<Self>) {
+        let interval = Duration::from_micros(self.options_.bgerror_resume_retry_interval);
+        let mut state = self.state_.lock().unwrap();
+        for _ in 0..self.options_.max_bgerror_resume_count {
+            let deadline = Instant::now() + interval;
+            while !state.closing_ {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                state = self
+                    .closing_cv_
+                    .wait_timeout(state, deadline - now)
+                    .unwrap()
+                    .0;
+            }
+            let (seq, error, reason) = match state.bg_error_ {
+                Some(ref e) if e.auto_recovery_ && !state.closing_ => {
+                    (e.seq_, e.error_.clone(), e.reason_)
+                }
+                _ => break,
+            };
+            let resume = state.resume_.clone().unwrap();
+            drop(state);
+            let _ = self.finish_resume(resume(), seq, &error, reason);
+            state = self.state_.lock().unwrap();
+        }
+        // Out of retries, or the error is gone or no longer retried. A DB
+        // still stopped now stays so until it is resumed by hand.
+        if let Some(ref mut e) = state.bg_error_ {
+            e.auto_recovery_ = false;
+        }
+        state.recovering_ = false;
+    }
+
+    // Clear the error numbered seq if the attempt s to resume from it worked.
+    fn finish_resume(
+        self: &Arc<Self>,
+        s: Result<(), Error>,
+        seq: u64,
+        error: &Error,
+        reason: BackgroundErrorReason,
+    ) -> Result<(), Error> {
+        if let Err(e) = s {
+            // Only a lack of space is worth waiting out; anything else the
+            // resume hit takes over if it is worse.
+            if e.subcode() != SubCode::NoSpace {
+                self.set_bg_error(e.clone(), reason);
+            }
+            return Err(e);
+        }
+        {
+            // resume only fixed the error it started from; one set in the
+            // meantime stays.
+            let mut state = self.state_.lock().unwrap();
+            match state.bg_error_ {
+                Some(ref e) if e.seq_ != seq => return Err(e.error_.clone()),
+                _ => state.bg_error_ = None,
+            }
+        }
+        for listener in &self.options_.listeners {
+            listener.on_error_recovery_completed(error);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct CountingListener {
+    errors_: Mutex<Vec<(BackgroundErrorReason, Severity)>>,
+    recoveries_begun_: Mutex<u32>,
+    recoveries_completed_: Mutex<u32>,
+    disable_auto_recovery_: bool,
+}
+
+#[cfg(test)]
+impl EventListener for CountingListener {
+    fn on_background_error(
+        &self,
+        reason: BackgroundErrorReason,
+        _error: &Error,
+        severity: Severity,
+    ) {
+        self.errors_.lock().unwrap().push((reason, severity));
+    }
+
+    fn on_error_recovery_begin(
+        &self,
+        _reason: BackgroundErrorReason,
+        _error: &Error,
+        auto_recovery: &mut bool,
+    ) {
+        *self.recoveries_begun_.lock().unwrap() += 1;
+        if self.disable_auto_recovery_ {
+            *auto_recovery = false;
+        }
+    }
+
+    fn on_error_recovery_completed(&self, _error: &Error) {
+        *self.recoveries_completed_.lock().unwrap() += 1;
+    }
+}
+
+#[test]
+fn test_error_severity() {
+    use std::io;
+
+    let no_space = Error::io_error("append", io::Error::from_raw_os_error(libc::ENOSPC));
+    let eio = Error::io_error("sync", io::Error::from_raw_os_error(libc::EIO));
+    let corruption = Error::Corruption("bad block".to_string());
+    let cases = [
+        (
+            BackgroundErrorReason::Compaction,
+            &no_space,
+            Severity::SoftError,
+        ),
+        (BackgroundErrorReason::Flush, &no_space, Severity::HardError),
+        (BackgroundErrorReason::WalSync, &eio, Severity::HardError),
+        (
+            BackgroundErrorReason::ManifestWrite,
+            &eio,
+            Severity::FatalError,
+        ),
+        (BackgroundErrorReason::Compaction, &eio, Severity::HardError),
+        (
+            BackgroundErrorReason::Flush,
+            &corruption,
+            Severity::UnrecoverableError,
+        ),
+    ];
+    for &(reason, error, severity) in cases.iter() {
+        assert_eq!(error_severity(reason, error, true), severity);
+    }
+    assert_eq!(
+        error_severity(BackgroundErrorReason::Compaction, &eio, false),
+        Severity::SoftError
+    );
+    assert_eq!(
+        error_severity(BackgroundErrorReason::Flush, &corruption, false),
+        Severity::HardError
+    );
+}
+
+#[test]
+fn test_error_handler_wal_sync() {
+    use crate::db::log_writer::Writer;
+    use crate::env::fault_injection_env::{FaultInjectionEnv, FaultOp};
+    use crate::env::mem_env::MemEnv;
+    use crate::env::{Env, EnvOptions};
+    use crate::util::file_reader_writer::WritableFileWriter;
+
+    let env = FaultInjectionEnv::new(MemEnv::new());
+    let listener = Arc::new(CountingListener::default());
+    let options = ErrorHandlerOptions {
+        listeners: vec![listener.clone()],
+        ..Default::default()
+    };
+    let handler = Arc::new(ErrorHandler::new(options));
+
+    let op = EnvOptions::default();
+    let fd = env.new_writable_file("wal", op.clone()).unwrap();
+    let mut wal = Writer::new(WritableFileWriter::new(fd, op), 0, false, true);
+    wal.set_error_handler(handler.clone());
+    assert!(wal.add_record(vec![1, 2, 3]).is_ok());
+    env.inject_error(FaultOp::Sync, env.call_count(FaultOp::Sync) + 1);
+    assert!(wal.sync(false).is_err());
+
+    // A failed WAL sync makes the DB read only.
+    assert_eq!(handler.severity(), Severity::HardError);
+    assert!(handler.is_db_stopped());
+    assert!(!handler.is_auto_recovery_pending());
+    assert_eq!(handler.check_write().unwrap_err().code(), Code::KIOError);
+    assert_eq!(
+        wal.add_record(vec![4, 5, 6]).unwrap_err().code(),
+        Code::KIOError
+    );
+    // A less severe error doesn't replace it.
+    let e = Error::io_error(
+        "compaction",
+        std::io::Error::from_raw_os_error(libc::ENOSPC),
+    );
+    assert_eq!(
+        handler.set_bg_error(e, BackgroundErrorReason::Compaction),
+        Severity::HardError
+    );
+    assert_eq!(
+        *listener.errors_.lock().unwrap(),
+        vec![
+            (BackgroundErrorReason::WalSync, Severity::HardError),
+            (BackgroundErrorReason::Compaction, Severity::SoftError),
+        ]
+    );
+
+    // Resuming by hand syncs the WAL again and reopens the DB for writes.
+    assert!(wal.resume().is_ok());
+    assert!(handler.check_write().is_ok());
+    assert!(!handler.is_bg_work_stopped());
+    assert!(handler.get_bg_error().is_none());
+    assert_eq!(*listener.recoveries_completed_.lock().unwrap(), 1);
+    assert!(wal.add_record(vec![4, 5, 6]).is_ok());
+}
+
+#[test]
+fn test_error_handler_concurrent_errors() {
+    use std::io;
+
+    let no_space = || Error::io_error("flush", io::Error::from_raw_os_error(libc::ENOSPC));
+    let eio = || Error::io_error("write", io::Error::from_raw_os_error(libc::EIO));
+
+    // Calls back into the handler when told about an error.
+    #[derive(Default)]
+    struct ReentrantListener {
+        handler_: Mutex<Option<Arc<ErrorHandler>>>,
+        severities_: Mutex<Vec<Severity>>,
+    }
+
+    impl EventListener for ReentrantListener {
+        fn on_error_recovery_begin(
+            &self,
+            _reason: BackgroundErrorReason,
+            _error: &Error,
+            _auto_recovery: &mut bool,
+        ) {
+            let handler = self.handler_.lock().unwrap().clone().unwrap();
+            self.severities_.lock().unwrap().push(handler.severity());
+        }
+    }
+
+    let listener = Arc::new(ReentrantListener::default());
+    let options = ErrorHandlerOptions {
+        // Keep the recovery thread out of the way.
+        bgerror_resume_retry_interval: 3_600_000_000,
+        listeners: vec![listener.clone()],
+        ..Default::default()
+    };
+    let handler = Arc::new(ErrorHandler::new(options));
+    handler.set_resume_fn(Arc::new(|| Ok(())));
+    *listener.handler_.lock().unwrap() = Some(handler.clone());
+
+    handler.set_bg_error(no_space(), BackgroundErrorReason::Flush);
+    assert_eq!(
+        *listener.severities_.lock().unwrap(),
+        vec![Severity::HardError]
+    );
+    assert!(handler.is_auto_recovery_pending());
+
+    // A MANIFEST write fails while the flush is being redone. The flush
+    // succeeding doesn't clear the newer, fatal error.
+    let s = handler.resume(|| {
+        handler.set_bg_error(eio(), BackgroundErrorReason::ManifestWrite);
+        Ok(())
+    });
+    assert_eq!(s.unwrap_err().code(), Code::KIOError);
+    assert_eq!(handler.severity(), Severity::FatalError);
+    assert!(handler.check_write().is_err());
+    *listener.handler_.lock().unwrap() = None;
+}
+
+// Wait for the recovery thread to stop retrying.
+#[cfg(test)]
+fn wait_for_recovery(handler: &ErrorHandler) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while handler.is_auto_recovery_pending() {
+        assert!(Instant::now() < deadline, "recovery thread is stuck");
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn test_error_handler_auto_recovery() {
+    use std::io;
+    use std::sync::atomic::AtomicU32;
+
+    let no_space = || Error::io_error("flush", io::Error::from_raw_os_error(libc::ENOSPC));
+    let listener = Arc::new(CountingListener::default());
+    let options = ErrorHandlerOptions {
+        max_bgerror_resume_count: 5,
+        bgerror_resume_retry_interval: 1000,
+        listeners: vec![listener.clone()],
+        ..Default::default()
+    };
+
+    // Without a resume function there is nothing to retry with.
+    let handler = ErrorHandler::new(options.clone());
+    handler.set_bg_error(no_space(), BackgroundErrorReason::Flush);
+    assert!(!handler.is_auto_recovery_pending());
+    assert!(handler.is_db_stopped());
+
+    // Space frees up on the third attempt, and the DB recovers without being
+    // resumed by hand.
+    let handler = ErrorHandler::new(options.clone());
+    let attempts = Arc::new(AtomicU32::new(0));
+    let counter = attempts.clone();
+    handler.set_resume_fn(Arc::new(move || {
+        if counter.fetch_add(1, Ordering::SeqCst) < 2 {
+            Err(no_space())
+        } else {
+            Ok(())
+        }
+    }));
+    assert_eq!(
+        handler.set_bg_error(no_space(), BackgroundErrorReason::Flush),
+        Severity::HardError
+    );
+    assert_eq!(*listener.recoveries_begun_.lock().unwrap(), 1);
+    wait_for_recovery(&handler);
+    assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    assert!(handler.check_write().is_ok());
+    assert!(handler.get_bg_error().is_none());
+    assert_eq!(*listener.recoveries_completed_.lock().unwrap(), 1);
+
+    // Retries stop after max_bgerror_resume_count and the DB stays stopped.
+    let handler = ErrorHandler::new(options.clone());
+    let attempts = Arc::new(AtomicU32::new(0));
+    let counter = attempts.clone();
+    handler.set_resume_fn(Arc::new(move || {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Err(no_space())
+    }));
+    handler.set_bg_error(no_space(), BackgroundErrorReason::Flush);
+    wait_for_recovery(&handler);
+    assert_eq!(attempts.load(Ordering::SeqCst), 5);
+    assert!(handler.is_db_stopped());
+
+    // Resuming by hand tries once and doesn't wait.
+    let mut tries = 0;
+    let s = handler.resume(|| {
+        tries += 1;
+        Err(no_space())
+    });
+    assert_eq!(s.unwrap_err().subcode(), SubCode::NoSpace);
+    assert_eq!(tries, 1);
+
+    // A different error while resuming takes over.
+    let s = handler.resume(|| Err(Error::Corruption("lost".to_string())));
+    assert_eq!(s.unwrap_err().code(), Code::KCorruption);
+    assert_eq!(handler.severity(), Severity::UnrecoverableError);
+    assert_eq!(
+        handler.resume(|| Ok(())).unwrap_err().code(),
+        Code::KCorruption
+    );
+
+    // A listener can turn automatic recovery off.
+    let listener = Arc::new(CountingListener {
+        disable_auto_recovery_: true,
+        ..Default::default()
+    });
+    let handler = ErrorHandler::new(ErrorHandlerOptions {
+        listeners: vec![listener.clone()],
+        ..options
+    });
+    handler.set_resume_fn(Arc::new(|| Ok(())));
+    handler.set_bg_error(no_space(), BackgroundErrorReason::Flush);
+    assert!(!handler.is_auto_recovery_pending());
+    assert!(handler.is_db_stopped());
+}
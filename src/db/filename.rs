@@ -0,0 +1,308 @@
+// File names used by a database, and helpers that create them durably.
+//
+// A new file survives a crash only once both its data and its directory
+// entry have been synced, so the helpers below fsync the directory after
+// creating a WAL or MANIFEST file and after switching CURRENT.
+
+use crate::env::{preallocation_block_size, Directory, Env, EnvOptions, WritableFile};
+use crate::util::file_reader_writer::WritableFileWriter;
+use crate::util::status::Error;
+
+fn make_file_name(dbname: &str, number: u64, suffix: &str) -> String {
+    format!("{}/{:06}.{}", dbname, number, suffix)
+}
+
+// Return the name of the log file with the specified number in the db named
+// by "dbname". The result will be prefixed with "dbname".
+pub fn log_file_name(dbname: &str, number: u64) -> String {
+    assert!(number > 0);
+    make_file_name(dbname, number, "log")
+}
+
+// Return the name of the table file with the specified number in the db
+// named by "dbname". The result will be prefixed with "dbname".
+pub fn table_file_name(dbname: &str, number: u64) -> String {
+    assert!(number > 0);
+    make_file_name(dbname, number, "sst")
+}
+
+// Return the name of the descriptor file for the db named by "dbname" and
+// the specified incarnation number.
+pub fn descriptor_file_name(dbname: &str, number: u64) -> String {
+    assert!(number > 0);
+    format!("{}/MANIFEST-{:06}", dbname, number)
+}
+
+// Return the name of the current file. This file contains the name of the
+// current manifest file.
+pub fn current_file_name(dbname: &str) -> String {
+    format!("{}/CURRENT", dbname)
+}
+
+// Return the name of a temporary file owned by the db named "dbname".
+pub fn temp_file_name(dbname: &str, number: u64) -> String {
+    assert!(number > 0);
+    make_file_name(dbname, number, "dbtmp")
+}
+
+// Create the log file fname, with options optimized for log writes and space
+// preallocated for expected_file_size bytes, and fsync dir, the directory
+// fname is in, so the new file is not lost in a crash once its data is
+// synced.
+fn new_durable_log_file<E: Env>(
+    env: &E,
+    fname: &str,
+    dir: &E::Directory,
+    expected_file_size: usize,
+    options: EnvOptions,
+) -> Result<WritableFileWriter<E::WritableFile>, Error> {
+    let options = options.optimize_for_log_write();
+    let mut file = env.new_writable_file(fname, options.clone())?;
+    file.set_preallocation_block_size(preallocation_block_size(expected_file_size));
+    if let Err(e) = dir.fsync() {
+        drop(file);
+        let _ = env.delete_file(fname);
+        return Err(e);
+    }
+    Ok(WritableFileWriter::new(file, options))
+}
+
+// Create the WAL file with the specified number, preallocated for
+// options.max_log_file_size bytes. dir must be the directory the db's WAL
+// files live in.
+pub fn new_log_file<E: Env>(
+    env: &E,
+    dbname: &str,
+    number: u64,
+    dir: &E::Directory,
+    options: EnvOptions,
+) -> Result<WritableFileWriter<E::WritableFile>, Error> {
+    let expected_file_size = options.max_log_file_size;
+    new_durable_log_file(
+        env,
+        &log_file_name(dbname, number),
+        dir,
+        expected_file_size,
+        options,
+    )
+}
+
+// Create the MANIFEST file with the specified number when rolling over to a
+// new manifest. It becomes the current one through set_current_file.
+pub fn new_descriptor_file<E: Env>(
+    env: &E,
+    dbname: &str,
+    number: u64,
+    dir: &E::Directory,
+    options: EnvOptions,
+) -> Result<WritableFileWriter<E::WritableFile>, Error> {
+    new_durable_log_file(env, &descriptor_file_name(dbname, number), dir, 0, options)
+}
+
+// Create the table file with the specified number, preallocated for
+// expected_file_size bytes: the write buffer size for a flush, the target
+// file size for a compaction. Its directory is fsynced once the table is
+// complete and added to the MANIFEST.
+pub fn new_table_file<E: Env>(
+    env: &E,
+    dbname: &str,
+    number: u64,
+    expected_file_size: usize,
+    options: EnvOptions,
+) -> Result<WritableFileWriter<E::WritableFile>, Error> {
+    let mut file = env.new_writable_file(&table_file_name(dbname, number), options.clone())?;
+    file.set_preallocation_block_size(preallocation_block_size(expected_file_size));
+    Ok(WritableFileWriter::new(file, options))
+}
+
+// Small files written in one go, like CURRENT, use the MANIFEST's options:
+// no mmap or direct I/O, which would need the data padded to a page.
+fn write_string_to_file<E: Env>(
+    env: &E,
+    data: &[u8],
+    fname: &str,
+    should_sync: bool,
+    options: EnvOptions,
+) -> Result<(), Error> {
+    let mut file = env.new_writable_file(fname, options.optimize_for_log_write())?;
+    let mut s = file.append(data.to_vec());
+    if s.is_ok() && should_sync {
+        s = file.sync();
+    }
+    let s = s.and(file.close());
+    if s.is_err() {
+        let _ = env.delete_file(fname);
+    }
+    s
+}
+
+// Make the file with the specified descriptor number the current manifest.
+// CURRENT is replaced atomically through a synced temporary file and a
+// rename, and dir is fsynced so the switch survives a crash.
+pub fn set_current_file<E: Env>(
+    env: &E,
+    dbname: &str,
+    descriptor_number: u64,
+    dir: &E::Directory,
+    options: EnvOptions,
+) -> Result<(), Error> {
+    let manifest = descriptor_file_name(dbname, descriptor_number);
+    let contents = format!("{}\n", &manifest[dbname.len() + 1..]);
+    let tmp = temp_file_name(dbname, descriptor_number);
+    let s = write_string_to_file(env, contents.as_bytes(), &tmp, true, options)
+        .and_then(|()| env.rename_file(&tmp, &current_file_name(dbname)));
+    if s.is_err() {
+        let _ = env.delete_file(&tmp);
+        return s;
+    }
+    dir.fsync()
+}
+
+// Read the name of the current manifest, relative to dbname, from CURRENT.
+pub fn read_current_file<E: Env>(env: &E, dbname: &str) -> Result<String, Error> {
+    use crate::env::SequentialFile;
+
+    let fname = current_file_name(dbname);
+    let mut file = env.new_sequential_file(&fname, EnvOptions::default())?;
+    let mut data = Vec::new();
+    let mut scratch = Vec::new();
+    loop {
+        let len = data.len();
+        file.read(4096, &mut data, &mut scratch)?;
+        if data.len() == len {
+            break;
+        }
+    }
+    if data.last() != Some(&b'\n') {
+        return Err(Error::Corruption(format!(
+            "CURRENT file does not end with newline: {}",
+            fname
+        )));
+    }
+    data.pop();
+    String::from_utf8(data)
+        .map_err(|_| Error::Corruption(format!("CURRENT file is not valid UTF-8: {}", fname)))
+}
+
+#[test]
+fn test_file_names() {
+    assert_eq!(log_file_name("db", 7), "db/000007.log");
+    assert_eq!(table_file_name("db", 8), "db/000008.sst");
+    assert_eq!(descriptor_file_name("db", 12), "db/MANIFEST-000012");
+    assert_eq!(current_file_name("db"), "db/CURRENT");
+    assert_eq!(temp_file_name("db", 3), "db/000003.dbtmp");
+}
+
+#[test]
+fn test_log_file_bytes_per_sync() {
+    use crate::db::log_writer::Writer;
+    use crate::env::fault_injection_env::{FaultInjectionEnv, FaultOp};
+    use crate::env::mem_env::MemEnv;
+
+    let env = FaultInjectionEnv::new(MemEnv::new());
+    let dir = env.new_directory("db").unwrap();
+    let options = EnvOptions {
+        bytes_per_sync: 1 << 20,
+        wal_bytes_per_sync: 256 << 10,
+        ..Default::default()
+    };
+
+    // Write 4MB of records, flushing after each, and count the range syncs.
+    let range_syncs = |file: WritableFileWriter<_>| {
+        let before = env.call_count(FaultOp::RangeSync);
+        let mut log = Writer::new(file, 1, false, true);
+        for i in 0..128u8 {
+            assert!(log.add_record(vec![i; 32 << 10]).is_ok());
+        }
+        env.call_count(FaultOp::RangeSync) - before
+    };
+
+    // The WAL and the MANIFEST are written back every wal_bytes_per_sync,
+    // other files every bytes_per_sync, both staying 1MB behind the end.
+    let wal = new_log_file(&env, "db", 1, &dir, options.clone()).unwrap();
+    let manifest = new_descriptor_file(&env, "db", 2, &dir, options.clone()).unwrap();
+    let other = env.new_writable_file("db/other", options.clone()).unwrap();
+    assert_eq!(range_syncs(wal), 12);
+    assert_eq!(range_syncs(manifest), 12);
+    assert_eq!(range_syncs(WritableFileWriter::new(other, options)), 3);
+}
+
+#[test]
+fn test_new_file_preallocation() {
+    use crate::env::env_posix::PosixEnv;
+
+    let env = PosixEnv::new();
+    let path = std::env::temp_dir().join(format!(
+        "test_new_file_preallocation_{}",
+        std::process::id()
+    ));
+    let dbname = path.to_str().unwrap();
+    assert!(env.create_dir_if_missing(dbname).is_ok());
+    let dir = env.new_directory(dbname).unwrap();
+    let mut options = EnvOptions {
+        max_log_file_size: 1 << 20,
+        ..Default::default()
+    };
+
+    // The WAL is preallocated for max_log_file_size, the table for the size
+    // it is expected to reach, each in a single block.
+    let check = |mut file: WritableFileWriter<Box<dyn WritableFile>>, expected_file_size: usize| {
+        assert_eq!(file.writable_file().get_preallocation_status().1, 0);
+        assert!(file.append(vec![1; 100 << 10]).is_ok());
+        assert!(file.append(vec![2; 800 << 10]).is_ok());
+        assert_eq!(
+            file.writable_file().get_preallocation_status(),
+            (preallocation_block_size(expected_file_size), 1)
+        );
+        assert!(file.close().is_ok());
+    };
+    check(
+        new_log_file(&env, dbname, 1, &dir, options.clone()).unwrap(),
+        1 << 20,
+    );
+    options.use_mmap_writes = false;
+    check(
+        new_table_file(&env, dbname, 2, 4 << 20, options.clone()).unwrap(),
+        4 << 20,
+    );
+
+    // A MANIFEST is small and isn't preallocated.
+    let manifest = new_descriptor_file(&env, dbname, 3, &dir, options).unwrap();
+    assert_eq!(manifest.writable_file().get_preallocation_status(), (0, 0));
+    drop(manifest);
+
+    for fname in &[
+        log_file_name(dbname, 1),
+        table_file_name(dbname, 2),
+        descriptor_file_name(dbname, 3),
+    ] {
+        assert!(env.delete_file(fname).is_ok());
+    }
+    assert!(env.delete_dir(dbname).is_ok());
+}
+
+#[test]
+fn test_set_current_file_options() {
+    use crate::env::env_posix::PosixEnv;
+
+    let env = PosixEnv::new();
+    let path = std::env::temp_dir().join(format!(
+        "test_set_current_file_options_{}",
+        std::process::id()
+    ));
+    let dbname = path.to_str().unwrap();
+    assert!(env.create_dir_if_missing(dbname).is_ok());
+    let dir = env.new_directory(dbname).unwrap();
+
+    // The DB's direct writes aren't used for the few bytes of CURRENT.
+    let options = EnvOptions {
+        use_direct_writes: true,
+        ..Default::default()
+    };
+    assert!(set_current_file(&env, dbname, 5, &dir, options).is_ok());
+    assert_eq!(read_current_file(&env, dbname).unwrap(), "MANIFEST-000005");
+    assert!(env.file_exists(&temp_file_name(dbname, 5)).is_err());
+
+    assert!(env.delete_file(&current_file_name(dbname)).is_ok());
+    assert!(env.delete_dir(dbname).is_ok());
+}
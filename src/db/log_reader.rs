@@ -2,10 +2,11 @@ use crate::db::log_format;
 use crate::db::log_format::kBlockSize;
 use crate::db::log_format::kMaxRecordType;
 use crate::env;
-use crate::env::io_posix::PosixSequentialFile;
+use crate::env::SequentialFile;
 use crate::util::coding::decode_fixed32;
 use crate::util::file_reader_writer::SequentialFileReader;
 use crate::util::hash::crc32;
+use crate::util::statistics::{record_tick, Tickers};
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum RecordType {
@@ -26,7 +27,7 @@ pub enum RecordType {
     kBadRecordChecksum = kMaxRecordType as isize + 6,
 }
 
-pub struct Reader {
+pub struct Reader<T: SequentialFile> {
     eof_: bool,
     buffer_: Vec<u8>,
     backing_store_: Vec<u8>,
@@ -45,16 +46,16 @@ pub struct Reader {
     log_number_: u32,
     recycled_: bool,
     checksum_: bool,
-    file_: SequentialFileReader<PosixSequentialFile>,
+    file_: SequentialFileReader<T>,
 }
 
-impl Reader {
+impl<T: SequentialFile> Reader<T> {
     pub fn new(
-        file: SequentialFileReader<PosixSequentialFile>,
+        file: SequentialFileReader<T>,
         initial_offset: u64,
         log_num: u32,
         checksum: bool,
-    ) -> Reader {
+    ) -> Reader<T> {
         Reader {
             eof_: false,
             buffer_: Vec::new(),
@@ -129,7 +130,7 @@ impl Reader {
                     // it could emit an empty kFirstType record at the tail end
                     // of a block followed by a kFullType or kFirstType record
                     // at the beginning of the next block.
-                    //ReportCorruption(scratch->size(), "partial record without end(1)");
+                    self.report_corruption(scratch.len()); // partial record without end(1)
                 }
                 {
                     prospective_record_offset = physical_record_offset;
@@ -149,7 +150,7 @@ impl Reader {
                     // it could emit an empty kFirstType record at the tail end
                     // of a block followed by a kFullType or kFirstType record
                     // at the beginning of the next block.
-                    //ReportCorruption(scratch->size(), "partial record without end(1)");
+                    self.report_corruption(scratch.len()); // partial record without end(1)
                 }
                 prospective_record_offset = physical_record_offset;
 
@@ -193,14 +194,14 @@ impl Reader {
             if record_type == RecordType::kBadHeader as isize {
                 if wal_recovery_mode == env::WALRecoveryMode::kAbsoluteConsistency {
                     // in clean shutdown we don't expect any error in the log files
-                    //ReportCorruption(drop_size, "truncated header");
+                    self.report_corruption(drop_size); // truncated header
                 }
             }
             if record_type == RecordType::kEof as isize {
                 if in_fragmented_record {
                     if wal_recovery_mode == env::WALRecoveryMode::kAbsoluteConsistency {
                         // in clean shutdown we don't expect any error in the log files
-                        //ReportCorruption(drop_size, "truncated header");
+                        self.report_corruption(scratch.len()); // truncated header
                     }
                     scratch.clear();
                 }
@@ -221,7 +222,7 @@ impl Reader {
             }
             if record_type == RecordType::kBadRecord as isize {
                 if in_fragmented_record {
-                    //ReportCorruption(drop_size, "truncated header");
+                    self.report_corruption(scratch.len()); // truncated header
                     in_fragmented_record = false;
                     scratch.clear();
                 }
@@ -237,12 +238,12 @@ impl Reader {
                     return false;
                 }
                 if record_type == RecordType::kBadRecordLen as isize {
-                    //ReportCorruption(drop_size, "bad record length");
+                    self.report_corruption(drop_size); // bad record length
                 } else {
-                    //ReportCorruption(drop_size, "checksum mismatch");
+                    self.report_corruption(drop_size); // checksum mismatch
                 }
                 if in_fragmented_record {
-                    //ReportCorruption(scratch->size(), "error in middle of record");
+                    self.report_corruption(scratch.len()); // error in middle of record
                     in_fragmented_record = false;
                     scratch.clear();
                 }
@@ -375,9 +376,9 @@ impl Reader {
                 &mut self.backing_store_,
             );
             self.end_of_buffer_offset_ += self.buffer_.len() as u64;
-            if !s.is_ok() {
+            if s.is_err() {
                 self.buffer_.clear();
-                //ReportDrop(kBlockSize, status);
+                self.report_corruption(log_format::kBlockSize); // read error
                 self.read_error_ = true;
                 *error = RecordType::kEof as isize;
                 return false;
@@ -401,5 +402,13 @@ impl Reader {
         }
     }
 
-    //fn ReportDrop(bytes: usize, reason: state) {}
+    // Dropped data is only counted; there is no reporter to pass it to.
+    fn report_corruption(&self, bytes: usize) {
+        record_tick(self.file_.stats(), Tickers::CorruptionDrops, 1);
+        record_tick(
+            self.file_.stats(),
+            Tickers::CorruptionDroppedBytes,
+            bytes as u64,
+        );
+    }
 }
@@ -1,3 +1,4 @@
+use crate::db::error_handler::{BackgroundErrorReason, ErrorHandler};
 use crate::db::log_format::{
     kBlockSize, kHeaderSize, kMaxRecordType, kRecyclableHeaderSize, RecordType,
 };
@@ -5,7 +6,9 @@ use crate::env::WritableFile;
 use crate::util::coding::{encode_fixed32, encode_fixed64};
 use crate::util::file_reader_writer::WritableFileWriter;
 use crate::util::hash::crc32;
-use crate::util::status::State;
+use crate::util::statistics::{record_tick, Tickers};
+use crate::util::status::Error;
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub struct Writer<T: WritableFile> {
@@ -15,11 +18,12 @@ pub struct Writer<T: WritableFile> {
     recycle_log_files_: bool,
     manual_flush_: bool,
     type_crc_: Vec<u32>,
+    error_handler_: Option<Arc<ErrorHandler>>,
 }
 
 impl<T: WritableFile> Drop for Writer<T> {
     fn drop(&mut self) {
-        self.dest_.close();
+        let _ = self.dest_.close();
     }
 }
 
@@ -41,10 +45,53 @@ impl<T: WritableFile> Writer<T> {
             recycle_log_files_: recycle_log_files,
             manual_flush_: manual_flush,
             type_crc_: type_crc.to_vec(),
+            error_handler_: None,
         }
     }
+
+    // Report failed appends and syncs to handler, and refuse new records
+    // while it has the DB stopped.
+    pub fn set_error_handler(&mut self, handler: Arc<ErrorHandler>) {
+        self.error_handler_ = Some(handler);
+    }
+
+    pub fn file(&mut self) -> &mut WritableFileWriter<T> {
+        &mut self.dest_
+    }
+
+    pub fn add_record(&mut self, slice: Vec<u8>) -> Result<(), Error> {
+        if let Some(ref handler) = self.error_handler_ {
+            handler.check_write()?;
+        }
+        let s = self.write_record(slice);
+        self.report(s)
+    }
+
+    pub fn sync(&mut self, use_fsync: bool) -> Result<(), Error> {
+        let s = self.dest_.sync(use_fsync);
+        self.report(s)
+    }
+
+    // Try once to clear the error handler's background error by syncing the
+    // WAL again. Without a handler there is nothing to recover from.
+    pub fn resume(&mut self) -> Result<(), Error> {
+        match self.error_handler_.clone() {
+            Some(handler) => handler.resume(|| self.dest_.sync(false)),
+            None => Ok(()),
+        }
+    }
+
+    // A record that may not have made it to the WAL makes later writes as
+    // unsafe as a failed sync does.
+    fn report(&self, s: Result<(), Error>) -> Result<(), Error> {
+        if let (Err(e), Some(handler)) = (&s, &self.error_handler_) {
+            handler.set_bg_error(e.clone(), BackgroundErrorReason::WalSync);
+        }
+        s
+    }
+
     /*const Slice& slice*/
-    pub fn add_record(&mut self, slice: Vec<u8>) {
+    fn write_record(&mut self, slice: Vec<u8>) -> Result<(), Error> {
         /*
         const char* ptr = slice.data();
         size_t left = slice.size();
@@ -65,14 +112,11 @@ impl<T: WritableFile> Writer<T> {
             if leftover < header_size {
                 if leftover > 0 {
                     assert!(header_size <= 11);
-                    let s = self.dest_.append(
+                    self.dest_.append(
                         vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]
                             [..leftover]
                             .to_vec(),
-                    );
-                    if !s.is_ok() {
-                        break;
-                    }
+                    )?;
                 }
                 self.block_offset_ = 0;
             }
@@ -107,18 +151,20 @@ impl<T: WritableFile> Writer<T> {
                     RecordType::kMiddleType
                 };
             };
-            let s = self.emit_physical_record(rtype, ptr.to_vec(), fragment_length);
+            self.emit_physical_record(rtype, ptr.to_vec(), fragment_length)?;
             ptr = &ptr[fragment_length..];
             left -= fragment_length;
             begin = false;
 
-            if !(s.is_ok() && left > 0) {
+            if left == 0 {
                 break;
             }
         }
+        record_tick(self.dest_.stats(), Tickers::WalRecords, 1);
+        Ok(())
     }
 
-    fn emit_physical_record(&mut self, t: RecordType, ptr: Vec<u8>, n: usize) -> State {
+    fn emit_physical_record(&mut self, t: RecordType, ptr: Vec<u8>, n: usize) -> Result<(), Error> {
         let mut header_size: usize = 0;
         let mut buf: [u8; kRecyclableHeaderSize] = [0u8; kRecyclableHeaderSize];
         let mut crc = self.type_crc_[t as usize];
@@ -0,0 +1,203 @@
+use crate::util::coding::{decode_fixed64, encode_fixed64};
+
+// The Merge Operator
+//
+// Essentially, a MergeOperator specifies the SEMANTICS of a merge, which only
+// client knows. It could be numeric addition, list append, string
+// concatenation, edit data structure, ... , anything.
+// The library, on the other hand, is concerned with the exercise of this
+// interface, at the right time (during get, iteration, compaction...)
+pub trait MergeOperator: Send + Sync {
+    // The name of the MergeOperator. Used to check for MergeOperator
+    // mismatches (i.e., a DB created with one MergeOperator is
+    // accessed using a different MergeOperator)
+    fn name(&self) -> &'static str;
+
+    // Gives the client a way to express the read -> modify -> write semantics
+    // key:           The key that's associated with this merge operation.
+    // existing_value: None indicates that the key does not exist before this op
+    // operand_list:  the sequence of merge operations to apply, front() first.
+    // new_value:     Client is responsible for filling the merge result here.
+    //
+    // Return true on success.
+    // All values passed in will be client-specific values. So if this method
+    // returns false, it is because client specified bad data or there was
+    // internal corruption. This will be treated as an error by the library.
+    fn full_merge(
+        &self,
+        key: &[u8],
+        existing_value: Option<&[u8]>,
+        operand_list: &[Vec<u8>],
+        new_value: &mut Vec<u8>,
+    ) -> bool;
+
+    // This function performs merge(left_op, right_op)
+    // when both the operands are themselves merge operation types
+    // that you would have passed to a DB::merge() call in the same order
+    // (i.e.: DB::merge(key,left_op), followed by DB::merge(key,right_op)).
+    //
+    // partial_merge should combine them into a single merge operation that is
+    // saved into new_value, and then it should return true.
+    // new_value should be constructed such that a call to
+    // DB::merge(key, new_value) would yield the same result as a call
+    // to DB::merge(key, left_op) followed by DB::merge(key, right_op).
+    //
+    // The default implementation returns false, which means the operands
+    // are kept as they are and folded later by full_merge.
+    fn partial_merge(
+        &self,
+        _key: &[u8],
+        _left_operand: &[u8],
+        _right_operand: &[u8],
+        _new_value: &mut Vec<u8>,
+    ) -> bool {
+        false
+    }
+}
+
+// A MergeOperator for 64-bit unsigned integer counters. Values and operands
+// are fixed64 encoded, and merging adds them with wrap-around.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UInt64AddOperator;
+
+impl UInt64AddOperator {
+    fn decode_integer(value: &[u8]) -> Option<u64> {
+        if value.len() != 8 {
+            return None;
+        }
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(value);
+        Some(decode_fixed64(buf))
+    }
+}
+
+impl MergeOperator for UInt64AddOperator {
+    fn name(&self) -> &'static str {
+        "UInt64AddOperator"
+    }
+
+    fn full_merge(
+        &self,
+        _key: &[u8],
+        existing_value: Option<&[u8]>,
+        operand_list: &[Vec<u8>],
+        new_value: &mut Vec<u8>,
+    ) -> bool {
+        let mut result = match existing_value {
+            Some(v) => match UInt64AddOperator::decode_integer(v) {
+                Some(n) => n,
+                None => return false,
+            },
+            None => 0,
+        };
+        for operand in operand_list {
+            match UInt64AddOperator::decode_integer(operand) {
+                Some(n) => result = result.wrapping_add(n),
+                None => return false,
+            }
+        }
+        new_value.clear();
+        new_value.extend_from_slice(&encode_fixed64(result));
+        true
+    }
+
+    fn partial_merge(
+        &self,
+        _key: &[u8],
+        left_operand: &[u8],
+        right_operand: &[u8],
+        new_value: &mut Vec<u8>,
+    ) -> bool {
+        let left = match UInt64AddOperator::decode_integer(left_operand) {
+            Some(n) => n,
+            None => return false,
+        };
+        let right = match UInt64AddOperator::decode_integer(right_operand) {
+            Some(n) => n,
+            None => return false,
+        };
+        new_value.clear();
+        new_value.extend_from_slice(&encode_fixed64(left.wrapping_add(right)));
+        true
+    }
+}
+
+// A MergeOperator that appends each operand to the existing value,
+// separated by a delimiter byte. Useful for append-only lists.
+#[derive(Debug, Clone, Copy)]
+pub struct StringAppendOperator {
+    delim_: u8,
+}
+
+impl StringAppendOperator {
+    pub fn new(delim: u8) -> StringAppendOperator {
+        StringAppendOperator { delim_: delim }
+    }
+}
+
+impl MergeOperator for StringAppendOperator {
+    fn name(&self) -> &'static str {
+        "StringAppendOperator"
+    }
+
+    fn full_merge(
+        &self,
+        _key: &[u8],
+        existing_value: Option<&[u8]>,
+        operand_list: &[Vec<u8>],
+        new_value: &mut Vec<u8>,
+    ) -> bool {
+        new_value.clear();
+        let mut first = true;
+        if let Some(v) = existing_value {
+            new_value.extend_from_slice(v);
+            first = false;
+        }
+        for operand in operand_list {
+            if !first {
+                new_value.push(self.delim_);
+            }
+            new_value.extend_from_slice(operand);
+            first = false;
+        }
+        true
+    }
+
+    fn partial_merge(
+        &self,
+        _key: &[u8],
+        left_operand: &[u8],
+        right_operand: &[u8],
+        new_value: &mut Vec<u8>,
+    ) -> bool {
+        new_value.clear();
+        new_value.extend_from_slice(left_operand);
+        new_value.push(self.delim_);
+        new_value.extend_from_slice(right_operand);
+        true
+    }
+}
+
+#[test]
+fn test_merge_operator() {
+    let op = UInt64AddOperator;
+    let mut value = Vec::new();
+    let operands = vec![encode_fixed64(3).to_vec(), encode_fixed64(4).to_vec()];
+    assert!(op.full_merge(b"k", Some(&encode_fixed64(10)), &operands, &mut value));
+    assert_eq!(value, encode_fixed64(17).to_vec());
+    assert!(op.full_merge(b"k", None, &operands, &mut value));
+    assert_eq!(value, encode_fixed64(7).to_vec());
+
+    let mut operand = Vec::new();
+    assert!(op.partial_merge(b"k", &encode_fixed64(1), &encode_fixed64(2), &mut operand));
+    assert!(op.full_merge(b"k", Some(&encode_fixed64(10)), &[operand], &mut value));
+    assert_eq!(value, encode_fixed64(13).to_vec());
+    assert!(!op.full_merge(b"k", Some(b"bad"), &operands, &mut value));
+
+    let op = StringAppendOperator::new(b',');
+    let operands = vec![b"b".to_vec(), b"c".to_vec()];
+    assert!(op.full_merge(b"k", Some(b"a"), &operands, &mut value));
+    assert_eq!(value, b"a,b,c".to_vec());
+    assert!(op.full_merge(b"k", None, &operands, &mut value));
+    assert_eq!(value, b"b,c".to_vec());
+}
@@ -1,41 +1,41 @@
+pub mod error_handler;
+pub mod filename;
 mod log_format;
 pub mod log_reader;
 pub mod log_writer;
-
-
-
-
-
-
-
-
-
-
-
+pub mod merge_operator;
 
 #[test]
 fn test_wal() {
+    use crate::db::log_reader::Reader;
+    use crate::db::log_writer::Writer;
+    use crate::env::mem_env::MemEnv;
+    use crate::env::{self, Env, EnvOptions};
+    use crate::util::file_reader_writer::{SequentialFileReader, WritableFileWriter};
+
+    let env = MemEnv::new();
     {
-        let fd = PosixWritableFile::new("test".to_string(), false, 1024);
-        let mut op: EnvOptions = EnvOptions::default();
-        op.writable_file_max_buffer_size = 50;
+        let op = EnvOptions {
+            writable_file_max_buffer_size: 50,
+            ..Default::default()
+        };
+        let fd = env.new_writable_file("test", op.clone()).unwrap();
         let writer = WritableFileWriter::new(fd, op);
         let mut wal = Writer::new(writer, 0, false, true);
 
         let input = vec![1, 2, 3];
-        wal.add_record(input);
+        assert!(wal.add_record(input).is_ok());
         let input = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
-        wal.add_record(input);
+        assert!(wal.add_record(input).is_ok());
         let input = vec![1, 2, 3];
-        wal.add_record(input);
+        assert!(wal.add_record(input).is_ok());
         let input = vec![1, 2];
-        wal.add_record(input);
+        assert!(wal.add_record(input).is_ok());
     }
     {
-        let mut pf: PosixSequentialFile = PosixSequentialFile::default();
         let op: EnvOptions = EnvOptions::default();
-        let state = PosixSequentialFile::new("test".to_string(), op, &mut pf);
-        let sf = SequentialFileReader::new(pf);
+        let pf = env.new_sequential_file("test", op).unwrap();
+        let sf = SequentialFileReader::new(pf, EnvOptions::default());
         let mut reader = Reader::new(sf, 0, 0, true);
         let mut record: Vec<u8> = Vec::new();
         let mut scratch: Vec<u8> = Vec::new();
@@ -60,3 +60,373 @@ fn test_wal() {
         assert_eq!(record, vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
     }
 }
+
+// Writes records to a new WAL, syncs it and returns the writer for more.
+#[cfg(test)]
+fn write_records<E: crate::env::Env>(
+    env: &E,
+    fname: &str,
+    options: crate::env::EnvOptions,
+    records: &[Vec<u8>],
+) -> log_writer::Writer<E::WritableFile> {
+    use crate::util::file_reader_writer::WritableFileWriter;
+
+    let fd = env.new_writable_file(fname, options.clone()).unwrap();
+    let mut wal = log_writer::Writer::new(WritableFileWriter::new(fd, options), 0, false, true);
+    for record in records {
+        assert!(wal.add_record(record.clone()).is_ok());
+    }
+    assert!(wal.file().sync(false).is_ok());
+    wal
+}
+
+// Replays the WAL fname and returns its records.
+#[cfg(test)]
+fn read_records<E: crate::env::Env>(
+    env: &E,
+    fname: &str,
+    options: crate::env::EnvOptions,
+    log_number: u32,
+    mode: crate::env::WALRecoveryMode,
+) -> Vec<Vec<u8>> {
+    use crate::util::file_reader_writer::SequentialFileReader;
+
+    let pf = env.new_sequential_file(fname, options.clone()).unwrap();
+    let mut reader =
+        log_reader::Reader::new(SequentialFileReader::new(pf, options), 0, log_number, true);
+    let mut records = Vec::new();
+    let mut record: Vec<u8> = Vec::new();
+    let mut scratch: Vec<u8> = Vec::new();
+    while reader.readRecord(&mut record, &mut scratch, mode) {
+        records.push(record.clone());
+    }
+    // once the reader has stopped it stays stopped
+    assert!(!reader.readRecord(&mut record, &mut scratch, mode));
+    records
+}
+
+#[cfg(test)]
+fn write_and_read_back<E: crate::env::Env>(
+    env: &E,
+    fname: &str,
+    options: crate::env::EnvOptions,
+    records: &[Vec<u8>],
+) -> Vec<Vec<u8>> {
+    drop(write_records(env, fname, options.clone(), records));
+    read_records(
+        env,
+        fname,
+        options,
+        0,
+        crate::env::WALRecoveryMode::kAbsoluteConsistency,
+    )
+}
+
+#[test]
+fn test_wal_fault_injection() {
+    use crate::db::log_format::kBlockSize;
+    use crate::env::fault_injection_env::{FaultInjectionEnv, FaultOp};
+    use crate::env::mem_env::MemEnv;
+    use crate::env::{Env, EnvOptions, WALRecoveryMode, WritableFile};
+    use crate::util::file_reader_writer::SequentialFileReader;
+    use crate::util::statistics::{Statistics, Tickers};
+    use std::sync::Arc;
+
+    let env = FaultInjectionEnv::new(MemEnv::new());
+    let synced = vec![vec![1, 2, 3], vec![4, 5, 6]];
+    {
+        let mut wal = write_records(&env, "wal", EnvOptions::default(), &synced);
+
+        // an append error is surfaced by add_record
+        env.inject_error(FaultOp::Append, env.call_count(FaultOp::Append) + 1);
+        assert!(wal.add_record(vec![7, 8, 9]).is_err());
+
+        // a sync error is surfaced and the record stays unsynced
+        assert!(wal.add_record(vec![10, 11]).is_ok());
+        env.inject_error(FaultOp::Sync, env.call_count(FaultOp::Sync) + 1);
+        assert!(wal.file().sync(false).is_err());
+
+        // crash before the last record is synced
+        env.set_filesystem_active(false);
+        assert!(wal.add_record(vec![12]).is_err());
+    }
+    assert!(env.drop_unsynced_file_data().is_ok());
+    env.set_filesystem_active(true);
+
+    // Returns the records read back and the number of bytes the reader
+    // reported as dropped. The log fits in one block, so the reader needs
+    // a single read whether or not it fails.
+    let read_all = |mode: WALRecoveryMode, fail_read: bool| -> (Vec<Vec<u8>>, u64) {
+        let stats = Arc::new(Statistics::new());
+        let op = EnvOptions {
+            statistics: Some(stats.clone()),
+            ..Default::default()
+        };
+        let reads = env.call_count(FaultOp::Read);
+        if fail_read {
+            env.inject_error(FaultOp::Read, reads + 1);
+        }
+        let records = read_records(&env, "wal", op, 0, mode);
+        assert_eq!(env.call_count(FaultOp::Read), reads + 1);
+        (
+            records,
+            stats.get_ticker_count(Tickers::CorruptionDroppedBytes),
+        )
+    };
+
+    // only the records synced before the crash are recovered, and the
+    // dropped tail leaves nothing for the reader to report
+    assert_eq!(
+        read_all(WALRecoveryMode::kAbsoluteConsistency, false),
+        (synced.clone(), 0)
+    );
+    // a read error stops the replay and drops the block being read
+    assert_eq!(
+        read_all(WALRecoveryMode::kAbsoluteConsistency, true),
+        (vec![], kBlockSize as u64)
+    );
+
+    // Append a copy of the first record (7 byte header and 3 byte payload)
+    // but crash after only its first 8 bytes were synced.
+    let mut data = Vec::new();
+    let mut scratch = Vec::new();
+    let mut pf = SequentialFileReader::new(
+        env.new_sequential_file("wal", EnvOptions::default())
+            .unwrap(),
+        EnvOptions::default(),
+    );
+    assert!(pf.read(10, &mut data, &mut scratch).is_ok());
+    assert_eq!(data.len(), 10);
+    {
+        let mut fd = env
+            .reopen_writable_file("wal", EnvOptions::default())
+            .unwrap();
+        assert!(fd.append(data[..8].to_vec()).is_ok());
+        assert!(fd.sync().is_ok());
+        assert!(fd.append(data[8..].to_vec()).is_ok());
+    }
+    assert!(env.drop_unsynced_file_data().is_ok());
+    assert_eq!(env.get_file_size("wal").unwrap(), 28);
+
+    // the torn record is only reported when no corruption is tolerated
+    assert_eq!(
+        read_all(WALRecoveryMode::kAbsoluteConsistency, false),
+        (synced.clone(), 8)
+    );
+    assert_eq!(
+        read_all(WALRecoveryMode::kTolerateCorruptedTailRecords, false),
+        (synced, 0)
+    );
+}
+
+#[test]
+fn test_wal_statistics() {
+    use crate::env::env_posix::PosixEnv;
+    use crate::env::{Env, EnvOptions, WALRecoveryMode};
+    use crate::util::statistics::{Histograms, Statistics, Tickers};
+    use std::fs;
+    use std::sync::Arc;
+
+    let env = PosixEnv::new();
+    let fname = format!("test_wal_statistics_{}", std::process::id());
+    let stats = Arc::new(Statistics::new());
+    let op = EnvOptions {
+        use_mmap_writes: false,
+        statistics: Some(stats.clone()),
+        ..Default::default()
+    };
+    let records: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; 100]).collect();
+    drop(write_records(&env, &fname, op.clone(), &records));
+
+    let snapshot = stats.snapshot();
+    assert_eq!(snapshot.ticker(Tickers::WalRecords), 10);
+    assert_eq!(snapshot.ticker(Tickers::BytesWritten), 10 * (7 + 100));
+    assert_eq!(snapshot.ticker(Tickers::Syncs), 1);
+    assert_eq!(snapshot.histogram(Histograms::SyncMicros).count, 1);
+    assert!(snapshot.histogram(Histograms::AppendMicros).count > 0);
+
+    // Corrupt the payload of the third record; the reader drops the rest of
+    // the block.
+    let mut data = fs::read(&fname).unwrap();
+    data[2 * 107 + 7] ^= 0xff;
+    fs::write(&fname, &data).unwrap();
+    stats.reset();
+
+    let read = read_records(&env, &fname, op, 0, WALRecoveryMode::kPointInTimeRecovery);
+    assert_eq!(read, records[..2].to_vec());
+    let snapshot = stats.snapshot();
+    assert_eq!(snapshot.ticker(Tickers::BytesRead), data.len() as u64);
+    assert_eq!(snapshot.ticker(Tickers::CorruptionDrops), 1);
+    assert_eq!(snapshot.ticker(Tickers::CorruptionDroppedBytes), 8 * 107);
+    assert!(snapshot.histogram(Histograms::ReadMicros).count > 0);
+    assert!(env.delete_file(&fname).is_ok());
+}
+
+#[test]
+fn test_wal_mmap_writes() {
+    use crate::env::env_posix::PosixEnv;
+    use crate::env::{Env, EnvOptions, WALRecoveryMode};
+
+    let env = PosixEnv::new();
+    let fname = format!("test_wal_mmap_writes_{}", std::process::id());
+    let op = EnvOptions {
+        use_mmap_writes: true,
+        use_direct_reads: false,
+        ..Default::default()
+    };
+    let records: Vec<Vec<u8>> = (0..100u8).map(|i| vec![i; i as usize + 1]).collect();
+    let wal = write_records(&env, &fname, op.clone(), &records);
+
+    // While the writer is open the file ends in the zero-filled part of the
+    // mapping, which the reader does not turn into records.
+    let size = env.get_file_size(&fname).unwrap();
+    assert!(size > 0);
+    let read_all = || {
+        read_records(
+            &env,
+            &fname,
+            op.clone(),
+            0,
+            WALRecoveryMode::kAbsoluteConsistency,
+        )
+    };
+    assert_eq!(read_all(), records);
+
+    drop(wal);
+    assert_eq!(read_all(), records);
+    assert!(env.delete_file(&fname).is_ok());
+}
+
+#[test]
+fn test_wal_direct_reads() {
+    use crate::env::env_posix::PosixEnv;
+    use crate::env::{Env, EnvOptions, SequentialFile};
+
+    let env = PosixEnv::new();
+    let fname = format!("test_wal_direct_reads_{}", std::process::id());
+    let op = EnvOptions {
+        use_mmap_writes: false,
+        use_direct_reads: true,
+        ..Default::default()
+    };
+    let records: Vec<Vec<u8>> = (0..100u8).map(|i| vec![i; i as usize + 1]).collect();
+    assert_eq!(
+        write_and_read_back(&env, &fname, op.clone(), &records),
+        records
+    );
+    let pf = env.new_sequential_file(&fname, op).unwrap();
+    assert!(pf.use_direct_io());
+    assert!(env.delete_file(&fname).is_ok());
+}
+
+#[test]
+fn test_wal_dir_sync_crash() {
+    use crate::db::filename::{
+        current_file_name, log_file_name, new_descriptor_file, new_log_file, read_current_file,
+        set_current_file, temp_file_name,
+    };
+    use crate::db::log_writer::Writer;
+    use crate::env::fault_injection_env::FaultInjectionEnv;
+    use crate::env::mem_env::MemEnv;
+    use crate::env::{Env, EnvOptions, WALRecoveryMode, WritableFile};
+    use crate::util::status::Code;
+
+    let env = FaultInjectionEnv::new(MemEnv::new());
+    let dbname = "db";
+    let dir = env.new_directory(dbname).unwrap();
+    let op = EnvOptions::default();
+    let records: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; 10]).collect();
+    {
+        // Roll over to MANIFEST-000003.
+        let mut manifest = new_descriptor_file(&env, dbname, 3, &dir, op.clone()).unwrap();
+        assert!(manifest.append(b"edit".to_vec()).is_ok());
+        assert!(manifest.sync(false).is_ok());
+        assert!(set_current_file(&env, dbname, 3, &dir, op.clone()).is_ok());
+
+        // WAL 1 is created with a directory fsync, WAL 2 without one. Any
+        // later fsync of the directory would make WAL 2 durable too.
+        let fd = new_log_file(&env, dbname, 1, &dir, op.clone()).unwrap();
+        let mut wal1 = Writer::new(fd, 1, false, true);
+        for record in &records {
+            assert!(wal1.add_record(record.clone()).is_ok());
+        }
+        assert!(wal1.file().sync(false).is_ok());
+        drop(write_records(
+            &env,
+            &log_file_name(dbname, 2),
+            op.clone(),
+            &records,
+        ));
+
+        // A file renamed into place without a directory fsync.
+        let tmp = env
+            .new_writable_file(&temp_file_name(dbname, 4), op.clone())
+            .unwrap();
+        assert!(tmp.sync().is_ok());
+        assert!(env
+            .rename_file(&temp_file_name(dbname, 4), "db/renamed")
+            .is_ok());
+
+        // power loss
+        env.set_filesystem_active(false);
+    }
+    assert!(env.drop_unsynced_file_data().is_ok());
+    assert!(env.delete_files_created_after_last_dir_sync().is_ok());
+    env.set_filesystem_active(true);
+
+    // The WAL whose directory entry was synced replays completely.
+    assert_eq!(
+        read_records(
+            &env,
+            &log_file_name(dbname, 1),
+            op,
+            1,
+            WALRecoveryMode::kAbsoluteConsistency,
+        ),
+        records
+    );
+
+    // The other WAL and the renamed file are gone with their directory
+    // entries, even though their data was synced.
+    assert_eq!(
+        env.file_exists(&log_file_name(dbname, 2))
+            .unwrap_err()
+            .code(),
+        Code::KNotFound
+    );
+    assert_eq!(
+        env.file_exists("db/renamed").unwrap_err().code(),
+        Code::KNotFound
+    );
+
+    // The manifest switch survived and left no temporary file behind.
+    assert_eq!(read_current_file(&env, dbname).unwrap(), "MANIFEST-000003");
+    assert!(env.file_exists(&current_file_name(dbname)).is_ok());
+    assert_eq!(
+        env.file_exists(&temp_file_name(dbname, 3))
+            .unwrap_err()
+            .code(),
+        Code::KNotFound
+    );
+    let size = env.get_file_size("db/MANIFEST-000003").unwrap();
+    assert_eq!(size, 4);
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_wal_encrypted() {
+    use crate::env::env_encryption::{EncryptedEnv, LocalKeyProvider};
+    use crate::env::mem_env::MemEnv;
+    use crate::env::EnvOptions;
+    use std::sync::Arc;
+
+    let provider = LocalKeyProvider::new(vec![("k1".to_string(), vec![7; 32])]);
+    let env = EncryptedEnv::new(MemEnv::new(), Arc::new(provider));
+    let records: Vec<Vec<u8>> = (0..20u32)
+        .map(|i| (0..i * 97).map(|j| (i + j) as u8).collect())
+        .collect();
+    assert_eq!(
+        write_and_read_back(&env, "wal", EnvOptions::default(), &records),
+        records
+    );
+}
@@ -0,0 +1,811 @@
+// An Env that encrypts file contents at rest with AES in CTR mode.
+//
+// Every file starts with a plain text header of ENCRYPTION_PREFIX_LENGTH
+// bytes holding the id of the key the file is encrypted with and a random
+// per-file IV; the data follows it. In CTR mode byte i of the data is xored
+// with byte i of the key stream, so files can be written, read and
+// rewritten at any offset and keep their size. The header is a whole page
+// so that the data stays aligned for direct I/O.
+//
+// The cipher comes from the RustCrypto aes and ctr crates, so the module is
+// only built with the encryption feature.
+
+use crate::env::k_default_page_size;
+use crate::env::{Env, EnvOptions, RandomAccessFile, ReadRequest, SequentialFile, WritableFile};
+use crate::util::coding::{decode_fixed32, encode_fixed32};
+use crate::util::status::{Error, SubCode};
+use aes::cipher::{InnerIvInit, InvalidLength, KeyInit, StreamCipher, StreamCipherSeek};
+use std::cmp::max;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::sync::Arc;
+
+pub const ENCRYPTION_PREFIX_LENGTH: usize = k_default_page_size;
+
+const ENCRYPTION_MAGIC: &[u8; 8] = b"ciboENC1";
+const AES_BLOCK_SIZE: usize = 16;
+
+// The AES block cipher with a 128, 192 or 256 bit key. The RustCrypto aes
+// crate picks AES-NI or a constant time software implementation at run
+// time.
+#[derive(Clone)]
+enum AesCipher {
+    Aes128(aes::Aes128),
+    Aes192(aes::Aes192),
+    Aes256(aes::Aes256),
+}
+
+impl fmt::Debug for AesCipher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Never print the key schedule.
+        let bits = match *self {
+            AesCipher::Aes128(_) => 128,
+            AesCipher::Aes192(_) => 192,
+            AesCipher::Aes256(_) => 256,
+        };
+        write!(f, "AesCipher({})", bits)
+    }
+}
+
+impl AesCipher {
+    fn new(key: &[u8]) -> Result<AesCipher, Error> {
+        let cipher = match key.len() {
+            16 => aes::Aes128::new_from_slice(key).map(AesCipher::Aes128),
+            24 => aes::Aes192::new_from_slice(key).map(AesCipher::Aes192),
+            32 => aes::Aes256::new_from_slice(key).map(AesCipher::Aes256),
+            _ => Err(InvalidLength),
+        };
+        cipher.map_err(|_| {
+            Error::InvalidArgument(format!(
+                "AES key must be 16, 24 or 32 bytes, not {}",
+                key.len()
+            ))
+        })
+    }
+}
+
+// The key stream of one file: the cipher applied to the IV, read as a big
+// endian counter, plus the block number.
+#[derive(Debug)]
+struct CtrCipherStream {
+    cipher_: AesCipher,
+    iv_: [u8; AES_BLOCK_SIZE],
+}
+
+impl CtrCipherStream {
+    // Encrypt or decrypt, which in CTR mode is the same thing, the data
+    // found at offset in the file.
+    fn apply(&self, offset: u64, data: &mut [u8]) {
+        macro_rules! apply_ctr {
+            ($cipher:ty, $c:expr) => {{
+                let core = ctr::CtrCore::inner_iv_init($c.clone(), (&self.iv_).into());
+                let mut ctr = ctr::Ctr128BE::<$cipher>::from_core(core);
+                ctr.seek(offset);
+                ctr.apply_keystream(data);
+            }};
+        }
+        match self.cipher_ {
+            AesCipher::Aes128(ref c) => apply_ctr!(aes::Aes128, c),
+            AesCipher::Aes192(ref c) => apply_ctr!(aes::Aes192, c),
+            AesCipher::Aes256(ref c) => apply_ctr!(aes::Aes256, c),
+        }
+    }
+}
+
+// Hands out encryption keys by id, so a key management service can be put
+// behind the env without it knowing about one.
+pub trait KeyProvider: fmt::Debug + Send + Sync {
+    // The id of the key new files are encrypted with. It is stored in the
+    // file header, so it must not be secret.
+    fn current_key_id(&self) -> String;
+
+    // The key with the given id. Files written before a key rotation still
+    // ask for their old key.
+    fn get_key(&self, key_id: &str) -> Result<Vec<u8>, Error>;
+}
+
+// Keys kept in a local key file, one "<id> <key in hex>" per line. Blank
+// lines and lines starting with '#' are ignored; the last key is the
+// current one.
+pub struct LocalKeyProvider {
+    keys_: Vec<(String, Vec<u8>)>,
+}
+
+impl fmt::Debug for LocalKeyProvider {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.keys_.iter().map(|(id, _)| id))
+            .finish()
+    }
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+impl LocalKeyProvider {
+    pub fn new(keys: Vec<(String, Vec<u8>)>) -> LocalKeyProvider {
+        assert!(!keys.is_empty());
+        LocalKeyProvider { keys_: keys }
+    }
+
+    pub fn open<E: Env>(env: &E, fname: &str) -> Result<LocalKeyProvider, Error> {
+        let mut file = env.new_sequential_file(fname, EnvOptions::default())?;
+        let mut contents = Vec::new();
+        let mut scratch = Vec::new();
+        loop {
+            let len = contents.len();
+            file.read(4096, &mut contents, &mut scratch)?;
+            if contents.len() == len {
+                break;
+            }
+        }
+
+        let malformed = |line: usize, why: &str| {
+            Err(Error::Corruption(format!(
+                "key file {} line {}: {}",
+                fname, line, why
+            )))
+        };
+        let contents = match String::from_utf8(contents) {
+            Ok(contents) => contents,
+            Err(_) => return malformed(1, "not valid UTF-8"),
+        };
+        let mut keys = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (id, hex) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(id), Some(hex), None) => (id, hex),
+                _ => return malformed(i + 1, "expected \"<id> <key in hex>\""),
+            };
+            let key = match decode_hex(hex) {
+                Some(key) => key,
+                None => return malformed(i + 1, "key is not hex"),
+            };
+            if key.len() != 16 && key.len() != 24 && key.len() != 32 {
+                return malformed(i + 1, "key must be 16, 24 or 32 bytes");
+            }
+            keys.push((id.to_string(), key));
+        }
+        if keys.is_empty() {
+            return Err(Error::InvalidArgument(format!(
+                "key file {} has no keys",
+                fname
+            )));
+        }
+        Ok(LocalKeyProvider::new(keys))
+    }
+}
+
+impl KeyProvider for LocalKeyProvider {
+    fn current_key_id(&self) -> String {
+        self.keys_.last().unwrap().0.clone()
+    }
+
+    fn get_key(&self, key_id: &str) -> Result<Vec<u8>, Error> {
+        match self.keys_.iter().rev().find(|(id, _)| id == key_id) {
+            Some((_, key)) => Ok(key.clone()),
+            None => Err(Error::NotFound(format!("encryption key {}", key_id))),
+        }
+    }
+}
+
+fn generate_iv() -> Result<[u8; AES_BLOCK_SIZE], Error> {
+    let mut iv = [0u8; AES_BLOCK_SIZE];
+    File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut iv))
+        .map_err(|e| Error::io_error("While generating an IV", e))?;
+    Ok(iv)
+}
+
+// magic, IV, key id length and key id, padded with zeros to
+// ENCRYPTION_PREFIX_LENGTH.
+fn encode_header(key_id: &str, iv: &[u8; AES_BLOCK_SIZE]) -> Vec<u8> {
+    let mut header = Vec::with_capacity(ENCRYPTION_PREFIX_LENGTH);
+    header.extend_from_slice(ENCRYPTION_MAGIC);
+    header.extend_from_slice(iv);
+    header.extend_from_slice(&encode_fixed32(key_id.len() as u32));
+    header.extend_from_slice(key_id.as_bytes());
+    header.resize(ENCRYPTION_PREFIX_LENGTH, 0);
+    header
+}
+
+fn decode_header(header: &[u8], fname: &str) -> Result<(String, [u8; AES_BLOCK_SIZE]), Error> {
+    let bad_header = |why: &str| {
+        Err(Error::Corruption(format!(
+            "encryption header of {}: {}",
+            fname, why
+        )))
+    };
+    if header.len() < ENCRYPTION_PREFIX_LENGTH {
+        return bad_header("file is too short");
+    }
+    if &header[..8] != ENCRYPTION_MAGIC {
+        return bad_header("bad magic");
+    }
+    let mut iv = [0u8; AES_BLOCK_SIZE];
+    iv.copy_from_slice(&header[8..24]);
+    let len = decode_fixed32([header[24], header[25], header[26], header[27]]) as usize;
+    if len > ENCRYPTION_PREFIX_LENGTH - 28 {
+        return bad_header("bad key id length");
+    }
+    match String::from_utf8(header[28..28 + len].to_vec()) {
+        Ok(key_id) => Ok((key_id, iv)),
+        Err(_) => bad_header("bad key id"),
+    }
+}
+
+#[derive(Debug)]
+pub struct EncryptedWritableFile<W: WritableFile> {
+    file_: W,
+    stream_: CtrCipherStream,
+    // End of the data written so far, where append writes.
+    offset_: usize,
+}
+
+impl<W: WritableFile> WritableFile for EncryptedWritableFile<W> {
+    fn append(&mut self, mut data: Vec<u8>) -> Result<(), Error> {
+        self.stream_.apply(self.offset_ as u64, &mut data);
+        let len = data.len();
+        self.file_.append(data)?;
+        self.offset_ += len;
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        self.file_.sync()
+    }
+
+    fn close(&mut self) -> Result<(), Error> {
+        self.file_.close()
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.file_.flush()
+    }
+
+    fn fcntl(&self) -> bool {
+        self.file_.fcntl()
+    }
+
+    fn truncate(&mut self, size: usize) -> Result<(), Error> {
+        self.file_.truncate(size + ENCRYPTION_PREFIX_LENGTH)?;
+        self.offset_ = size;
+        Ok(())
+    }
+
+    fn get_required_buffer_alignment(&self) -> usize {
+        self.file_.get_required_buffer_alignment()
+    }
+
+    fn range_sync(&self, offset: i64, nbytes: i64) -> Result<(), Error> {
+        self.file_
+            .range_sync(offset + ENCRYPTION_PREFIX_LENGTH as i64, nbytes)
+    }
+
+    fn allocate(&self, offset: i64, len: i64) -> Result<(), Error> {
+        self.file_
+            .allocate(offset + ENCRYPTION_PREFIX_LENGTH as i64, len)
+    }
+
+    fn prepare_write(&mut self, offset: usize, len: usize) {
+        self.file_
+            .prepare_write(offset + ENCRYPTION_PREFIX_LENGTH, len)
+    }
+
+    fn set_preallocation_block_size(&mut self, size: usize) {
+        self.file_.set_preallocation_block_size(size)
+    }
+
+    fn get_preallocation_status(&self) -> (usize, usize) {
+        self.file_.get_preallocation_status()
+    }
+
+    fn positioned_append(&mut self, mut data: Vec<u8>, offset: usize) -> Result<(), Error> {
+        self.stream_.apply(offset as u64, &mut data);
+        let end = offset + data.len();
+        self.file_
+            .positioned_append(data, offset + ENCRYPTION_PREFIX_LENGTH)?;
+        self.offset_ = max(self.offset_, end);
+        Ok(())
+    }
+
+    fn fsync(&self) -> Result<(), Error> {
+        self.file_.fsync()
+    }
+
+    fn get_file_size(&self) -> usize {
+        self.file_
+            .get_file_size()
+            .saturating_sub(ENCRYPTION_PREFIX_LENGTH)
+    }
+
+    fn use_direct_io(&self) -> bool {
+        self.file_.use_direct_io()
+    }
+}
+
+#[derive(Debug)]
+pub struct EncryptedSequentialFile<S: SequentialFile> {
+    file_: S,
+    stream_: CtrCipherStream,
+    // Offset of the next read in the data.
+    offset_: u64,
+}
+
+impl<S: SequentialFile> SequentialFile for EncryptedSequentialFile<S> {
+    fn skip(&mut self, n: i64) -> Result<i64, Error> {
+        // The underlying file would happily move back into the prefix.
+        if self.offset_.checked_add_signed(n).is_none() {
+            return Err(Error::InvalidArgument(format!(
+                "cannot skip {} bytes from offset {}",
+                n, self.offset_
+            )));
+        }
+        let moved = self.file_.skip(n)?;
+        self.offset_ = self.offset_.saturating_add_signed(moved);
+        Ok(moved)
+    }
+
+    fn read(&mut self, n: usize, result: &mut Vec<u8>, scratch: &mut Vec<u8>) -> Result<(), Error> {
+        let start = result.len();
+        self.file_.read(n, result, scratch)?;
+        self.stream_.apply(self.offset_, &mut result[start..]);
+        self.offset_ += (result.len() - start) as u64;
+        Ok(())
+    }
+
+    fn positioned_read(
+        &self,
+        offset: u64,
+        n: usize,
+        result: &mut Vec<u8>,
+        scratch: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        let start = result.len();
+        self.file_
+            .positioned_read(offset + ENCRYPTION_PREFIX_LENGTH as u64, n, result, scratch)?;
+        self.stream_.apply(offset, &mut result[start..]);
+        Ok(())
+    }
+
+    fn use_direct_io(&self) -> bool {
+        self.file_.use_direct_io()
+    }
+
+    fn get_required_buffer_alignment(&self) -> usize {
+        self.file_.get_required_buffer_alignment()
+    }
+}
+
+#[derive(Debug)]
+pub struct EncryptedRandomAccessFile<R: RandomAccessFile> {
+    file_: R,
+    stream_: CtrCipherStream,
+}
+
+impl<R: RandomAccessFile> RandomAccessFile for EncryptedRandomAccessFile<R> {
+    fn read(
+        &self,
+        offset: u64,
+        n: usize,
+        result: &mut Vec<u8>,
+        scratch: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        let start = result.len();
+        self.file_
+            .read(offset + ENCRYPTION_PREFIX_LENGTH as u64, n, result, scratch)?;
+        self.stream_.apply(offset, &mut result[start..]);
+        Ok(())
+    }
+
+    fn multi_read(&self, reqs: &mut [ReadRequest]) -> Result<(), Error> {
+        for req in reqs.iter_mut() {
+            req.offset += ENCRYPTION_PREFIX_LENGTH as u64;
+        }
+        let s = self.file_.multi_read(reqs);
+        for req in reqs.iter_mut() {
+            req.offset -= ENCRYPTION_PREFIX_LENGTH as u64;
+            if s.is_ok() && req.status.is_ok() {
+                self.stream_.apply(req.offset, &mut req.result);
+            }
+        }
+        s
+    }
+
+    fn prefetch(&self, offset: u64, n: usize) -> Result<(), Error> {
+        self.file_
+            .prefetch(offset + ENCRYPTION_PREFIX_LENGTH as u64, n)
+    }
+
+    fn use_direct_io(&self) -> bool {
+        self.file_.use_direct_io()
+    }
+
+    fn get_required_buffer_alignment(&self) -> usize {
+        self.file_.get_required_buffer_alignment()
+    }
+}
+
+// A wrapper around an Env that encrypts everything written to its files
+// and decrypts everything read from them, with keys from a KeyProvider.
+// File sizes and offsets seen through it leave out the header, so code on
+// top of it, like the WAL writer and reader, works unchanged.
+pub struct EncryptedEnv<E: Env> {
+    target_: E,
+    key_provider_: Arc<dyn KeyProvider>,
+}
+
+impl<E: Env> EncryptedEnv<E> {
+    pub fn new(target: E, key_provider: Arc<dyn KeyProvider>) -> EncryptedEnv<E> {
+        EncryptedEnv {
+            target_: target,
+            key_provider_: key_provider,
+        }
+    }
+
+    pub fn target(&self) -> &E {
+        &self.target_
+    }
+
+    fn new_stream(&self, key_id: &str, iv: [u8; AES_BLOCK_SIZE]) -> Result<CtrCipherStream, Error> {
+        let key = self.key_provider_.get_key(key_id)?;
+        Ok(CtrCipherStream {
+            cipher_: AesCipher::new(&key)?,
+            iv_: iv,
+        })
+    }
+
+    // Write a new header to file and return the stream for its data.
+    fn write_header<W: WritableFile>(&self, file: &mut W) -> Result<CtrCipherStream, Error> {
+        let key_id = self.key_provider_.current_key_id();
+        if key_id.len() > ENCRYPTION_PREFIX_LENGTH - 28 {
+            return Err(Error::InvalidArgument(format!(
+                "encryption key id {} is too long",
+                key_id
+            )));
+        }
+        let iv = generate_iv()?;
+        let stream = self.new_stream(&key_id, iv)?;
+        let header = encode_header(&key_id, &iv);
+        if file.use_direct_io() {
+            file.positioned_append(header, 0)?;
+        } else {
+            file.append(header)?;
+        }
+        Ok(stream)
+    }
+
+    fn read_header(&self, fname: &str) -> Result<CtrCipherStream, Error> {
+        let file = self
+            .target_
+            .new_random_access_file(fname, EnvOptions::default())?;
+        let mut header = Vec::new();
+        let mut scratch = Vec::new();
+        file.read(0, ENCRYPTION_PREFIX_LENGTH, &mut header, &mut scratch)?;
+        let (key_id, iv) = decode_header(&header, fname)?;
+        self.new_stream(&key_id, iv)
+    }
+}
+
+impl<E: Env> Env for EncryptedEnv<E> {
+    type WritableFile = EncryptedWritableFile<E::WritableFile>;
+    type SequentialFile = EncryptedSequentialFile<E::SequentialFile>;
+    type RandomAccessFile = EncryptedRandomAccessFile<E::RandomAccessFile>;
+    type Directory = E::Directory;
+    type FileLock = E::FileLock;
+
+    fn new_sequential_file(
+        &self,
+        fname: &str,
+        options: EnvOptions,
+    ) -> Result<Self::SequentialFile, Error> {
+        let mut file = self.target_.new_sequential_file(fname, options)?;
+        let mut header = Vec::new();
+        let mut scratch = Vec::new();
+        while header.len() < ENCRYPTION_PREFIX_LENGTH {
+            let len = header.len();
+            file.read(ENCRYPTION_PREFIX_LENGTH - len, &mut header, &mut scratch)?;
+            if header.len() == len {
+                break;
+            }
+        }
+        let (key_id, iv) = decode_header(&header, fname)?;
+        Ok(EncryptedSequentialFile {
+            file_: file,
+            stream_: self.new_stream(&key_id, iv)?,
+            offset_: 0,
+        })
+    }
+
+    fn new_random_access_file(
+        &self,
+        fname: &str,
+        options: EnvOptions,
+    ) -> Result<Self::RandomAccessFile, Error> {
+        let file = self.target_.new_random_access_file(fname, options)?;
+        let mut header = Vec::new();
+        let mut scratch = Vec::new();
+        file.read(0, ENCRYPTION_PREFIX_LENGTH, &mut header, &mut scratch)?;
+        let (key_id, iv) = decode_header(&header, fname)?;
+        Ok(EncryptedRandomAccessFile {
+            file_: file,
+            stream_: self.new_stream(&key_id, iv)?,
+        })
+    }
+
+    fn new_writable_file(
+        &self,
+        fname: &str,
+        options: EnvOptions,
+    ) -> Result<Self::WritableFile, Error> {
+        let mut file = self.target_.new_writable_file(fname, options)?;
+        let stream = self.write_header(&mut file)?;
+        Ok(EncryptedWritableFile {
+            file_: file,
+            stream_: stream,
+            offset_: 0,
+        })
+    }
+
+    fn reopen_writable_file(
+        &self,
+        fname: &str,
+        options: EnvOptions,
+    ) -> Result<Self::WritableFile, Error> {
+        // Only a missing file is started afresh; any other failure to stat it
+        // must not end up with a second header written over its data.
+        let size = match self.target_.get_file_size(fname) {
+            Ok(size) => size,
+            Err(ref e) if e.subcode() == SubCode::PathNotFound => 0,
+            Err(e) => return Err(e),
+        };
+        // An existing file keeps its key and IV.
+        let stream = if size > 0 {
+            Some(self.read_header(fname)?)
+        } else {
+            None
+        };
+        let mut file = self.target_.reopen_writable_file(fname, options)?;
+        let stream = match stream {
+            Some(stream) => stream,
+            None => self.write_header(&mut file)?,
+        };
+        Ok(EncryptedWritableFile {
+            file_: file,
+            stream_: stream,
+            offset_: (size as usize).saturating_sub(ENCRYPTION_PREFIX_LENGTH),
+        })
+    }
+
+    fn new_directory(&self, name: &str) -> Result<Self::Directory, Error> {
+        self.target_.new_directory(name)
+    }
+
+    fn file_exists(&self, fname: &str) -> Result<(), Error> {
+        self.target_.file_exists(fname)
+    }
+
+    fn get_children(&self, dir: &str) -> Result<Vec<String>, Error> {
+        self.target_.get_children(dir)
+    }
+
+    fn delete_file(&self, fname: &str) -> Result<(), Error> {
+        self.target_.delete_file(fname)
+    }
+
+    fn create_dir(&self, dirname: &str) -> Result<(), Error> {
+        self.target_.create_dir(dirname)
+    }
+
+    fn create_dir_if_missing(&self, dirname: &str) -> Result<(), Error> {
+        self.target_.create_dir_if_missing(dirname)
+    }
+
+    fn delete_dir(&self, dirname: &str) -> Result<(), Error> {
+        self.target_.delete_dir(dirname)
+    }
+
+    fn get_file_size(&self, fname: &str) -> Result<u64, Error> {
+        let file_size = self.target_.get_file_size(fname)?;
+        Ok(file_size.saturating_sub(ENCRYPTION_PREFIX_LENGTH as u64))
+    }
+
+    fn get_file_modification_time(&self, fname: &str) -> Result<u64, Error> {
+        self.target_.get_file_modification_time(fname)
+    }
+
+    fn rename_file(&self, src: &str, target: &str) -> Result<(), Error> {
+        self.target_.rename_file(src, target)
+    }
+
+    fn lock_file(&self, fname: &str) -> Result<Self::FileLock, Error> {
+        self.target_.lock_file(fname)
+    }
+
+    fn unlock_file(&self, lock: Self::FileLock) -> Result<(), Error> {
+        self.target_.unlock_file(lock)
+    }
+
+    fn now_micros(&self) -> u64 {
+        self.target_.now_micros()
+    }
+
+    fn now_nanos(&self) -> u64 {
+        self.target_.now_nanos()
+    }
+
+    fn sleep_for_microseconds(&self, micros: u64) {
+        self.target_.sleep_for_microseconds(micros)
+    }
+}
+
+#[test]
+fn test_aes_ctr() {
+    use crate::util::status::Code;
+
+    // SP 800-38A F.5.1, F.5.3 and F.5.5; the counter wraps in its last byte.
+    let plaintext = decode_hex(
+        "6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51\
+         30c81c46a35ce411e5fbc1191a0a52eff69f2445df4f9b17ad2b417be66c3710",
+    )
+    .unwrap();
+    let cases = [
+        (
+            "2b7e151628aed2a6abf7158809cf4f3c",
+            "874d6191b620e3261bef6864990db6ce9806f66b7970fdff8617187bb9fffdff\
+             5ae4df3edbd5d35e5b4f09020db03eab1e031dda2fbe03d1792170a0f3009cee",
+        ),
+        (
+            "8e73b0f7da0e6452c810f32b809079e562f8ead2522c6b7b",
+            "1abc932417521ca24f2b0459fe7e6e0b090339ec0aa6faefd5ccc2c6f4ce8e94\
+             1e36b26bd1ebc670d1bd1d665620abf74f78a7f6d29809585a97daec58c6b050",
+        ),
+        (
+            "603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4",
+            "601ec313775789a5b7a7f504bbf3d228f443e3ca4d62b59aca84e990cacaf5c5\
+             2b0930daa23de94ce87017ba2d84988ddfc9c58db67aada613c2dd08457941a6",
+        ),
+    ];
+    let mut iv = [0u8; AES_BLOCK_SIZE];
+    iv.copy_from_slice(&decode_hex("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff").unwrap());
+    for &(key, ciphertext) in &cases {
+        let stream = CtrCipherStream {
+            cipher_: AesCipher::new(&decode_hex(key).unwrap()).unwrap(),
+            iv_: iv,
+        };
+        let ciphertext = decode_hex(ciphertext).unwrap();
+        let mut data = plaintext.clone();
+        stream.apply(0, &mut data);
+        assert_eq!(data, ciphertext);
+        // Any range can be decrypted on its own.
+        let mut data = ciphertext[5..43].to_vec();
+        stream.apply(5, &mut data);
+        assert_eq!(&data[..], &plaintext[5..43]);
+    }
+    assert_eq!(
+        AesCipher::new(&[0; 20]).unwrap_err().code(),
+        Code::KInvalidArgument
+    );
+}
+
+#[test]
+fn test_encrypted_env() {
+    use crate::env::mem_env::MemEnv;
+    use crate::util::status::Code;
+
+    let keys = "# rotated keys\nold 000102030405060708090a0b0c0d0e0f\n\n\
+                new 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f\n";
+    let mem = MemEnv::new();
+    let mut file = mem
+        .new_writable_file("/keys", EnvOptions::default())
+        .unwrap();
+    assert!(file.append(keys.as_bytes().to_vec()).is_ok());
+    let provider = LocalKeyProvider::open(&mem, "/keys").unwrap();
+    assert_eq!(provider.current_key_id(), "new");
+    assert_eq!(provider.get_key("old").unwrap().len(), 16);
+    assert_eq!(
+        provider.get_key("missing").unwrap_err().code(),
+        Code::KNotFound
+    );
+
+    let env = EncryptedEnv::new(mem, Arc::new(provider));
+    let data: Vec<u8> = (0..10000u32).map(|i| (i % 251) as u8).collect();
+    let mut file = env
+        .new_writable_file("/dir/f", EnvOptions::default())
+        .unwrap();
+    assert!(file.append(data[..6000].to_vec()).is_ok());
+    assert!(file.close().is_ok());
+    let mut file = env
+        .reopen_writable_file("/dir/f", EnvOptions::default())
+        .unwrap();
+    assert!(file.append(data[6000..].to_vec()).is_ok());
+    assert!(file.close().is_ok());
+
+    let size = env.get_file_size("/dir/f").unwrap();
+    assert_eq!(size, data.len() as u64);
+    // Nothing of the plain text is on disk.
+    let mut raw = env
+        .target()
+        .new_sequential_file("/dir/f", EnvOptions::default())
+        .unwrap();
+    let mut on_disk = Vec::new();
+    let mut scratch = Vec::new();
+    assert!(raw
+        .read(
+            ENCRYPTION_PREFIX_LENGTH + data.len(),
+            &mut on_disk,
+            &mut scratch
+        )
+        .is_ok());
+    assert_eq!(on_disk.len(), ENCRYPTION_PREFIX_LENGTH + data.len());
+    assert_eq!(&on_disk[28..31], b"new");
+    assert!(on_disk[ENCRYPTION_PREFIX_LENGTH..]
+        .windows(16)
+        .all(|w| !data.windows(16).any(|d| d == w)));
+
+    let mut file = env
+        .new_sequential_file("/dir/f", EnvOptions::default())
+        .unwrap();
+    let mut result = Vec::new();
+    assert!(file.read(100, &mut result, &mut scratch).is_ok());
+    assert!(file.skip(900).is_ok());
+    assert!(file.read(100, &mut result, &mut scratch).is_ok());
+    assert_eq!(&result[..100], &data[..100]);
+    assert_eq!(&result[100..], &data[1000..1100]);
+    // Skipping back stops at the start of the data, not of the prefix.
+    assert_eq!(file.skip(-1101).unwrap_err().code(), Code::KInvalidArgument);
+    assert_eq!(file.skip(-1100).unwrap(), -1100);
+    result.clear();
+    assert!(file.read(10, &mut result, &mut scratch).is_ok());
+    assert_eq!(&result[..], &data[..10]);
+
+    let file = env
+        .new_random_access_file("/dir/f", EnvOptions::default())
+        .unwrap();
+    let mut result = Vec::new();
+    assert!(file.read(5999, 3, &mut result, &mut scratch).is_ok());
+    assert_eq!(&result[..], &data[5999..6002]);
+    let mut reqs = vec![ReadRequest::new(17, 40), ReadRequest::new(9990, 40)];
+    assert!(file.multi_read(&mut reqs).is_ok());
+    assert_eq!(&reqs[0].result[..], &data[17..57]);
+    assert_eq!(&reqs[1].result[..], &data[9990..]);
+
+    // Reopening a missing file creates it with a fresh header.
+    let mut file = env
+        .reopen_writable_file("/dir/g", EnvOptions::default())
+        .unwrap();
+    assert!(file.append(data[..100].to_vec()).is_ok());
+    assert!(file.close().is_ok());
+    assert_eq!(env.get_file_size("/dir/g").unwrap(), 100);
+    let mut file = env
+        .new_sequential_file("/dir/g", EnvOptions::default())
+        .unwrap();
+    let mut result = Vec::new();
+    assert!(file.read(200, &mut result, &mut scratch).is_ok());
+    assert_eq!(&result[..], &data[..100]);
+
+    // A file without a header can't be opened.
+    let mut plain = env
+        .target()
+        .new_writable_file("/dir/plain", EnvOptions::default())
+        .unwrap();
+    assert!(plain.append(data.clone()).is_ok());
+    assert_eq!(
+        env.new_sequential_file("/dir/plain", EnvOptions::default())
+            .unwrap_err()
+            .code(),
+        Code::KCorruption
+    );
+}
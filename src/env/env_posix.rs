@@ -0,0 +1,753 @@
+use crate::env::io_posix::{
+    get_flag_for_posix_sequential_file, io_error, page_size, FileDescriptor, PosixDirectory,
+    PosixMmapFile, PosixMmapReadableFile, PosixRandomAccessFile, PosixSequentialFile,
+    PosixWritableFile,
+};
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use crate::env::io_uring;
+use crate::env::{Env, EnvOptions, RandomAccessFile, WritableFile};
+use crate::util::flock;
+use crate::util::status::Error;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::ptr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub struct PosixFileLock {
+    filename_: String,
+    _lock: flock::Lock,
+}
+
+// fcntl locks are held per process, so a second lock_file on the same path
+// from this process would silently succeed. Track the files we have locked
+// to reject that case.
+#[derive(Default)]
+pub struct PosixEnv {
+    locked_files_: Mutex<HashSet<String>>,
+}
+
+impl PosixEnv {
+    pub fn new() -> PosixEnv {
+        PosixEnv::default()
+    }
+
+    fn open_writable_file(
+        &self,
+        fname: &str,
+        reopen: bool,
+        options: EnvOptions,
+    ) -> Result<Box<dyn WritableFile>, Error> {
+        let file = PosixWritableFile::new(fname.to_string(), reopen, 0, &options)?;
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        let file = io_uring::writable_file(file, fname);
+        Ok(Box::new(file))
+    }
+
+    fn open_mmap_file(
+        &self,
+        fname: &str,
+        options: EnvOptions,
+    ) -> Result<Box<dyn WritableFile>, Error> {
+        let fd = FileDescriptor::open(
+            fname,
+            libc::O_CREAT | libc::O_TRUNC | libc::O_RDWR,
+            "While open a file for appending",
+            &options,
+        )?;
+        Ok(Box::new(PosixMmapFile::new(
+            fname.to_string(),
+            fd,
+            page_size(),
+            &options,
+        )))
+    }
+}
+
+impl Env for PosixEnv {
+    type WritableFile = Box<dyn WritableFile>;
+    type SequentialFile = PosixSequentialFile;
+    type RandomAccessFile = Box<dyn RandomAccessFile>;
+    type Directory = PosixDirectory;
+    type FileLock = PosixFileLock;
+
+    fn new_sequential_file(
+        &self,
+        fname: &str,
+        options: EnvOptions,
+    ) -> Result<PosixSequentialFile, Error> {
+        PosixSequentialFile::new(fname.to_string(), &options)
+    }
+
+    fn new_random_access_file(
+        &self,
+        fname: &str,
+        options: EnvOptions,
+    ) -> Result<Box<dyn RandomAccessFile>, Error> {
+        let mut flag = libc::O_RDONLY;
+        if options.use_direct_reads && !options.use_mmap_reads {
+            flag |= get_flag_for_posix_sequential_file();
+        }
+        let fd = FileDescriptor::open(fname, flag, "While open a file for random read", &options)?;
+
+        if options.use_mmap_reads {
+            let size = self.get_file_size(fname)?;
+            let mut base = ptr::null_mut();
+            if size > 0 {
+                base = unsafe {
+                    libc::mmap(
+                        ptr::null_mut(),
+                        size as usize,
+                        libc::PROT_READ,
+                        libc::MAP_SHARED,
+                        fd.raw(),
+                        0,
+                    )
+                };
+                if base == libc::MAP_FAILED {
+                    return Err(io_error(
+                        "while mmap file for read",
+                        fname,
+                        io::Error::last_os_error(),
+                    ));
+                }
+            }
+            // The mapping stays valid after the descriptor is closed on drop.
+            Ok(Box::new(PosixMmapReadableFile::new(
+                fname.to_string(),
+                base,
+                size as usize,
+            )))
+        } else {
+            let file = PosixRandomAccessFile::new(fname.to_string(), fd, &options);
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            let file = io_uring::random_access_file(file, fname);
+            Ok(Box::new(file))
+        }
+    }
+
+    fn new_writable_file(
+        &self,
+        fname: &str,
+        options: EnvOptions,
+    ) -> Result<Box<dyn WritableFile>, Error> {
+        if options.use_mmap_writes && !options.use_direct_writes {
+            return self.open_mmap_file(fname, options);
+        }
+        self.open_writable_file(fname, false, options)
+    }
+
+    // mmap writes always start a fresh file, so reopening appends through
+    // the write() based PosixWritableFile.
+    fn reopen_writable_file(
+        &self,
+        fname: &str,
+        options: EnvOptions,
+    ) -> Result<Box<dyn WritableFile>, Error> {
+        self.open_writable_file(fname, true, options)
+    }
+
+    fn new_directory(&self, name: &str) -> Result<PosixDirectory, Error> {
+        PosixDirectory::new(name.to_string(), &EnvOptions::default())
+    }
+
+    fn file_exists(&self, fname: &str) -> Result<(), Error> {
+        match fs::metadata(fname) {
+            Ok(_) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                Err(Error::NotFound(fname.to_string()))
+            }
+            Err(e) => Err(io_error("While checking existence of", fname, e)),
+        }
+    }
+
+    fn get_children(&self, dir: &str) -> Result<Vec<String>, Error> {
+        let entries = fs::read_dir(dir).map_err(|e| io_error("While opendir", dir, e))?;
+        let mut result = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(|e| io_error("While readdir", dir, e))?;
+            result.push(entry.file_name().to_string_lossy().into_owned());
+        }
+        Ok(result)
+    }
+
+    fn delete_file(&self, fname: &str) -> Result<(), Error> {
+        fs::remove_file(fname).map_err(|e| io_error("while unlink() file", fname, e))
+    }
+
+    fn create_dir(&self, dirname: &str) -> Result<(), Error> {
+        fs::create_dir(dirname).map_err(|e| io_error("While mkdir", dirname, e))
+    }
+
+    fn create_dir_if_missing(&self, dirname: &str) -> Result<(), Error> {
+        match fs::create_dir(dirname) {
+            Ok(()) => Ok(()),
+            Err(ref e)
+                if e.kind() == io::ErrorKind::AlreadyExists && Path::new(dirname).is_dir() =>
+            {
+                Ok(())
+            }
+            Err(e) => Err(io_error("While mkdir if missing", dirname, e)),
+        }
+    }
+
+    fn delete_dir(&self, dirname: &str) -> Result<(), Error> {
+        fs::remove_dir(dirname).map_err(|e| io_error("file rmdir", dirname, e))
+    }
+
+    fn get_file_size(&self, fname: &str) -> Result<u64, Error> {
+        fs::metadata(fname)
+            .map(|meta| meta.len())
+            .map_err(|e| io_error("while stat a file for size", fname, e))
+    }
+
+    fn get_file_modification_time(&self, fname: &str) -> Result<u64, Error> {
+        let modified = fs::metadata(fname)
+            .and_then(|meta| meta.modified())
+            .map_err(|e| io_error("while stat a file for modification time", fname, e))?;
+        Ok(modified
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0))
+    }
+
+    fn rename_file(&self, src: &str, target: &str) -> Result<(), Error> {
+        fs::rename(src, target)
+            .map_err(|e| io_error(&format!("While renaming a file to {}", target), src, e))
+    }
+
+    fn lock_file(&self, fname: &str) -> Result<PosixFileLock, Error> {
+        let mut locked_files = self.locked_files_.lock().unwrap();
+        if locked_files.contains(fname) {
+            return Err(Error::io_error(
+                format!("lock {}", fname),
+                io::Error::new(io::ErrorKind::WouldBlock, "lock hold by current process"),
+            ));
+        }
+        let l = flock::Lock::new(Path::new(fname), false, true, true)
+            .map_err(|e| io_error("While lock file", fname, e))?;
+        locked_files.insert(fname.to_string());
+        Ok(PosixFileLock {
+            filename_: fname.to_string(),
+            _lock: l,
+        })
+    }
+
+    fn unlock_file(&self, lock: PosixFileLock) -> Result<(), Error> {
+        let mut locked_files = self.locked_files_.lock().unwrap();
+        if !locked_files.remove(&lock.filename_) {
+            return Err(Error::io_error(
+                format!("unlock {}", lock.filename_),
+                io::Error::other("file not locked"),
+            ));
+        }
+        // Dropping the flock::Lock releases the fcntl lock and closes the fd.
+        Ok(())
+    }
+
+    fn now_micros(&self) -> u64 {
+        let d = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::from_secs(0));
+        d.as_secs() * 1_000_000 + u64::from(d.subsec_micros())
+    }
+
+    fn now_nanos(&self) -> u64 {
+        let d = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::from_secs(0));
+        d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos())
+    }
+
+    fn sleep_for_microseconds(&self, micros: u64) {
+        thread::sleep(Duration::from_micros(micros));
+    }
+}
+
+// Tests work in the temp directory so a failed run doesn't leave files in
+// the source tree.
+#[cfg(test)]
+fn test_path(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("{}_{}", name, std::process::id()));
+    path.to_str().unwrap().to_string()
+}
+
+#[test]
+fn test_posix_env() {
+    use crate::util::status::Code;
+
+    let env = PosixEnv::new();
+    let dir = test_path("test_posix_env");
+    assert!(env.create_dir_if_missing(&dir).is_ok());
+    assert!(env.create_dir_if_missing(&dir).is_ok());
+
+    let fname = format!("{}/f", dir);
+    let mut file = env
+        .new_writable_file(&fname, EnvOptions::default())
+        .unwrap();
+    assert!(file.close().is_ok());
+    assert!(env.file_exists(&fname).is_ok());
+
+    assert_eq!(env.get_children(&dir).unwrap(), vec!["f".to_string()]);
+    assert_eq!(env.get_file_size(&fname).unwrap(), 0);
+
+    let lock_name = format!("{}/LOCK", dir);
+    let lock = env.lock_file(&lock_name).unwrap();
+    assert!(env.lock_file(&lock_name).is_err());
+    assert!(env.unlock_file(lock).is_ok());
+    let lock = env.lock_file(&lock_name).unwrap();
+    assert!(env.unlock_file(lock).is_ok());
+
+    let renamed = format!("{}/g", dir);
+    assert!(env.rename_file(&fname, &renamed).is_ok());
+    assert_eq!(env.file_exists(&fname).unwrap_err().code(), Code::KNotFound);
+    assert!(env.delete_file(&renamed).is_ok());
+    assert!(env.delete_file(&lock_name).is_ok());
+    assert!(env.delete_dir(&dir).is_ok());
+}
+
+#[test]
+fn test_posix_random_access_file() {
+    use crate::env::ReadRequest;
+
+    let env = PosixEnv::new();
+    let fname = test_path("test_posix_random_access");
+    let data: Vec<u8> = (0..10000).map(|i| (i % 251) as u8).collect();
+    fs::write(&fname, &data).unwrap();
+
+    for &(use_mmap_reads, use_direct_reads) in &[(false, false), (true, false), (false, true)] {
+        let options = EnvOptions {
+            use_mmap_reads,
+            use_direct_reads,
+            ..Default::default()
+        };
+        let file = env.new_random_access_file(&fname, options).unwrap();
+        assert!(file.prefetch(0, 4096).is_ok());
+
+        let mut result = Vec::new();
+        let mut scratch = Vec::new();
+        assert!(file.read(100, 50, &mut result, &mut scratch).is_ok());
+        assert_eq!(result, data[100..150].to_vec());
+
+        // short read at the end of the file
+        result.clear();
+        assert!(file.read(9990, 50, &mut result, &mut scratch).is_ok());
+        assert_eq!(result, data[9990..].to_vec());
+
+        let mut reqs = vec![
+            ReadRequest::new(0, 10),
+            ReadRequest::new(5000, 4096),
+            ReadRequest::new(10000, 1),
+        ];
+        assert!(file.multi_read(&mut reqs).is_ok());
+        assert!(reqs.iter().all(|r| r.status.is_ok()));
+        assert_eq!(reqs[0].result, data[0..10].to_vec());
+        assert_eq!(reqs[1].result, data[5000..9096].to_vec());
+        assert!(reqs[2].result.is_empty());
+    }
+
+    assert!(env
+        .new_random_access_file("no_such_file", EnvOptions::default())
+        .is_err());
+    assert!(env.delete_file(&fname).is_ok());
+}
+
+#[test]
+fn test_posix_mmap_file() {
+    let env = PosixEnv::new();
+    let fname = test_path("test_posix_mmap_file");
+    let options = EnvOptions {
+        use_mmap_writes: true,
+        ..Default::default()
+    };
+    let mut file = env.new_writable_file(&fname, options).unwrap();
+
+    // spans several mapped regions, including ones that grow the map size
+    let mut expected = Vec::new();
+    let mut i = 0;
+    while expected.len() < 3 * 1024 * 1024 {
+        let chunk: Vec<u8> = (0..(i * 7919) % 20000 + 1).map(|j| (i + j) as u8).collect();
+        expected.extend_from_slice(&chunk);
+        assert!(file.append(chunk).is_ok());
+        i += 1;
+    }
+    assert!(file.sync().is_ok());
+    assert_eq!(file.get_file_size(), expected.len());
+
+    // the mapped tail is allocated but not yet written
+    let size = env.get_file_size(&fname).unwrap();
+    assert!(size as usize > expected.len());
+
+    assert!(file.close().is_ok());
+    assert!(!file.fcntl());
+    let size = env.get_file_size(&fname).unwrap();
+    assert_eq!(size as usize, expected.len());
+    assert!(fs::read(&fname).unwrap() == expected);
+    assert!(env.delete_file(&fname).is_ok());
+}
+
+#[cfg(test)]
+fn check_posix_direct_writes(env: &PosixEnv, fname: &str) {
+    // Direct writes win over the default mmap writes.
+    let options = EnvOptions {
+        use_direct_writes: true,
+        ..Default::default()
+    };
+    assert!(options.use_mmap_writes);
+    // A filesystem that rejects O_DIRECT gets a buffered file instead, which
+    // must take the same writes.
+    let mut file = env.new_writable_file(fname, options).unwrap();
+    let alignment = if file.use_direct_io() {
+        file.get_required_buffer_alignment()
+    } else {
+        512
+    };
+    assert!(alignment >= 512 && (alignment & (alignment - 1)) == 0);
+
+    // Vec buffers are not sector aligned, so these exercise the staging copy.
+    let block: Vec<u8> = (0..alignment * 3).map(|i| (i % 253) as u8).collect();
+    assert!(file.positioned_append(block.clone(), 0).is_ok());
+    assert!(file
+        .positioned_append(block[..alignment].to_vec(), alignment * 3)
+        .is_ok());
+    assert_eq!(file.get_file_size(), alignment * 4);
+    assert!(file.truncate(alignment * 3 + 10).is_ok());
+    assert!(file.sync().is_ok());
+    assert!(file.close().is_ok());
+
+    let mut expected = block.clone();
+    expected.extend_from_slice(&block[..10]);
+    assert!(fs::read(fname).unwrap() == expected);
+    assert!(env.delete_file(fname).is_ok());
+}
+
+#[test]
+fn test_posix_direct_writes() {
+    let env = PosixEnv::new();
+    check_posix_direct_writes(&env, &test_path("test_posix_direct_writes"));
+    // tmpfs either rejects O_DIRECT or, on newer kernels, accepts it with a
+    // page sized alignment.
+    if Path::new("/dev/shm").is_dir() {
+        check_posix_direct_writes(
+            &env,
+            &format!("/dev/shm/test_posix_direct_writes_{}", std::process::id()),
+        );
+    }
+}
+
+#[test]
+fn test_posix_direct_writes_fallback() {
+    use crate::env::io_posix::open_with_buffered_fallback;
+    use crate::util::status::Code;
+
+    let fname = test_path("test_posix_direct_writes_fallback");
+    let options = EnvOptions::default();
+    let open = |direct: bool, attempts: &mut Vec<bool>| {
+        attempts.push(direct);
+        FileDescriptor::open(
+            &fname,
+            libc::O_CREAT | libc::O_RDWR,
+            "While open a file for appending",
+            &options,
+        )
+    };
+
+    // A filesystem without O_DIRECT fails the open with EINVAL.
+    let mut attempts = Vec::new();
+    let (fd, direct) = open_with_buffered_fallback(true, |direct| {
+        if direct {
+            attempts.push(direct);
+            return Err(io_error(
+                "While open a file for appending",
+                &fname,
+                io::Error::from_raw_os_error(libc::EINVAL),
+            ));
+        }
+        open(direct, &mut attempts)
+    })
+    .unwrap();
+    assert!(!direct);
+    assert!(fd.raw() >= 0);
+    assert_eq!(attempts, vec![true, false]);
+
+    // Other errors are not retried.
+    let mut attempts = 0;
+    let e = open_with_buffered_fallback(true, |_| {
+        attempts += 1;
+        Err(io_error(
+            "While open a file for appending",
+            &fname,
+            io::Error::from_raw_os_error(libc::EACCES),
+        ))
+    })
+    .unwrap_err();
+    assert_eq!(e.code(), Code::KIOError);
+    assert_eq!(attempts, 1);
+
+    let mut attempts = Vec::new();
+    let (_, direct) = open_with_buffered_fallback(false, |d| open(d, &mut attempts)).unwrap();
+    assert!(!direct);
+    assert_eq!(attempts, vec![false]);
+    assert!(fs::remove_file(&fname).is_ok());
+}
+
+#[test]
+fn test_posix_buffered_writes() {
+    use crate::util::file_reader_writer::WritableFileWriter;
+
+    let env = PosixEnv::new();
+    let fname = test_path("test_posix_buffered_writes");
+    let options = EnvOptions {
+        use_mmap_writes: false,
+        writable_file_max_buffer_size: 65536,
+        ..Default::default()
+    };
+    let file = env.new_writable_file(&fname, options.clone()).unwrap();
+    assert!(!file.use_direct_io());
+    let mut writer = WritableFileWriter::new(file, options.clone());
+    let mut expected = Vec::new();
+    for i in 0..100 {
+        let chunk: Vec<u8> = (0..i * 37 + 1).map(|j| (i + j) as u8).collect();
+        expected.extend_from_slice(&chunk);
+        assert!(writer.append(chunk).is_ok());
+    }
+    assert!(writer.close().is_ok());
+    assert!(fs::read(&fname).unwrap() == expected);
+
+    // reopening appends after the existing contents
+    let mut file = env.reopen_writable_file(&fname, options).unwrap();
+    assert_eq!(file.get_file_size(), expected.len());
+    assert!(file.append(vec![1, 2, 3]).is_ok());
+    assert!(file.close().is_ok());
+    expected.extend_from_slice(&[1, 2, 3]);
+    assert!(fs::read(&fname).unwrap() == expected);
+    assert!(env.delete_file(&fname).is_ok());
+}
+
+#[test]
+fn test_posix_sequential_direct_reads() {
+    use crate::env::SequentialFile;
+    use crate::util::status::Code;
+
+    let env = PosixEnv::new();
+    let fname = test_path("test_posix_sequential_direct_reads");
+    let data: Vec<u8> = (0..10000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(&fname, &data).unwrap();
+
+    // Only direct I/O files read at an offset.
+    let file = env
+        .new_sequential_file(&fname, EnvOptions::default())
+        .unwrap();
+    let e = file
+        .positioned_read(0, 4096, &mut Vec::new(), &mut Vec::new())
+        .unwrap_err();
+    assert_eq!(e.code(), Code::KNotSupported);
+
+    let options = EnvOptions {
+        use_direct_reads: true,
+        ..Default::default()
+    };
+    let mut file = match env.new_sequential_file(&fname, options) {
+        Ok(file) => file,
+        Err(e) => {
+            // The filesystem rejects O_DIRECT.
+            assert_eq!(e.code(), Code::KIOError);
+            assert!(env.delete_file(&fname).is_ok());
+            return;
+        }
+    };
+    assert!(file.use_direct_io());
+    let alignment = file.get_required_buffer_alignment();
+
+    // Unaligned reads and skips are served from aligned preads.
+    let mut result = Vec::new();
+    let mut scratch = Vec::new();
+    assert!(file.read(100, &mut result, &mut scratch).is_ok());
+    assert!(file.skip(1000).is_ok());
+    assert!(file.read(3333, &mut result, &mut scratch).is_ok());
+    let mut expected = data[..100].to_vec();
+    expected.extend_from_slice(&data[1100..4433]);
+    assert!(result == expected);
+
+    // Reading past the end returns the tail and then nothing.
+    result.clear();
+    assert!(file.read(100000, &mut result, &mut scratch).is_ok());
+    assert!(result == data[4433..].to_vec());
+    result.clear();
+    assert!(file.read(10, &mut result, &mut scratch).is_ok());
+    assert!(result.is_empty());
+
+    result.clear();
+    assert!(file
+        .positioned_read(alignment as u64, alignment, &mut result, &mut scratch)
+        .is_ok());
+    assert!(result == data[alignment..2 * alignment].to_vec());
+    // Unaligned positioned reads are refused rather than handed to O_DIRECT.
+    for &(offset, n) in &[(1, alignment), (0, alignment + 1)] {
+        let e = file
+            .positioned_read(offset as u64, n, &mut result, &mut scratch)
+            .unwrap_err();
+        assert_eq!(e.code(), Code::KInvalidArgument);
+        assert!(e.to_string().contains(&fname));
+    }
+
+    // Skipping back before the start fails and leaves the position alone.
+    let e = file.skip(-(data.len() as i64) - 1).unwrap_err();
+    assert_eq!(e.code(), Code::KInvalidArgument);
+    assert!(file.skip(-(data.len() as i64)).is_ok());
+    result.clear();
+    assert!(file.read(10, &mut result, &mut scratch).is_ok());
+    assert!(result == data[..10].to_vec());
+    assert!(env.delete_file(&fname).is_ok());
+}
+
+#[test]
+fn test_posix_io_errors() {
+    use crate::util::status::Code;
+
+    let env = PosixEnv::new();
+    let fname = format!("{}/missing", test_path("test_posix_io_errors"));
+    let options = EnvOptions {
+        use_mmap_writes: false,
+        ..Default::default()
+    };
+
+    // Open failures name the operation, the file and the errno text.
+    let e = env
+        .new_sequential_file(&fname, options.clone())
+        .unwrap_err();
+    assert_eq!(e.code(), Code::KIOError);
+    let msg = e.to_string();
+    assert!(msg.starts_with("IO error: While opening a file for sequentially reading"));
+    assert!(msg.contains(&fname));
+    assert!(msg.contains("No such file or directory"));
+
+    let e = env
+        .new_writable_file(&fname, options.clone())
+        .err()
+        .unwrap();
+    assert_eq!(e.code(), Code::KIOError);
+    assert!(e.to_string().contains("No such file or directory"));
+
+    let e = env
+        .new_writable_file("bad\0name", options.clone())
+        .err()
+        .unwrap();
+    assert_eq!(e.code(), Code::KInvalidArgument);
+
+    // Using a closed file reports EBADF instead of touching a stale fd.
+    let fname = test_path("test_posix_io_errors");
+    let mut file = env.new_writable_file(&fname, options).unwrap();
+    assert!(file.close().is_ok());
+    let e = file.append(vec![1, 2, 3]).unwrap_err();
+    assert_eq!(e.code(), Code::KIOError);
+    let msg = e.to_string();
+    assert!(msg.starts_with("IO error: While appending to file"));
+    assert!(msg.contains(&fname));
+    assert!(msg.contains("Bad file descriptor"));
+    assert!(env.delete_file(&fname).is_ok());
+}
+
+#[test]
+fn test_posix_directory() {
+    use crate::env::Directory;
+    use crate::util::status::Code;
+
+    let env = PosixEnv::new();
+    let dirname = test_path("test_posix_directory");
+    assert!(env.create_dir(&dirname).is_ok());
+    let mut dir = env.new_directory(&dirname).unwrap();
+    let fname = format!("{}/f", dirname);
+    let file = env
+        .new_writable_file(&fname, EnvOptions::default())
+        .unwrap();
+    drop(file);
+    assert!(dir.fsync().is_ok());
+    assert!(dir.close().is_ok());
+    // fsync after close reports the closed descriptor
+    let e = dir.fsync().unwrap_err();
+    assert_eq!(e.code(), Code::KIOError);
+    assert!(e.to_string().contains(&dirname));
+
+    // regular files and missing paths are not directories
+    assert_eq!(
+        env.new_directory(&fname).unwrap_err().code(),
+        Code::KIOError
+    );
+    assert!(env.delete_file(&fname).is_ok());
+    assert!(env.delete_dir(&dirname).is_ok());
+    assert_eq!(
+        env.new_directory(&dirname).unwrap_err().code(),
+        Code::KIOError
+    );
+}
+
+#[test]
+fn test_posix_preallocation() {
+    use crate::env::preallocation_block_size;
+    use crate::util::file_reader_writer::WritableFileWriter;
+    use std::os::unix::fs::MetadataExt;
+
+    let env = PosixEnv::new();
+    let fname = test_path("test_posix_preallocation");
+    let block_size = preallocation_block_size(1 << 20);
+    assert_eq!(block_size, (1 << 20) + (1 << 20) / 10);
+
+    for &(allow_fallocate, keep_size) in &[(true, true), (true, false), (false, true)] {
+        let options = EnvOptions {
+            use_mmap_writes: false,
+            allow_fallocate,
+            fallocate_with_keep_size: keep_size,
+            ..Default::default()
+        };
+        let mut file = env.new_writable_file(&fname, options.clone()).unwrap();
+        assert_eq!(file.get_preallocation_status(), (0, 0));
+        file.set_preallocation_block_size(block_size);
+        let mut writer = WritableFileWriter::new(file, options.clone());
+        assert!(writer.append(vec![7; 100]).is_ok());
+        assert!(writer.flush().is_ok());
+
+        let metadata = fs::metadata(&fname).unwrap();
+        let allocated = metadata.blocks() * 512;
+        if allow_fallocate && allocated < block_size as u64 {
+            // The filesystem can't preallocate; writing still works.
+            assert_eq!(metadata.len(), 100);
+        } else if allow_fallocate {
+            // Without KEEP_SIZE the file grows to the preallocated size
+            // until it is closed.
+            let size = if keep_size { 100 } else { block_size as u64 };
+            assert_eq!(metadata.len(), size);
+        } else {
+            assert!(allocated < block_size as u64);
+            assert_eq!(metadata.len(), 100);
+        }
+
+        assert!(writer.append(vec![8; 1 << 20]).is_ok());
+        assert!(writer.close().is_ok());
+        let metadata = fs::metadata(&fname).unwrap();
+        assert_eq!(metadata.len(), 100 + (1 << 20));
+        assert!(metadata.blocks() * 512 < 2 * block_size as u64);
+        let data = fs::read(&fname).unwrap();
+        assert!(data[..100].iter().all(|&b| b == 7));
+        assert!(data[100..].iter().all(|&b| b == 8));
+    }
+
+    // Reopened files always keep their size, so appends land after the data.
+    let options = EnvOptions {
+        use_mmap_writes: false,
+        fallocate_with_keep_size: false,
+        ..Default::default()
+    };
+    let mut file = env.reopen_writable_file(&fname, options.clone()).unwrap();
+    file.set_preallocation_block_size(block_size);
+    file.prepare_write(file.get_file_size(), 10);
+    assert!(file.append(vec![9; 10]).is_ok());
+    assert!(file.close().is_ok());
+    let data = fs::read(&fname).unwrap();
+    assert_eq!(data.len(), 100 + (1 << 20) + 10);
+    assert!(data[100 + (1 << 20)..].iter().all(|&b| b == 9));
+    assert!(env.delete_file(&fname).is_ok());
+}
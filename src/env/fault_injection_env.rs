@@ -0,0 +1,651 @@
+use crate::env::{Directory, Env, EnvOptions, RandomAccessFile, SequentialFile, WritableFile};
+use crate::util::status::{Error, SubCode};
+use std::cmp::max;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+// The operations FaultInjectionEnv can make fail with EIO.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FaultOp {
+    Append,
+    Sync,
+    RangeSync,
+    Read,
+}
+
+#[derive(Debug, Default)]
+struct FileState {
+    // Logical size of the file as seen by the writer.
+    pos_: usize,
+    // Size of the file at the last successful sync. Everything past it is
+    // lost by drop_unsynced_file_data.
+    pos_at_last_sync_: usize,
+}
+
+#[derive(Debug)]
+struct FaultState {
+    files_: HashMap<String, FileState>,
+    // Number of calls made so far for each operation, across all files.
+    calls_: HashMap<FaultOp, usize>,
+    // Call numbers (1-based) that should fail with EIO.
+    faults_: HashMap<FaultOp, HashSet<usize>>,
+    filesystem_active_: bool,
+    // Files created or renamed into each directory since the directory was
+    // last fsynced. Their names are lost in a crash.
+    dir_new_files_: HashMap<String, HashSet<String>>,
+}
+
+impl FaultState {
+    fn add_new_file(&mut self, fname: &str) {
+        self.dir_new_files_
+            .entry(parent_dir(fname))
+            .or_default()
+            .insert(fname.to_string());
+    }
+
+    fn remove_new_file(&mut self, fname: &str) {
+        if let Some(files) = self.dir_new_files_.get_mut(&parent_dir(fname)) {
+            files.remove(fname);
+        }
+    }
+}
+
+fn parent_dir(fname: &str) -> String {
+    match fname.rfind('/') {
+        Some(0) => "/".to_string(),
+        Some(i) => fname[..i].to_string(),
+        None => ".".to_string(),
+    }
+}
+
+fn normalize_dir(dirname: &str) -> String {
+    let trimmed = dirname.trim_end_matches('/');
+    if trimmed.is_empty() && !dirname.is_empty() {
+        "/".to_string()
+    } else {
+        trimmed.to_string()
+    }
+}
+
+type SharedState = Arc<Mutex<FaultState>>;
+
+fn injected_error(op: FaultOp, fname: &str) -> Error {
+    Error::io_error(
+        format!("injected {:?} error on {}", op, fname),
+        io::Error::from_raw_os_error(libc::EIO),
+    )
+}
+
+fn filesystem_inactive(fname: &str) -> Error {
+    Error::io_error(fname, io::Error::other("Filesystem inactive"))
+}
+
+// Count the call and return an error if the filesystem has "crashed" or an
+// error was injected for this call number.
+fn check_fault(state: &SharedState, op: FaultOp, fname: &str) -> Result<(), Error> {
+    let mut state = state.lock().unwrap();
+    if !state.filesystem_active_ {
+        return Err(filesystem_inactive(fname));
+    }
+    let count = {
+        let calls = state.calls_.entry(op).or_insert(0);
+        *calls += 1;
+        *calls
+    };
+    if let Some(faults) = state.faults_.get_mut(&op) {
+        if faults.remove(&count) {
+            return Err(injected_error(op, fname));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct FaultInjectionWritableFile<W: WritableFile> {
+    target_: W,
+    filename_: String,
+    state_: SharedState,
+}
+
+impl<W: WritableFile> FaultInjectionWritableFile<W> {
+    fn record_write(&self, end: usize) {
+        let mut state = self.state_.lock().unwrap();
+        let file = state.files_.entry(self.filename_.clone()).or_default();
+        file.pos_ = max(file.pos_, end);
+    }
+
+    fn record_sync(&self) {
+        let mut state = self.state_.lock().unwrap();
+        let file = state.files_.entry(self.filename_.clone()).or_default();
+        file.pos_at_last_sync_ = file.pos_;
+    }
+
+    fn current_pos(&self) -> usize {
+        let state = self.state_.lock().unwrap();
+        state.files_.get(&self.filename_).map_or(0, |f| f.pos_)
+    }
+}
+
+impl<W: WritableFile> WritableFile for FaultInjectionWritableFile<W> {
+    fn append(&mut self, data: Vec<u8>) -> Result<(), Error> {
+        check_fault(&self.state_, FaultOp::Append, &self.filename_)?;
+        let end = self.current_pos() + data.len();
+        self.target_.append(data)?;
+        self.record_write(end);
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        check_fault(&self.state_, FaultOp::Sync, &self.filename_)?;
+        self.target_.sync()?;
+        self.record_sync();
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), Error> {
+        self.target_.close()
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        if !self.state_.lock().unwrap().filesystem_active_ {
+            return Err(filesystem_inactive(&self.filename_));
+        }
+        self.target_.flush()
+    }
+
+    fn fcntl(&self) -> bool {
+        self.target_.fcntl()
+    }
+
+    fn truncate(&mut self, size: usize) -> Result<(), Error> {
+        if !self.state_.lock().unwrap().filesystem_active_ {
+            return Err(filesystem_inactive(&self.filename_));
+        }
+        self.target_.truncate(size)?;
+        let mut state = self.state_.lock().unwrap();
+        let file = state.files_.entry(self.filename_.clone()).or_default();
+        file.pos_ = size;
+        if file.pos_at_last_sync_ > size {
+            file.pos_at_last_sync_ = size;
+        }
+        Ok(())
+    }
+
+    fn get_required_buffer_alignment(&self) -> usize {
+        self.target_.get_required_buffer_alignment()
+    }
+
+    fn range_sync(&self, offset: i64, nbytes: i64) -> Result<(), Error> {
+        check_fault(&self.state_, FaultOp::RangeSync, &self.filename_)?;
+        self.target_.range_sync(offset, nbytes)
+    }
+
+    fn allocate(&self, offset: i64, len: i64) -> Result<(), Error> {
+        self.target_.allocate(offset, len)
+    }
+
+    fn prepare_write(&mut self, offset: usize, len: usize) {
+        self.target_.prepare_write(offset, len)
+    }
+
+    fn set_preallocation_block_size(&mut self, size: usize) {
+        self.target_.set_preallocation_block_size(size)
+    }
+
+    fn get_preallocation_status(&self) -> (usize, usize) {
+        self.target_.get_preallocation_status()
+    }
+
+    fn positioned_append(&mut self, data: Vec<u8>, offset: usize) -> Result<(), Error> {
+        check_fault(&self.state_, FaultOp::Append, &self.filename_)?;
+        let end = offset + data.len();
+        self.target_.positioned_append(data, offset)?;
+        self.record_write(end);
+        Ok(())
+    }
+
+    fn fsync(&self) -> Result<(), Error> {
+        check_fault(&self.state_, FaultOp::Sync, &self.filename_)?;
+        self.target_.fsync()?;
+        self.record_sync();
+        Ok(())
+    }
+
+    fn get_file_size(&self) -> usize {
+        self.target_.get_file_size()
+    }
+
+    fn use_direct_io(&self) -> bool {
+        self.target_.use_direct_io()
+    }
+}
+
+#[derive(Debug)]
+pub struct FaultInjectionSequentialFile<S: SequentialFile> {
+    target_: S,
+    filename_: String,
+    state_: SharedState,
+}
+
+impl<S: SequentialFile> SequentialFile for FaultInjectionSequentialFile<S> {
+    fn skip(&mut self, n: i64) -> Result<i64, Error> {
+        self.target_.skip(n)
+    }
+
+    fn read(&mut self, n: usize, result: &mut Vec<u8>, scratch: &mut Vec<u8>) -> Result<(), Error> {
+        check_fault(&self.state_, FaultOp::Read, &self.filename_)?;
+        self.target_.read(n, result, scratch)
+    }
+
+    fn positioned_read(
+        &self,
+        offset: u64,
+        n: usize,
+        result: &mut Vec<u8>,
+        scratch: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        check_fault(&self.state_, FaultOp::Read, &self.filename_)?;
+        self.target_.positioned_read(offset, n, result, scratch)
+    }
+
+    fn use_direct_io(&self) -> bool {
+        self.target_.use_direct_io()
+    }
+
+    fn get_required_buffer_alignment(&self) -> usize {
+        self.target_.get_required_buffer_alignment()
+    }
+}
+
+#[derive(Debug)]
+pub struct FaultInjectionRandomAccessFile<R: RandomAccessFile> {
+    target_: R,
+    filename_: String,
+    state_: SharedState,
+}
+
+impl<R: RandomAccessFile> RandomAccessFile for FaultInjectionRandomAccessFile<R> {
+    fn read(
+        &self,
+        offset: u64,
+        n: usize,
+        result: &mut Vec<u8>,
+        scratch: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        check_fault(&self.state_, FaultOp::Read, &self.filename_)?;
+        self.target_.read(offset, n, result, scratch)
+    }
+
+    fn prefetch(&self, offset: u64, n: usize) -> Result<(), Error> {
+        self.target_.prefetch(offset, n)
+    }
+
+    fn use_direct_io(&self) -> bool {
+        self.target_.use_direct_io()
+    }
+
+    fn get_required_buffer_alignment(&self) -> usize {
+        self.target_.get_required_buffer_alignment()
+    }
+}
+
+#[derive(Debug)]
+pub struct FaultInjectionDirectory<D: Directory> {
+    target_: D,
+    dirname_: String,
+    state_: SharedState,
+}
+
+impl<D: Directory> Directory for FaultInjectionDirectory<D> {
+    fn fsync(&self) -> Result<(), Error> {
+        if !self.state_.lock().unwrap().filesystem_active_ {
+            return Err(filesystem_inactive(&self.dirname_));
+        }
+        self.target_.fsync()?;
+        self.state_
+            .lock()
+            .unwrap()
+            .dir_new_files_
+            .remove(&self.dirname_);
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), Error> {
+        self.target_.close()
+    }
+}
+
+// A wrapper around an Env that tracks how much of every file it created has
+// been synced, so that drop_unsynced_file_data can simulate a crash by
+// throwing away everything written after the last sync and every file
+// created since its directory was last fsynced. It can also make
+// chosen append, sync or read calls fail with EIO.
+pub struct FaultInjectionEnv<E: Env> {
+    target_: E,
+    state_: SharedState,
+}
+
+impl<E: Env> FaultInjectionEnv<E> {
+    pub fn new(target: E) -> FaultInjectionEnv<E> {
+        FaultInjectionEnv {
+            target_: target,
+            state_: Arc::new(Mutex::new(FaultState {
+                files_: HashMap::new(),
+                calls_: HashMap::new(),
+                faults_: HashMap::new(),
+                filesystem_active_: true,
+                dir_new_files_: HashMap::new(),
+            })),
+        }
+    }
+
+    pub fn target(&self) -> &E {
+        &self.target_
+    }
+
+    // Number of calls made so far to op, across all files of this env.
+    pub fn call_count(&self, op: FaultOp) -> usize {
+        let state = self.state_.lock().unwrap();
+        *state.calls_.get(&op).unwrap_or(&0)
+    }
+
+    // Make the nth call (1-based, counted like call_count) to op fail with
+    // EIO. Each injected error fires once.
+    pub fn inject_error(&self, op: FaultOp, nth_call: usize) {
+        let mut state = self.state_.lock().unwrap();
+        state.faults_.entry(op).or_default().insert(nth_call);
+    }
+
+    pub fn clear_errors(&self) {
+        self.state_.lock().unwrap().faults_.clear();
+    }
+
+    // While the filesystem is inactive every append, sync, flush and read
+    // fails, as if the machine had lost power.
+    pub fn set_filesystem_active(&self, active: bool) {
+        self.state_.lock().unwrap().filesystem_active_ = active;
+    }
+
+    pub fn is_filesystem_active(&self) -> bool {
+        self.state_.lock().unwrap().filesystem_active_
+    }
+
+    // Truncate every tracked file back to the size it had at its last
+    // successful sync.
+    pub fn drop_unsynced_file_data(&self) -> Result<(), Error> {
+        let mut state = self.state_.lock().unwrap();
+        for (fname, file) in state.files_.iter_mut() {
+            if file.pos_ <= file.pos_at_last_sync_ {
+                continue;
+            }
+            let mut f = self
+                .target_
+                .reopen_writable_file(fname, EnvOptions::default())?;
+            f.truncate(file.pos_at_last_sync_)?;
+            f.close()?;
+            file.pos_ = file.pos_at_last_sync_;
+        }
+        Ok(())
+    }
+
+    // Delete every file created or renamed since its directory was last
+    // fsynced, as a crash would lose the directory entry.
+    pub fn delete_files_created_after_last_dir_sync(&self) -> Result<(), Error> {
+        // Entries are dropped only once deleted, so a failure leaves the rest
+        // to a later call.
+        let mut state = self.state_.lock().unwrap();
+        let FaultState {
+            ref mut dir_new_files_,
+            ref mut files_,
+            ..
+        } = *state;
+        for files in dir_new_files_.values_mut() {
+            for fname in std::mem::take(files) {
+                match self.target_.delete_file(&fname) {
+                    Err(ref e) if e.subcode() != SubCode::PathNotFound => {
+                        files.insert(fname);
+                        return Err(e.clone());
+                    }
+                    _ => {}
+                }
+                files_.remove(&fname);
+            }
+        }
+        dir_new_files_.retain(|_, files| !files.is_empty());
+        Ok(())
+    }
+}
+
+impl<E: Env> Env for FaultInjectionEnv<E> {
+    type WritableFile = FaultInjectionWritableFile<E::WritableFile>;
+    type SequentialFile = FaultInjectionSequentialFile<E::SequentialFile>;
+    type RandomAccessFile = FaultInjectionRandomAccessFile<E::RandomAccessFile>;
+    type Directory = FaultInjectionDirectory<E::Directory>;
+    type FileLock = E::FileLock;
+
+    fn new_sequential_file(
+        &self,
+        fname: &str,
+        options: EnvOptions,
+    ) -> Result<Self::SequentialFile, Error> {
+        if !self.is_filesystem_active() {
+            return Err(filesystem_inactive(fname));
+        }
+        let target = self.target_.new_sequential_file(fname, options)?;
+        Ok(FaultInjectionSequentialFile {
+            target_: target,
+            filename_: fname.to_string(),
+            state_: self.state_.clone(),
+        })
+    }
+
+    fn new_random_access_file(
+        &self,
+        fname: &str,
+        options: EnvOptions,
+    ) -> Result<Self::RandomAccessFile, Error> {
+        if !self.is_filesystem_active() {
+            return Err(filesystem_inactive(fname));
+        }
+        let target = self.target_.new_random_access_file(fname, options)?;
+        Ok(FaultInjectionRandomAccessFile {
+            target_: target,
+            filename_: fname.to_string(),
+            state_: self.state_.clone(),
+        })
+    }
+
+    fn new_writable_file(
+        &self,
+        fname: &str,
+        options: EnvOptions,
+    ) -> Result<Self::WritableFile, Error> {
+        if !self.is_filesystem_active() {
+            return Err(filesystem_inactive(fname));
+        }
+        let target = self.target_.new_writable_file(fname, options)?;
+        let mut state = self.state_.lock().unwrap();
+        state.files_.insert(fname.to_string(), FileState::default());
+        state.add_new_file(fname);
+        Ok(FaultInjectionWritableFile {
+            target_: target,
+            filename_: fname.to_string(),
+            state_: self.state_.clone(),
+        })
+    }
+
+    fn reopen_writable_file(
+        &self,
+        fname: &str,
+        options: EnvOptions,
+    ) -> Result<Self::WritableFile, Error> {
+        if !self.is_filesystem_active() {
+            return Err(filesystem_inactive(fname));
+        }
+        let size = self.target_.get_file_size(fname);
+        let exists = size.is_ok();
+        let size = size.unwrap_or(0);
+        let target = self.target_.reopen_writable_file(fname, options)?;
+        // Whatever was already in the file is treated as durable.
+        let mut state = self.state_.lock().unwrap();
+        state.files_.entry(fname.to_string()).or_insert(FileState {
+            pos_: size as usize,
+            pos_at_last_sync_: size as usize,
+        });
+        if !exists {
+            state.add_new_file(fname);
+        }
+        Ok(FaultInjectionWritableFile {
+            target_: target,
+            filename_: fname.to_string(),
+            state_: self.state_.clone(),
+        })
+    }
+
+    fn new_directory(&self, name: &str) -> Result<Self::Directory, Error> {
+        let target = self.target_.new_directory(name)?;
+        Ok(FaultInjectionDirectory {
+            target_: target,
+            dirname_: normalize_dir(name),
+            state_: self.state_.clone(),
+        })
+    }
+
+    fn file_exists(&self, fname: &str) -> Result<(), Error> {
+        self.target_.file_exists(fname)
+    }
+
+    fn get_children(&self, dir: &str) -> Result<Vec<String>, Error> {
+        self.target_.get_children(dir)
+    }
+
+    fn delete_file(&self, fname: &str) -> Result<(), Error> {
+        if !self.is_filesystem_active() {
+            return Err(filesystem_inactive(fname));
+        }
+        self.target_.delete_file(fname)?;
+        let mut state = self.state_.lock().unwrap();
+        state.files_.remove(fname);
+        state.remove_new_file(fname);
+        Ok(())
+    }
+
+    fn create_dir(&self, dirname: &str) -> Result<(), Error> {
+        self.target_.create_dir(dirname)
+    }
+
+    fn create_dir_if_missing(&self, dirname: &str) -> Result<(), Error> {
+        self.target_.create_dir_if_missing(dirname)
+    }
+
+    fn delete_dir(&self, dirname: &str) -> Result<(), Error> {
+        self.target_.delete_dir(dirname)
+    }
+
+    fn get_file_size(&self, fname: &str) -> Result<u64, Error> {
+        self.target_.get_file_size(fname)
+    }
+
+    fn get_file_modification_time(&self, fname: &str) -> Result<u64, Error> {
+        self.target_.get_file_modification_time(fname)
+    }
+
+    fn rename_file(&self, src: &str, target: &str) -> Result<(), Error> {
+        if !self.is_filesystem_active() {
+            return Err(filesystem_inactive(src));
+        }
+        self.target_.rename_file(src, target)?;
+        let mut state = self.state_.lock().unwrap();
+        if let Some(file) = state.files_.remove(src) {
+            state.files_.insert(target.to_string(), file);
+        }
+        // the new name is not durable until target's directory is fsynced
+        state.remove_new_file(src);
+        state.add_new_file(target);
+        Ok(())
+    }
+
+    fn lock_file(&self, fname: &str) -> Result<Self::FileLock, Error> {
+        self.target_.lock_file(fname)
+    }
+
+    fn unlock_file(&self, lock: Self::FileLock) -> Result<(), Error> {
+        self.target_.unlock_file(lock)
+    }
+
+    fn now_micros(&self) -> u64 {
+        self.target_.now_micros()
+    }
+
+    fn now_nanos(&self) -> u64 {
+        self.target_.now_nanos()
+    }
+
+    fn sleep_for_microseconds(&self, micros: u64) {
+        self.target_.sleep_for_microseconds(micros)
+    }
+}
+
+#[test]
+fn test_fault_injection_env() {
+    use crate::env::mem_env::MemEnv;
+    use crate::util::status::Code;
+
+    let env = FaultInjectionEnv::new(MemEnv::new());
+    let mut file = env.new_writable_file("f", EnvOptions::default()).unwrap();
+    assert!(file.append(vec![1, 2, 3]).is_ok());
+    assert!(file.sync().is_ok());
+    assert!(file.append(vec![4, 5]).is_ok());
+
+    env.inject_error(FaultOp::Append, env.call_count(FaultOp::Append) + 1);
+    let e = file.append(vec![6]).unwrap_err();
+    assert_eq!(e.code(), Code::KIOError);
+    // the injected error fires only once
+    assert!(file.append(vec![6]).is_ok());
+
+    env.inject_error(FaultOp::Sync, env.call_count(FaultOp::Sync) + 1);
+    assert!(file.sync().is_err());
+
+    // simulate a crash: only the synced prefix survives
+    env.set_filesystem_active(false);
+    assert!(file.append(vec![7]).is_err());
+    assert!(env.drop_unsynced_file_data().is_ok());
+    env.set_filesystem_active(true);
+
+    let size = env.get_file_size("f").unwrap();
+    assert_eq!(size, 3);
+
+    let mut reader = env.new_sequential_file("f", EnvOptions::default()).unwrap();
+    env.inject_error(FaultOp::Read, env.call_count(FaultOp::Read) + 1);
+    let mut result = Vec::new();
+    let mut scratch = Vec::new();
+    assert!(reader.read(10, &mut result, &mut scratch).is_err());
+    assert!(reader.read(10, &mut result, &mut scratch).is_ok());
+    assert_eq!(result, vec![1, 2, 3]);
+}
+
+#[test]
+fn test_delete_files_created_after_last_dir_sync() {
+    use crate::env::mem_env::MemEnv;
+    use crate::util::status::Code;
+
+    let env = FaultInjectionEnv::new(MemEnv::new());
+    for fname in &["/dir/a", "/dir/b", "/other/c"] {
+        let file = env.new_writable_file(fname, EnvOptions::default()).unwrap();
+        assert!(file.sync().is_ok());
+    }
+    // a file already gone from the target counts as deleted
+    assert!(env.target().delete_file("/dir/b").is_ok());
+    assert!(env.new_directory("/other").unwrap().fsync().is_ok());
+
+    assert!(env.delete_files_created_after_last_dir_sync().is_ok());
+    assert_eq!(
+        env.file_exists("/dir/a").unwrap_err().code(),
+        Code::KNotFound
+    );
+    assert!(env.file_exists("/other/c").is_ok());
+    assert!(env.state_.lock().unwrap().dir_new_files_.is_empty());
+    // nothing is left to delete the second time
+    assert!(env.delete_files_created_after_last_dir_sync().is_ok());
+}
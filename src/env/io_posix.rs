@@ -1,11 +1,15 @@
 use crate::env;
 use crate::env::k_default_page_size;
-use crate::env::{SequentialFile, WritableFile};
-use crate::util::status::{Code, State};
+use crate::env::{Directory, RandomAccessFile, SequentialFile, WritableFile};
+use crate::util::aligned_buffer::{truncate_to_page_boundary, AlignedBuffer};
+use crate::util::status::Error;
 use libc::c_int;
+use std::alloc::{self, Layout};
+use std::cmp::{max, min};
 use std::ffi::CString;
+use std::io;
 use std::os::raw::c_char;
-use std::usize;
+use std::slice;
 
 pub fn clearerr(stream: *mut libc::FILE) {
     extern "C" {
@@ -23,7 +27,7 @@ unsafe fn posix_fread_unlocked(
     nobj: libc::size_t,
     stream: *mut libc::FILE,
 ) -> libc::size_t {
-    return libc::fread(ptr, size, nobj, stream);
+    libc::fread(ptr, size, nobj, stream)
 }
 
 #[cfg(any(target_os = "linux"))]
@@ -33,10 +37,10 @@ unsafe fn posix_fread_unlocked(
     nobj: libc::size_t,
     stream: *mut libc::FILE,
 ) -> libc::size_t {
-    return libc::fread_unlocked(ptr, size, nobj, stream);
+    libc::fread_unlocked(ptr, size, nobj, stream)
 }
 
-fn SetFD_CLOEXEC(fd: i32, options: env::EnvOptions) {
+pub(crate) fn set_fd_cloexec(fd: i32, options: env::EnvOptions) {
     if options.set_fd_cloexec && fd > 0 {
         unsafe {
             libc::fcntl(
@@ -88,19 +92,116 @@ unsafe fn errno_location() -> *const c_int {
     __errno_location()
 }
 
+pub(crate) fn nul_in_file_name(fname: &str) -> Error {
+    Error::InvalidArgument(format!("{}: file name contains a nul byte", fname))
+}
+
+// An owned file descriptor, closed when dropped.
+#[derive(Debug)]
+pub(crate) struct FileDescriptor {
+    fd_: i32,
+}
+
+impl FileDescriptor {
+    // open(2) the file, retrying on EINTR. Failures are reported as IOErrors
+    // naming the operation, the file and the errno text.
+    pub(crate) fn open(
+        fname: &str,
+        flags: i32,
+        context: &str,
+        options: &env::EnvOptions,
+    ) -> Result<FileDescriptor, Error> {
+        let path = CString::new(fname).map_err(|_| nul_in_file_name(fname))?;
+        loop {
+            let fd = unsafe { libc::open(path.as_ptr(), flags, 0o644) };
+            if fd >= 0 {
+                set_fd_cloexec(fd, options.clone());
+                return Ok(FileDescriptor { fd_: fd });
+            }
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EINTR) {
+                return Err(io_error(context, fname, err));
+            }
+        }
+    }
+
+    // The raw descriptor, or -1 once closed.
+    pub(crate) fn raw(&self) -> i32 {
+        self.fd_
+    }
+
+    // Close now rather than on drop, so that the error can be reported.
+    pub(crate) fn close(&mut self) -> io::Result<()> {
+        let fd = self.fd_;
+        self.fd_ = -1;
+        if fd >= 0 && unsafe { libc::close(fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn into_raw(mut self) -> i32 {
+        let fd = self.fd_;
+        self.fd_ = -1;
+        fd
+    }
+}
+
+impl Drop for FileDescriptor {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+// A stdio stream owning its descriptor, fclose()d when dropped.
+#[derive(Debug)]
+struct PosixFileStream {
+    file_: *mut libc::FILE,
+}
+
+impl PosixFileStream {
+    fn fdopen(fd: FileDescriptor) -> io::Result<PosixFileStream> {
+        let fd = fd.into_raw();
+        loop {
+            let file = unsafe { libc::fdopen(fd, b"r\0".as_ptr() as *const c_char) };
+            if !file.is_null() {
+                return Ok(PosixFileStream { file_: file });
+            }
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EINTR) {
+                unsafe {
+                    libc::close(fd);
+                }
+                return Err(err);
+            }
+        }
+    }
+}
+
+impl Drop for PosixFileStream {
+    fn drop(&mut self) {
+        unsafe {
+            libc::fclose(self.file_);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct PosixWritableFile {
     filename_: String,
     use_direct_io_: bool,
-    fd_: i32,
+    fd_: FileDescriptor,
     preallocation_block_size_: usize,
     last_preallocated_block_: usize,
+    allow_fallocate_: bool,
+    fallocate_with_keep_size_: bool,
     filesize_: usize,
     logical_sector_size_: usize,
+    strict_bytes_per_sync_: bool,
 }
 
 #[cfg(target_os = "macos")]
-fn get_flag() -> i32 {
+fn get_flag(_use_direct_writes: bool) -> i32 {
     libc::O_CREAT
 }
 
@@ -111,195 +212,374 @@ fn get_flag() -> i32 {
     target_os = "linux",
     target_os = "netbsd"
 ))]
-fn get_flag() -> i32 {
-    libc::O_CREAT | libc::O_DIRECT
+fn get_flag(use_direct_writes: bool) -> i32 {
+    if use_direct_writes {
+        libc::O_CREAT | libc::O_DIRECT
+    } else {
+        libc::O_CREAT
+    }
 }
 
-fn get_logical_buffer_size() -> usize {
-    if cfg!(not(target_os = "linux")) {
+// Returns the logical block size of the device backing fd, which is the
+// alignment O_DIRECT requires for offsets, lengths and buffers.
+#[cfg(target_os = "linux")]
+fn get_logical_buffer_size(fd: i32) -> usize {
+    let mut buf: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut buf) } == -1 {
         return k_default_page_size;
-    } else {
+    }
+    let major = libc::major(buf.st_dev);
+    let minor = libc::minor(buf.st_dev);
+    if major == 0 {
+        // Unnamed devices (e.g. non-device mounts such as tmpfs) have no
+        // entry in /sys/dev/block/.
         return k_default_page_size;
-        //Todo: support linux
     }
+    let device_dir = match std::fs::canonicalize(format!("/sys/dev/block/{}:{}", major, minor)) {
+        Ok(path) => path,
+        Err(_) => return k_default_page_size,
+    };
+    // Partitions like sda3 or nvme0n1p1 do not have a `queue/` subdir, only
+    // their parent device sda and nvme0n1 have it.
+    //   /sys/dev/block/8:3 -> ../../block/sda/sda3
+    //   /sys/dev/block/259:4 -> ../../devices/.../nvme/nvme0/nvme0n1/nvme0n1p1
+    let mut queue_dir = device_dir.clone();
+    if let (Some(child), Some(parent)) = (
+        device_dir.file_name().and_then(|c| c.to_str()),
+        device_dir
+            .parent()
+            .and_then(|p| p.file_name())
+            .and_then(|p| p.to_str()),
+    ) {
+        if parent != "block" && (!child.starts_with("nvme") || child.contains('p')) {
+            queue_dir = device_dir.parent().unwrap().to_path_buf();
+        }
+    }
+    let size = std::fs::read_to_string(queue_dir.join("queue/logical_block_size"))
+        .ok()
+        .and_then(|s| s.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    if size != 0 && (size & (size - 1)) == 0 {
+        return size;
+    }
+    k_default_page_size
 }
 
-fn IsSectorAligned(off: usize, sector_size: usize) -> bool {
-    return off % sector_size == 0;
+#[cfg(not(target_os = "linux"))]
+fn get_logical_buffer_size(_fd: i32) -> usize {
+    k_default_page_size
 }
 
-impl WritableFile for PosixWritableFile {
-    fn new(filename: String, reopen: bool, preallocation_block_size: usize) -> PosixWritableFile {
-        let fd;
-        let flag = if reopen {
-            get_flag() | libc::O_APPEND | libc::O_RDWR
-        } else {
-            get_flag() | libc::O_TRUNC | libc::O_RDWR
+fn is_sector_aligned(off: usize, sector_size: usize) -> bool {
+    off % sector_size == 0
+}
+
+// Open with O_DIRECT if direct is set, falling back to buffered I/O when the
+// filesystem rejects O_DIRECT with EINVAL, as tmpfs does on older kernels.
+// Returns the descriptor and whether it is opened for direct I/O.
+pub(crate) fn open_with_buffered_fallback<F>(
+    direct: bool,
+    mut open: F,
+) -> Result<(FileDescriptor, bool), Error>
+where
+    F: FnMut(bool) -> Result<FileDescriptor, Error>,
+{
+    match open(direct) {
+        Err(Error::IOError { ref source, .. })
+            if direct && source.raw_os_error() == Some(libc::EINVAL) =>
+        {
+            Ok((open(false)?, false))
+        }
+        s => s.map(|fd| (fd, direct)),
+    }
+}
+
+impl PosixWritableFile {
+    pub fn new(
+        filename: String,
+        reopen: bool,
+        preallocation_block_size: usize,
+        options: &env::EnvOptions,
+    ) -> Result<PosixWritableFile, Error> {
+        let open = |direct| {
+            let flag = if reopen {
+                get_flag(direct) | libc::O_APPEND | libc::O_RDWR
+            } else {
+                get_flag(direct) | libc::O_TRUNC | libc::O_RDWR
+            };
+            FileDescriptor::open(&filename, flag, "While open a file for appending", options)
         };
+        let (fd, use_direct_io) = open_with_buffered_fallback(options.use_direct_writes, open)?;
+        #[cfg(target_os = "macos")]
         unsafe {
-            fd = libc::open(
-                CString::from_vec_unchecked(filename.clone().into_bytes()).as_ptr(),
-                flag,
-                0o644,
-            );
+            if use_direct_io && libc::fcntl(fd.raw(), libc::F_NOCACHE, 1) == -1 {
+                return Err(io_error(
+                    "While fcntl NoCache",
+                    &filename,
+                    io::Error::last_os_error(),
+                ));
+            }
+        }
+        let mut filesize = 0;
+        if reopen {
+            let mut buf: libc::stat = unsafe { std::mem::zeroed() };
+            if unsafe { libc::fstat(fd.raw(), &mut buf) } < 0 {
+                return Err(io_error(
+                    "While fstat",
+                    &filename,
+                    io::Error::last_os_error(),
+                ));
+            }
+            filesize = buf.st_size as usize;
         }
-        PosixWritableFile {
+        let logical_sector_size = get_logical_buffer_size(fd.raw());
+        Ok(PosixWritableFile {
             filename_: filename,
-            use_direct_io_: true,
+            use_direct_io_: use_direct_io,
             fd_: fd,
             preallocation_block_size_: preallocation_block_size,
             last_preallocated_block_: 0,
-            filesize_: 0,
-            logical_sector_size_: get_logical_buffer_size(),
-        }
+            allow_fallocate_: options.allow_fallocate,
+            // With O_APPEND, writes go to the end of the file, which must not
+            // move past the data when space is preallocated.
+            fallocate_with_keep_size_: options.fallocate_with_keep_size || reopen,
+            filesize_: filesize,
+            logical_sector_size_: logical_sector_size,
+            strict_bytes_per_sync_: options.strict_bytes_per_sync,
+        })
     }
 
-    fn append(&mut self, data: Vec<u8>) -> State {
-        let State: isize;
-        println!("write {:?}", data);
+    fn error(&self, context: &str) -> Error {
+        io_error(context, &self.filename_, io::Error::last_os_error())
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub(crate) fn fd(&self) -> i32 {
+        self.fd_.raw()
+    }
+
+    // O_DIRECT requires the offset, the length and the buffer address to be
+    // aligned to the logical sector size. Offsets and lengths are the
+    // caller's responsibility; data that does not start on an aligned
+    // address is staged through an aligned buffer.
+    fn aligned_for_direct_io(&self, data: &[u8]) -> Option<AlignedScratch> {
+        assert!(is_sector_aligned(data.len(), self.logical_sector_size_));
+        if data.is_empty() || is_sector_aligned(data.as_ptr() as usize, self.logical_sector_size_) {
+            return None;
+        }
+        let scratch = AlignedScratch::new(data.len(), self.logical_sector_size_);
         unsafe {
-            State = libc::write(self.fd_, data.as_ptr() as *const libc::c_void, data.len());
+            std::ptr::copy_nonoverlapping(data.as_ptr(), scratch.ptr_, data.len());
         }
-        if State < 0 {
-            return State::new(Code::KIOError, "cannot append".to_string(), "".to_string());
+        Some(scratch)
+    }
+}
+
+impl WritableFile for PosixWritableFile {
+    fn append(&mut self, data: Vec<u8>) -> Result<(), Error> {
+        let mut src = data.as_ptr();
+        let staged = if self.use_direct_io() {
+            self.aligned_for_direct_io(&data)
+        } else {
+            None
+        };
+        if let Some(ref scratch) = staged {
+            src = scratch.ptr_;
+        }
+        let mut left = data.len();
+        while left != 0 {
+            let done = unsafe { libc::write(self.fd_.raw(), src as *const libc::c_void, left) };
+            if done < 0 {
+                if unsafe { *errno_location() } == libc::EINTR {
+                    continue;
+                }
+                return Err(self.error("While appending to file"));
+            }
+            left -= done as usize;
+            src = unsafe { src.offset(done) };
         }
         self.filesize_ += data.len();
-        return State::ok();
+        Ok(())
     }
 
-    fn sync(&self) -> State {
-        let State: i32;
-        unsafe {
-            State = libc::fsync(self.fd_);
-        }
-        if State < 0 {
-            return State::new(Code::KIOError, "cannot sync".to_string(), "".to_string());
+    fn sync(&self) -> Result<(), Error> {
+        if unsafe { libc::fsync(self.fd_.raw()) } < 0 {
+            return Err(self.error("While fsync"));
         }
-        return State::ok();
+        Ok(())
     }
 
-    fn close(&self) -> State {
-        let State: i32;
-        unsafe {
-            State = libc::close(self.fd_);
+    fn close(&mut self) -> Result<(), Error> {
+        let mut s = Ok(());
+        if self.last_preallocated_block_ > 0 {
+            // Trim the space preallocated past the end of the data.
+            if unsafe { libc::ftruncate(self.fd_.raw(), self.filesize_ as libc::off_t) } < 0 {
+                s = Err(self.error(&format!("While ftruncate file to size {}", self.filesize_)));
+            }
+            // Some filesystems keep the blocks of a KEEP_SIZE preallocation
+            // past the end of the file after ftruncate; release them.
+            #[cfg(target_os = "linux")]
+            unsafe {
+                let allocated = self.preallocation_block_size_ * self.last_preallocated_block_;
+                if allocated > self.filesize_ {
+                    libc::fallocate(
+                        self.fd_.raw(),
+                        libc::FALLOC_FL_KEEP_SIZE | libc::FALLOC_FL_PUNCH_HOLE,
+                        self.filesize_ as libc::off_t,
+                        (allocated - self.filesize_) as libc::off_t,
+                    );
+                }
+            }
+            self.last_preallocated_block_ = 0;
         }
-        if State < 0 {
-            return State::new(Code::KIOError, "cannot close".to_string(), "".to_string());
+        if let Err(e) = self.fd_.close() {
+            if s.is_ok() {
+                s = Err(io_error(
+                    "While closing file after writing",
+                    &self.filename_,
+                    e,
+                ));
+            }
         }
-        return State::ok();
+        s
     }
 
     #[cfg(target_os = "linux")]
-    fn range_sync(&self, offset: i64, nbytes: i64) -> State {
-        let State: i32;
-        unsafe {
-            State = libc::sync_file_range(self.fd_, offset, nbytes, libc::SYNC_FILE_RANGE_WRITE);
-        }
-        if State < 0 {
-            return State::new(
-                Code::KIOError,
-                "cannot sync_file_range".to_string(),
-                "".to_string(),
-            );
-        }
-        return State::ok();
+    fn range_sync(&self, offset: i64, nbytes: i64) -> Result<(), Error> {
+        sync_file_range(
+            &self.fd_,
+            &self.filename_,
+            offset,
+            nbytes,
+            self.strict_bytes_per_sync_,
+        )
     }
 
     #[cfg(target_os = "linux")]
-    fn allocate(&self, offset: i64, len: i64) -> State {
-        let State: i32;
-        unsafe {
-            State = libc::fallocate(self.fd_, libc::FALLOC_FL_KEEP_SIZE, offset, len);
+    fn allocate(&self, offset: i64, len: i64) -> Result<(), Error> {
+        if !self.allow_fallocate_ {
+            return Ok(());
         }
-        if State < 0 {
-            return State::new(
-                Code::KIOError,
-                "cannot allocate".to_string(),
-                "".to_string(),
-            );
+        let mode = if self.fallocate_with_keep_size_ {
+            libc::FALLOC_FL_KEEP_SIZE
+        } else {
+            0
+        };
+        loop {
+            if unsafe { libc::fallocate(self.fd_.raw(), mode, offset, len) } == 0 {
+                return Ok(());
+            }
+            match unsafe { *errno_location() } {
+                libc::EINTR => continue,
+                libc::EOPNOTSUPP | libc::ENOSYS => {
+                    return Err(Error::NotSupported(format!(
+                        "fallocate {}: not supported by the filesystem",
+                        self.filename_
+                    )))
+                }
+                _ => {
+                    return Err(
+                        self.error(&format!("While fallocate offset {} len {}", offset, len))
+                    )
+                }
+            }
         }
-        return State::ok();
     }
 
     #[cfg(target_os = "linux")]
     fn prepare_write(&mut self, offset: usize, len: usize) {
-        if self.preallocation_block_size_ == 0 {
+        if self.preallocation_block_size_ == 0 || !self.allow_fallocate_ {
             return;
         }
         let block_size = self.preallocation_block_size_;
         let new_last_preallocated_block = (offset + len + block_size - 1) / block_size;
         if new_last_preallocated_block > self.last_preallocated_block_ {
             let num_spanned_blocks = new_last_preallocated_block - self.last_preallocated_block_;
-            self.allocate(
+            let s = self.allocate(
                 (block_size * self.last_preallocated_block_) as i64,
                 (block_size * num_spanned_blocks) as i64,
             );
+            if let Err(Error::NotSupported(_)) = s {
+                // Writes still work, they just aren't preallocated.
+                self.allow_fallocate_ = false;
+                return;
+            }
+            // Other failures are not fatal either; the write will report
+            // running out of space if that is the cause.
             self.last_preallocated_block_ = new_last_preallocated_block;
         }
     }
 
-    fn flush(&self) -> State {
-        return State::ok();
+    fn set_preallocation_block_size(&mut self, size: usize) {
+        self.preallocation_block_size_ = size;
+    }
+
+    fn get_preallocation_status(&self) -> (usize, usize) {
+        (
+            self.preallocation_block_size_,
+            self.last_preallocated_block_,
+        )
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
     }
 
     fn use_direct_io(&self) -> bool {
-        return self.use_direct_io_;
+        self.use_direct_io_
     }
 
     fn fcntl(&self) -> bool {
-        return unsafe { libc::fcntl(self.fd_, libc::F_GETFL) != -1 };
+        unsafe { libc::fcntl(self.fd_.raw(), libc::F_GETFL) != -1 }
     }
 
-    fn truncate(&mut self, size: usize) -> State {
-        let State: i32;
-        unsafe {
-            State = libc::ftruncate(self.fd_, size as i64);
+    fn truncate(&mut self, size: usize) -> Result<(), Error> {
+        if unsafe { libc::ftruncate(self.fd_.raw(), size as i64) } < 0 {
+            return Err(self.error(&format!("While ftruncate file to size {}", size)));
         }
-        if State < 0 {
-            return State::new(
-                Code::KIOError,
-                "cannot truncate".to_string(),
-                "".to_string(),
-            );
-        } else {
-            self.filesize_ = size;
-        }
-        return State::ok();
+        self.filesize_ = size;
+        Ok(())
+    }
+
+    fn get_file_size(&self) -> usize {
+        self.filesize_
     }
 
     fn get_required_buffer_alignment(&self) -> usize {
         self.logical_sector_size_
     }
 
-    fn positioned_append(&mut self, mut data: Vec<u8>, mut offset: usize) -> State {
-        if self.use_direct_io() {
-            //println!("offset {} get_logical_buffer_size {}",offset,get_logical_buffer_size());
-            //assert!(IsSectorAligned(offset, get_logical_buffer_size()));
-            //println!("data len {} get_logical_buffer_size {}",data.len(),get_logical_buffer_size());
-            //assert!(IsSectorAligned(data.len(), get_logical_buffer_size()));
-            //assert!(IsSectorAligned(data.as_ptr() as usize,get_logical_buffer_size()));
+    fn positioned_append(&mut self, data: Vec<u8>, mut offset: usize) -> Result<(), Error> {
+        let mut src = data.as_ptr();
+        let staged = if self.use_direct_io() {
+            assert!(is_sector_aligned(offset, self.logical_sector_size_));
+            self.aligned_for_direct_io(&data)
+        } else {
+            None
+        };
+        if let Some(ref scratch) = staged {
+            src = scratch.ptr_;
         }
         assert!(offset <= usize::MAX);
-        let mut src = data.as_mut_ptr();
         let mut left = data.len();
 
         let mut done;
         while left != 0 {
             unsafe {
-                done = libc::pwrite(self.fd_, src as *const libc::c_void, left, offset as i64);
+                done = libc::pwrite(
+                    self.fd_.raw(),
+                    src as *const libc::c_void,
+                    left,
+                    offset as i64,
+                );
             }
             if done < 1 {
                 unsafe {
-                    if *errno_location() as i32 == libc::EINTR {
+                    if *errno_location() == libc::EINTR {
                         continue;
                     }
                 }
-                return State::new(
-                    Code::KIOError,
-                    format!("While pwrite to file at offset {}", offset.to_string()),
-                    "".to_string(),
-                );
-                //IOError("While pwrite to file at offset " + ToString(offset),filename_, errno);
+                return Err(self.error(&format!("While pwrite to file at offset {}", offset)));
             }
             left -= done as usize;
             offset += done as usize;
@@ -307,13 +587,15 @@ impl WritableFile for PosixWritableFile {
                 src = src.offset(done);
             }
         }
-        self.filesize_ = offset;
-        return State::ok();
+        if offset > self.filesize_ {
+            self.filesize_ = offset;
+        }
+        Ok(())
     }
 }
 
 #[cfg(target_os = "macos")]
-fn get_flag_for_posix_sequential_file() -> i32 {
+pub(crate) fn get_flag_for_posix_sequential_file() -> i32 {
     0
 }
 
@@ -324,130 +606,172 @@ fn get_flag_for_posix_sequential_file() -> i32 {
     target_os = "linux",
     target_os = "netbsd"
 ))]
-fn get_flag_for_posix_sequential_file() -> i32 {
+pub(crate) fn get_flag_for_posix_sequential_file() -> i32 {
     libc::O_DIRECT
 }
 
+// Buffered reads go through a stdio stream, which owns the descriptor;
+// direct reads use the descriptor itself.
+#[derive(Debug)]
+enum SequentialHandle {
+    Stream(PosixFileStream),
+    Direct(FileDescriptor),
+}
+
 #[derive(Debug)]
 pub struct PosixSequentialFile {
     filename_: String,
-    fd_: i32,
+    handle_: SequentialHandle,
     use_direct_io_: bool,
     logical_sector_size_: usize,
-    file_: *mut libc::FILE,
+    // Read position for direct I/O, where there is no FILE stream.
+    offset_: u64,
 }
 
-impl Default for PosixSequentialFile {
-    fn default() -> PosixSequentialFile {
-        PosixSequentialFile {
-            filename_: "".to_string(),
-            fd_: 0,
-            use_direct_io_: true,
-            logical_sector_size_: 0,
-            file_: 0 as *mut libc::FILE,
-        }
-    }
-}
-
-impl SequentialFile for PosixSequentialFile {
-    fn new(filename: String, options: env::EnvOptions, ptr: &mut PosixSequentialFile) -> State {
-        let mut fd = -1;
+impl PosixSequentialFile {
+    pub fn new(filename: String, options: &env::EnvOptions) -> Result<PosixSequentialFile, Error> {
+        let use_direct_io = options.use_direct_reads && !options.use_mmap_reads;
         let mut flag = libc::O_RDONLY;
-        let mut file = 0 as *mut libc::FILE;
-        if options.use_direct_reads && !options.use_mmap_reads {
+        if use_direct_io {
             if cfg!(feature = "CIBO_LITE") {
-                return State::new(
-                    Code::KIOError,
+                return Err(Error::NotSupported(
                     "Direct I/O not supported in cibo lite".to_string(),
-                    "".to_string(),
-                );
+                ));
             }
             flag = flag | get_flag_for_posix_sequential_file();
         }
-        //flag = get_flag_for_posix_sequential_file();
-        loop {
-            unsafe {
-                fd = libc::open(
-                    CString::from_vec_unchecked(filename.clone().into_bytes()).as_ptr(),
-                    flag,
-                    0o644,
-                );
-                if !(fd < 0 && *errno_location() as i32 == libc::EINTR) {
-                    break;
-                }
-                println!("{} {} {}", "wait for open", fd, *errno_location());
-            }
-        }
-        if fd < 0 {
-            return State::new(
-                Code::KIOError,
-                "While opening a file for sequentially reading".to_string(),
-                "".to_string(),
-            );
-        }
-
-        SetFD_CLOEXEC(fd, options.clone());
-        if options.use_direct_reads && !options.use_mmap_reads {
+        let fd = FileDescriptor::open(
+            &filename,
+            flag,
+            "While opening a file for sequentially reading",
+            options,
+        )?;
+        let logical_sector_size = get_logical_buffer_size(fd.raw());
+        let handle = if use_direct_io {
             #[cfg(target_os = "macos")]
             unsafe {
-                if libc::fcntl(fd, libc::F_NOCACHE, 1) == -1 {
-                    libc::close(fd);
-                    println!("While fcntl NoCache");
-                    return State::new(
-                        Code::KIOError,
-                        "While fcntl NoCache".to_string(),
-                        "".to_string(),
-                    );
-                    //IOError("While fcntl NoCache", fname, errno);
+                if libc::fcntl(fd.raw(), libc::F_NOCACHE, 1) == -1 {
+                    return Err(io_error(
+                        "While fcntl NoCache",
+                        &filename,
+                        io::Error::last_os_error(),
+                    ));
                 }
             }
+            SequentialHandle::Direct(fd)
         } else {
-            unsafe {
-                loop {
-                    file = libc::fdopen(fd, b"r".as_ptr() as *const c_char);
-                    if !(file == 0 as *mut libc::FILE && *errno_location() as i32 == libc::EINTR) {
-                        break;
-                    }
-                }
-                if file == 0 as *mut libc::FILE {
-                    libc::close(fd);
-                    println!("While opening a file for sequentially read");
-                    return State::new(
-                        Code::KIOError,
-                        "While opening a file for sequentially read".to_string(),
-                        "".to_string(),
-                    );
+            match PosixFileStream::fdopen(fd) {
+                Ok(stream) => SequentialHandle::Stream(stream),
+                Err(e) => {
+                    return Err(io_error(
+                        "While opening file for sequentially read",
+                        &filename,
+                        e,
+                    ))
                 }
             }
-        }
-        println!("file new {:?}", file);
-        *ptr = PosixSequentialFile {
-            filename_: filename,
-            fd_: fd,
-            file_: file,
-            use_direct_io_: true,
-            logical_sector_size_: get_logical_buffer_size(),
         };
-        return State::ok();
+        Ok(PosixSequentialFile {
+            filename_: filename,
+            handle_: handle,
+            use_direct_io_: use_direct_io,
+            logical_sector_size_: logical_sector_size,
+            offset_: 0,
+        })
     }
 
-    fn skip(&self, n: i64) -> State {
-        unsafe {
-            if libc::fseek(self.file_, n, libc::SEEK_CUR) > 0 {
-                // return IOError("While fseek to skip " + ToString(n) + " bytes", filename_, errno);
-                return State::new(
-                    Code::KIOError,
-                    "While fseek to skip ".to_string() + &n.to_string() + &" bytes".to_string(),
-                    "".to_string(),
-                );
+    fn stream(&self) -> *mut libc::FILE {
+        match self.handle_ {
+            SequentialHandle::Stream(ref stream) => stream.file_,
+            SequentialHandle::Direct(_) => panic!("no stream for a direct I/O file"),
+        }
+    }
+
+    fn fd(&self) -> i32 {
+        match self.handle_ {
+            SequentialHandle::Stream(ref stream) => unsafe { libc::fileno(stream.file_) },
+            SequentialHandle::Direct(ref fd) => fd.raw(),
+        }
+    }
+
+    // Direct I/O counterpart of the fread() path: read the sector aligned
+    // range covering [offset_, offset_ + n) and copy out the requested bytes.
+    fn direct_read(&mut self, n: usize, result: &mut Vec<u8>) -> Result<(), Error> {
+        let alignment = self.logical_sector_size_;
+        let offset = self.offset_ as usize;
+        let aligned_offset = truncate_to_page_boundary(alignment, offset);
+        let offset_advance = offset - aligned_offset;
+        let size = roundup(offset + n, alignment) - aligned_offset;
+        let mut tmp = Vec::new();
+        self.positioned_read(aligned_offset as u64, size, &mut tmp, &mut Vec::new())?;
+        if offset_advance < tmp.len() {
+            let r = min(tmp.len() - offset_advance, n);
+            result.extend_from_slice(&tmp[offset_advance..offset_advance + r]);
+            self.offset_ += r as u64;
+        }
+        Ok(())
+    }
+}
+
+impl SequentialFile for PosixSequentialFile {
+    fn skip(&mut self, n: i64) -> Result<i64, Error> {
+        let mut buf: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstat(self.fd(), &mut buf) } < 0 {
+            return Err(io_error(
+                "While fstat",
+                &self.filename_,
+                io::Error::last_os_error(),
+            ));
+        }
+        let pos = if self.use_direct_io_ {
+            self.offset_
+        } else {
+            let pos = unsafe { libc::ftell(self.stream()) };
+            if pos < 0 {
+                return Err(io_error(
+                    "While ftell",
+                    &self.filename_,
+                    io::Error::last_os_error(),
+                ));
             }
-            return State::ok();
+            pos as u64
+        };
+        // Stop at the end of the file like MemEnv does, and refuse to move
+        // before the start.
+        let target = match pos.checked_add_signed(n) {
+            Some(target) => min(target, max(pos, buf.st_size as u64)),
+            None => {
+                return Err(Error::InvalidArgument(format!(
+                    "{}: cannot skip {} bytes from offset {}",
+                    self.filename_, n, pos
+                )))
+            }
+        };
+        if self.use_direct_io_ {
+            self.offset_ = target;
+        } else if unsafe { libc::fseek(self.stream(), target as libc::c_long, libc::SEEK_SET) } != 0
+        {
+            return Err(io_error(
+                &format!("While fseek to skip {} bytes", n),
+                &self.filename_,
+                io::Error::last_os_error(),
+            ));
         }
+        Ok(target as i64 - pos as i64)
     }
 
-    fn read(&mut self, n: usize, result: &mut Vec<u8>, _scratch: &mut Vec<u8>) -> State {
-        let mut s: State = State::ok();
-        let mut r = 0;
+    fn read(
+        &mut self,
+        n: usize,
+        result: &mut Vec<u8>,
+        _scratch: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        if self.use_direct_io_ {
+            return self.direct_read(n, result);
+        }
+        let file = self.stream();
+        let mut s = Ok(());
+        let mut r;
         let mut scratch: Vec<u8> = vec![0; n];
         unsafe {
             loop {
@@ -455,36 +779,686 @@ impl SequentialFile for PosixSequentialFile {
                     scratch.as_mut_ptr() as *mut libc::c_void,
                     1 as libc::size_t,
                     n as libc::size_t,
-                    self.file_,
+                    file,
                 );
 
-                if !(libc::ferror(self.file_) > 0
-                    && ((*errno_location()) as i32 == libc::EINTR)
-                    && r == 0)
-                {
+                if !(libc::ferror(file) > 0 && (*errno_location() == libc::EINTR) && r == 0) {
                     break;
                 }
             }
-            println!("fread result len  {:?}", scratch.len());
-            //println!("scratch {:?}", scratch);
+            scratch.truncate(r);
             result.extend_from_slice(scratch.as_slice());
-            println!("fread size {}", r);
-            result.split_off(r);
 
             if r < n {
-                println!("feof {}", libc::feof(self.file_));
-                if libc::feof(self.file_) == 0 {
-                    s = State::new(
-                        Code::KIOError,
-                        "While reading file sequentially".to_string(),
-                        "".to_string(),
-                    );
+                if libc::feof(file) == 0 {
+                    // A partial read with an error
+                    s = Err(io_error(
+                        "While reading file sequentially",
+                        &self.filename_,
+                        io::Error::last_os_error(),
+                    ));
                 } else {
-                    println!("clear eof err");
-                    clearerr(self.file_);
+                    // fread() sets the EOF indicator; clear it so later reads
+                    // see data appended since.
+                    clearerr(file);
+                }
+            }
+        }
+        s
+    }
+
+    fn positioned_read(
+        &self,
+        offset: u64,
+        n: usize,
+        result: &mut Vec<u8>,
+        _scratch: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        if !self.use_direct_io_ {
+            // A buffered file reads through its stream position only.
+            return Err(Error::NotSupported(format!(
+                "positioned_read {}: not opened for direct I/O",
+                self.filename_
+            )));
+        }
+        if !is_sector_aligned(offset as usize, self.logical_sector_size_)
+            || !is_sector_aligned(n, self.logical_sector_size_)
+        {
+            return Err(Error::InvalidArgument(format!(
+                "positioned_read {}: offset {} and length {} must be multiples of {}",
+                self.filename_, offset, n, self.logical_sector_size_
+            )));
+        }
+        let mut buf = AlignedBuffer::default();
+        buf.alignment(self.logical_sector_size_);
+        buf.allocate_new_buffer(n, false);
+        let ptr = buf.buffer_start();
+        let mut left = n;
+        let mut r = 0;
+        while left > 0 {
+            let done = unsafe {
+                libc::pread(
+                    self.fd(),
+                    ptr.add(r) as *mut libc::c_void,
+                    left,
+                    (offset as usize + r) as libc::off_t,
+                )
+            };
+            if done < 0 {
+                if unsafe { *errno_location() } == libc::EINTR {
+                    continue;
+                }
+                return Err(io_error(
+                    &format!("While pread {} bytes from offset {}", n, offset),
+                    &self.filename_,
+                    io::Error::last_os_error(),
+                ));
+            }
+            if done == 0 {
+                // EOF
+                break;
+            }
+            r += done as usize;
+            left -= done as usize;
+            if !is_sector_aligned(done as usize, self.logical_sector_size_) {
+                // A partial sector is only returned at the end of the file.
+                break;
+            }
+        }
+        buf.size(r);
+        result.extend_from_slice(&buf.read(0, r));
+        Ok(())
+    }
+
+    fn use_direct_io(&self) -> bool {
+        self.use_direct_io_
+    }
+
+    fn get_required_buffer_alignment(&self) -> usize {
+        self.logical_sector_size_
+    }
+}
+
+pub(crate) fn io_error(context: &str, fname: &str, err: io::Error) -> Error {
+    Error::io_error(format!("{} {}", context, fname), err)
+}
+
+// An aligned heap allocation, used as the target of O_DIRECT reads.
+struct AlignedScratch {
+    ptr_: *mut u8,
+    layout_: Layout,
+}
+
+impl AlignedScratch {
+    fn new(size: usize, alignment: usize) -> AlignedScratch {
+        let layout = Layout::from_size_align(size, alignment).unwrap();
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        AlignedScratch {
+            ptr_: ptr,
+            layout_: layout,
+        }
+    }
+}
+
+impl Drop for AlignedScratch {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr_, self.layout_) }
+    }
+}
+
+// pread() based random-access
+#[derive(Debug)]
+pub struct PosixRandomAccessFile {
+    filename_: String,
+    fd_: FileDescriptor,
+    use_direct_io_: bool,
+    logical_sector_size_: usize,
+}
+
+impl PosixRandomAccessFile {
+    pub(crate) fn new(
+        filename: String,
+        fd: FileDescriptor,
+        options: &env::EnvOptions,
+    ) -> PosixRandomAccessFile {
+        assert!(!options.use_direct_reads || !options.use_mmap_reads);
+        let logical_sector_size = get_logical_buffer_size(fd.raw());
+        PosixRandomAccessFile {
+            filename_: filename,
+            fd_: fd,
+            use_direct_io_: options.use_direct_reads,
+            logical_sector_size_: logical_sector_size,
+        }
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub(crate) fn fd(&self) -> i32 {
+        self.fd_.raw()
+    }
+
+    // Read exactly n bytes at offset into buf unless EOF is hit first.
+    // Returns the number of bytes read.
+    fn pread_fully(&self, buf: *mut u8, n: usize, offset: u64) -> Result<usize, Error> {
+        let mut left = n;
+        let mut done_total = 0;
+        while left > 0 {
+            let r = unsafe {
+                libc::pread(
+                    self.fd_.raw(),
+                    buf.add(done_total) as *mut libc::c_void,
+                    left,
+                    (offset as usize + done_total) as libc::off_t,
+                )
+            };
+            if r <= 0 {
+                if r == -1 && unsafe { *errno_location() } == libc::EINTR {
+                    continue;
+                }
+                if r == 0 {
+                    // EOF
+                    break;
+                }
+                return Err(io_error(
+                    &format!("While pread offset {} len {}", offset, n),
+                    &self.filename_,
+                    io::Error::last_os_error(),
+                ));
+            }
+            done_total += r as usize;
+            left -= r as usize;
+            if self.use_direct_io_ && !(r as usize).is_multiple_of(self.logical_sector_size_) {
+                // Bytes reads don't fill sectors. Should only happen at the end
+                // of the file.
+                break;
+            }
+        }
+        Ok(done_total)
+    }
+}
+
+impl RandomAccessFile for PosixRandomAccessFile {
+    fn read(
+        &self,
+        offset: u64,
+        n: usize,
+        result: &mut Vec<u8>,
+        _scratch: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        if !self.use_direct_io_ {
+            let start = result.len();
+            result.resize(start + n, 0);
+            return match self.pread_fully(result[start..].as_mut_ptr(), n, offset) {
+                Ok(r) => {
+                    result.truncate(start + r);
+                    Ok(())
+                }
+                Err(e) => {
+                    result.truncate(start);
+                    Err(e)
+                }
+            };
+        }
+
+        // O_DIRECT needs the offset, length and buffer aligned to the logical
+        // sector size, so read the enclosing aligned range and copy out.
+        let alignment = self.logical_sector_size_;
+        let aligned_offset = offset as usize - offset as usize % alignment;
+        let offset_advance = offset as usize - aligned_offset;
+        let aligned_len = (offset_advance + n).div_ceil(alignment) * alignment;
+        let buf = AlignedScratch::new(aligned_len.max(alignment), alignment);
+        match self.pread_fully(buf.ptr_, aligned_len, aligned_offset as u64) {
+            Ok(r) => {
+                if r > offset_advance {
+                    let copy = min(n, r - offset_advance);
+                    let data = unsafe { slice::from_raw_parts(buf.ptr_.add(offset_advance), copy) };
+                    result.extend_from_slice(data);
                 }
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn prefetch(&self, offset: u64, n: usize) -> Result<(), Error> {
+        if self.use_direct_io_ {
+            return Ok(());
+        }
+        let r = unsafe { libc::readahead(self.fd_.raw(), offset as libc::off64_t, n) };
+        if r != 0 {
+            return Err(io_error(
+                &format!("While prefetching offset {} len {}", offset, n),
+                &self.filename_,
+                io::Error::last_os_error(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn use_direct_io(&self) -> bool {
+        self.use_direct_io_
+    }
+
+    fn get_required_buffer_alignment(&self) -> usize {
+        self.logical_sector_size_
+    }
+}
+
+// mmap() based random-access
+#[derive(Debug)]
+pub struct PosixMmapReadableFile {
+    filename_: String,
+    mmapped_region_: *mut libc::c_void,
+    length_: usize,
+}
+
+impl PosixMmapReadableFile {
+    // base[0,length-1] contains the mmapped contents of the file. A zero
+    // length file has no mapping and a null base.
+    pub fn new(filename: String, base: *mut libc::c_void, length: usize) -> PosixMmapReadableFile {
+        PosixMmapReadableFile {
+            filename_: filename,
+            mmapped_region_: base,
+            length_: length,
+        }
+    }
+}
+
+impl Drop for PosixMmapReadableFile {
+    fn drop(&mut self) {
+        if self.length_ > 0 {
+            unsafe {
+                libc::munmap(self.mmapped_region_, self.length_);
             }
         }
-        return s;
+    }
+}
+
+impl RandomAccessFile for PosixMmapReadableFile {
+    fn read(
+        &self,
+        offset: u64,
+        n: usize,
+        result: &mut Vec<u8>,
+        _scratch: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        if offset as usize > self.length_ {
+            return Err(io_error(
+                &format!(
+                    "While mmap read offset {} larger than file length {}",
+                    offset, self.length_
+                ),
+                &self.filename_,
+                io::Error::from_raw_os_error(libc::EINVAL),
+            ));
+        }
+        let n = min(n, self.length_ - offset as usize);
+        if n > 0 {
+            let data = unsafe {
+                slice::from_raw_parts((self.mmapped_region_ as *const u8).add(offset as usize), n)
+            };
+            result.extend_from_slice(data);
+        }
+        Ok(())
+    }
+
+    fn prefetch(&self, offset: u64, n: usize) -> Result<(), Error> {
+        if offset as usize >= self.length_ {
+            return Ok(());
+        }
+        let page = k_default_page_size;
+        let start = offset as usize - offset as usize % page;
+        let len = min(n + offset as usize - start, self.length_ - start);
+        let r = unsafe {
+            libc::posix_madvise(
+                (self.mmapped_region_ as *mut u8).add(start) as *mut libc::c_void,
+                len,
+                libc::POSIX_MADV_WILLNEED,
+            )
+        };
+        if r != 0 {
+            return Err(io_error(
+                &format!("While madvise offset {} len {}", offset, n),
+                &self.filename_,
+                io::Error::from_raw_os_error(r),
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn page_size() -> usize {
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if size <= 0 {
+        k_default_page_size
+    } else {
+        size as usize
+    }
+}
+
+// mmap() based writable file. The file is grown and mapped in chunks of
+// map_size_ bytes, which double up to 1MB; on close the file is truncated
+// back to the number of bytes actually appended.
+#[derive(Debug)]
+pub struct PosixMmapFile {
+    filename_: String,
+    fd_: FileDescriptor,
+    page_size_: usize,
+    // How much extra memory to map at a time
+    map_size_: usize,
+    // The mapped region, or null when nothing is mapped
+    base_: *mut u8,
+    // File offset of base_
+    region_offset_: usize,
+    region_len_: usize,
+    // Physical size of the file, including the mapped tail not yet written
+    allocated_size_: usize,
+    // Logical size of the file
+    filesize_: usize,
+    strict_bytes_per_sync_: bool,
+}
+
+impl PosixMmapFile {
+    pub(crate) fn new(
+        filename: String,
+        fd: FileDescriptor,
+        page_size: usize,
+        options: &env::EnvOptions,
+    ) -> PosixMmapFile {
+        assert!((page_size & (page_size - 1)) == 0);
+        PosixMmapFile {
+            filename_: filename,
+            fd_: fd,
+            page_size_: page_size,
+            map_size_: roundup(65536, page_size),
+            base_: std::ptr::null_mut(),
+            region_offset_: 0,
+            region_len_: 0,
+            allocated_size_: 0,
+            filesize_: 0,
+            strict_bytes_per_sync_: options.strict_bytes_per_sync,
+        }
+    }
+
+    fn unmap_current_region(&mut self) -> Result<(), Error> {
+        if !self.base_.is_null() {
+            let r = unsafe { libc::munmap(self.base_ as *mut libc::c_void, self.region_len_) };
+            self.base_ = std::ptr::null_mut();
+            self.region_len_ = 0;
+            if r != 0 {
+                return Err(io_error(
+                    "While munmap",
+                    &self.filename_,
+                    io::Error::last_os_error(),
+                ));
+            }
+            // Increase the amount we map the next time, but capped at 1MB
+            if self.map_size_ < (1 << 20) {
+                self.map_size_ *= 2;
+            }
+        }
+        Ok(())
+    }
+
+    fn map_new_region(&mut self) -> Result<(), Error> {
+        assert!(self.base_.is_null());
+        // mmap offsets must be page aligned, so the region may start below
+        // the current end of the file.
+        let region_offset = self.filesize_ - self.filesize_ % self.page_size_;
+        let region_end = region_offset + self.map_size_;
+        if self.allocated_size_ < region_end {
+            if unsafe { libc::ftruncate(self.fd_.raw(), region_end as libc::off_t) } < 0 {
+                return Err(io_error(
+                    "While ftruncate",
+                    &self.filename_,
+                    io::Error::last_os_error(),
+                ));
+            }
+            self.allocated_size_ = region_end;
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                self.map_size_,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                self.fd_.raw(),
+                region_offset as libc::off_t,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io_error(
+                "MMap failed on",
+                &self.filename_,
+                io::Error::last_os_error(),
+            ));
+        }
+        self.base_ = ptr as *mut u8;
+        self.region_offset_ = region_offset;
+        self.region_len_ = self.map_size_;
+        Ok(())
+    }
+
+    fn msync(&self) -> Result<(), Error> {
+        if self.base_.is_null() || self.filesize_ == self.region_offset_ {
+            return Ok(());
+        }
+        let len = roundup(self.filesize_ - self.region_offset_, self.page_size_);
+        let r = unsafe { libc::msync(self.base_ as *mut libc::c_void, len, libc::MS_SYNC) };
+        if r < 0 {
+            return Err(io_error(
+                "While msync",
+                &self.filename_,
+                io::Error::last_os_error(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Start writing back nbytes at offset of the file. With strict, the call
+// covers everything written so far with WAIT_BEFORE, which makes it wait
+// for the writeback of earlier ranges to finish before starting this one.
+#[cfg(target_os = "linux")]
+fn sync_file_range(
+    fd: &FileDescriptor,
+    filename: &str,
+    offset: i64,
+    nbytes: i64,
+    strict: bool,
+) -> Result<(), Error> {
+    let r = if strict {
+        unsafe {
+            libc::sync_file_range(
+                fd.raw(),
+                0,
+                offset + nbytes,
+                libc::SYNC_FILE_RANGE_WAIT_BEFORE | libc::SYNC_FILE_RANGE_WRITE,
+            )
+        }
+    } else {
+        unsafe { libc::sync_file_range(fd.raw(), offset, nbytes, libc::SYNC_FILE_RANGE_WRITE) }
+    };
+    if r < 0 {
+        return Err(io_error(
+            &format!("While sync_file_range offset {} bytes {}", offset, nbytes),
+            filename,
+            io::Error::last_os_error(),
+        ));
+    }
+    Ok(())
+}
+
+#[inline]
+fn roundup(x: usize, y: usize) -> usize {
+    x.div_ceil(y) * y
+}
+
+impl WritableFile for PosixMmapFile {
+    fn append(&mut self, data: Vec<u8>) -> Result<(), Error> {
+        let mut src = 0;
+        let mut left = data.len();
+        while left > 0 {
+            if self.base_.is_null() || self.filesize_ == self.region_offset_ + self.region_len_ {
+                self.unmap_current_region()?;
+                self.map_new_region()?;
+            }
+            let avail = self.region_offset_ + self.region_len_ - self.filesize_;
+            let n = min(left, avail);
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    data.as_ptr().add(src),
+                    self.base_.add(self.filesize_ - self.region_offset_),
+                    n,
+                );
+            }
+            self.filesize_ += n;
+            src += n;
+            left -= n;
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        if unsafe { libc::fdatasync(self.fd_.raw()) } < 0 {
+            return Err(io_error(
+                "While fdatasync mmapped file",
+                &self.filename_,
+                io::Error::last_os_error(),
+            ));
+        }
+        self.msync()
+    }
+
+    fn fsync(&self) -> Result<(), Error> {
+        if unsafe { libc::fsync(self.fd_.raw()) } < 0 {
+            return Err(io_error(
+                "While fsync mmaped file",
+                &self.filename_,
+                io::Error::last_os_error(),
+            ));
+        }
+        self.msync()
+    }
+
+    fn close(&mut self) -> Result<(), Error> {
+        if self.fd_.raw() < 0 {
+            return Ok(());
+        }
+        let mut s = self.unmap_current_region();
+        if s.is_ok() && self.allocated_size_ > self.filesize_ {
+            // Trim the extra space at the end of the file
+            if unsafe { libc::ftruncate(self.fd_.raw(), self.filesize_ as libc::off_t) } < 0 {
+                s = Err(io_error(
+                    "While ftruncate mmaped file",
+                    &self.filename_,
+                    io::Error::last_os_error(),
+                ));
+            } else {
+                self.allocated_size_ = self.filesize_;
+            }
+        }
+        if let Err(e) = self.fd_.close() {
+            if s.is_ok() {
+                s = Err(io_error("While closing mmapped file", &self.filename_, e));
+            }
+        }
+        s
+    }
+
+    // Data is copied straight into the mapping, so there is nothing to flush.
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn fcntl(&self) -> bool {
+        self.fd_.raw() >= 0 && unsafe { libc::fcntl(self.fd_.raw(), libc::F_GETFL) != -1 }
+    }
+
+    fn truncate(&mut self, size: usize) -> Result<(), Error> {
+        self.unmap_current_region()?;
+        if unsafe { libc::ftruncate(self.fd_.raw(), size as libc::off_t) } < 0 {
+            return Err(io_error(
+                "While ftruncate mmaped file",
+                &self.filename_,
+                io::Error::last_os_error(),
+            ));
+        }
+        self.allocated_size_ = size;
+        self.filesize_ = size;
+        Ok(())
+    }
+
+    fn get_required_buffer_alignment(&self) -> usize {
+        self.page_size_
+    }
+
+    // Pages written through a shared mapping are dirty in the page cache
+    // like written ones, so they are written back the same way.
+    #[cfg(target_os = "linux")]
+    fn range_sync(&self, offset: i64, nbytes: i64) -> Result<(), Error> {
+        sync_file_range(
+            &self.fd_,
+            &self.filename_,
+            offset,
+            nbytes,
+            self.strict_bytes_per_sync_,
+        )
+    }
+
+    fn get_file_size(&self) -> usize {
+        self.filesize_
+    }
+}
+
+impl Drop for PosixMmapFile {
+    fn drop(&mut self) {
+        if self.fd_.raw() >= 0 {
+            let _ = self.close();
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PosixDirectory {
+    dirname_: String,
+    fd_: FileDescriptor,
+}
+
+impl PosixDirectory {
+    pub fn new(dirname: String, options: &env::EnvOptions) -> Result<PosixDirectory, Error> {
+        let fd = FileDescriptor::open(
+            &dirname,
+            libc::O_RDONLY | libc::O_DIRECTORY,
+            "While open directory",
+            options,
+        )?;
+        Ok(PosixDirectory {
+            dirname_: dirname,
+            fd_: fd,
+        })
+    }
+}
+
+impl Directory for PosixDirectory {
+    fn fsync(&self) -> Result<(), Error> {
+        if unsafe { libc::fsync(self.fd_.raw()) } < 0 {
+            return Err(io_error(
+                "While fsync a directory",
+                &self.dirname_,
+                io::Error::last_os_error(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), Error> {
+        if let Err(e) = self.fd_.close() {
+            return Err(io_error("While closing directory", &self.dirname_, e));
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,535 @@
+// io_uring backed files for PosixEnv, built with the `io-uring` feature.
+//
+// Appends are queued on the ring and complete asynchronously; every other
+// operation on the file first waits for the queued writes, and the first
+// failed write is reported by all later calls. multi_read submits its
+// requests as one batch. When the kernel does not support io_uring the
+// plain pread/pwrite files from io_posix are used instead.
+
+use crate::env::io_posix::{io_error, PosixRandomAccessFile, PosixWritableFile};
+use crate::env::{RandomAccessFile, ReadRequest, WritableFile};
+use crate::util::status::Error;
+use ::io_uring::{opcode, types, IoUring};
+use std::cell::RefCell;
+use std::cmp::min;
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+
+// Number of submission queue entries, and the most writes kept in flight.
+const QUEUE_DEPTH: u32 = 32;
+
+// An entry's length is a u32; longer buffers go out as several entries, the
+// rest being picked up like a short read or write.
+fn entry_len(len: usize) -> u32 {
+    min(len, u32::MAX as usize) as u32
+}
+
+// Returns file wrapped for io_uring, or file itself if a ring can't be set
+// up. Direct I/O files need aligned buffers and stay on the pwrite path.
+pub(crate) fn writable_file(file: PosixWritableFile, fname: &str) -> Box<dyn WritableFile> {
+    if file.use_direct_io() {
+        return Box::new(file);
+    }
+    match IoUring::new(QUEUE_DEPTH) {
+        Ok(ring) => Box::new(UringWritableFile::new(file, fname, ring)),
+        Err(_) => Box::new(file),
+    }
+}
+
+pub(crate) fn random_access_file(
+    file: PosixRandomAccessFile,
+    fname: &str,
+) -> Box<dyn RandomAccessFile> {
+    if file.use_direct_io() {
+        return Box::new(file);
+    }
+    match IoUring::new(QUEUE_DEPTH) {
+        Ok(ring) => Box::new(UringRandomAccessFile {
+            file_: file,
+            filename_: fname.to_string(),
+            ring_: Mutex::new(Some(ring)),
+        }),
+        Err(_) => Box::new(file),
+    }
+}
+
+// A queued write. The ring reads from data_ until the write completes.
+struct PendingWrite {
+    data_: Vec<u8>,
+    offset_: u64,
+    written_: usize,
+}
+
+struct UringWriter {
+    ring_: IoUring,
+    fd_: i32,
+    filename_: String,
+    pending_: HashMap<u64, PendingWrite>,
+    next_id_: u64,
+    // The first failed write
+    error_: Option<Error>,
+}
+
+impl UringWriter {
+    fn write(&mut self, data: Vec<u8>, offset: u64) -> Result<(), Error> {
+        while self.pending_.len() >= QUEUE_DEPTH as usize {
+            self.wait_for_completions()?;
+        }
+        let id = self.next_id_;
+        self.next_id_ += 1;
+        self.pending_.insert(
+            id,
+            PendingWrite {
+                data_: data,
+                offset_: offset,
+                written_: 0,
+            },
+        );
+        self.submit_write(id)
+    }
+
+    // Queue the unwritten part of pending write id.
+    fn submit_write(&mut self, id: u64) -> Result<(), Error> {
+        let entry = {
+            let w = &self.pending_[&id];
+            let rest = &w.data_[w.written_..];
+            opcode::Write::new(types::Fd(self.fd_), rest.as_ptr(), entry_len(rest.len()))
+                .offset(w.offset_ + w.written_ as u64)
+                .build()
+                .user_data(id)
+        };
+        // The buffer stays in pending_ until its completion is reaped.
+        while unsafe { self.ring_.submission().push(&entry) }.is_err() {
+            if let Err(e) = self.ring_.submit() {
+                // never queued, so nothing will complete it
+                self.pending_.remove(&id);
+                return Err(io_error("While submitting to io_uring", &self.filename_, e));
+            }
+        }
+        // A failed submit leaves the entry queued; the next wait submits it.
+        let _ = self.ring_.submit();
+        Ok(())
+    }
+
+    fn wait_for_completions(&mut self) -> Result<(), Error> {
+        if let Err(e) = self.ring_.submit_and_wait(1) {
+            if e.raw_os_error() != Some(libc::EINTR) {
+                let err = io_error("While waiting for io_uring", &self.filename_, e);
+                self.abandon_pending(&err);
+                return Err(err);
+            }
+        }
+        let completed: Vec<(u64, i32)> = self
+            .ring_
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+            .collect();
+        for (id, res) in completed {
+            if res < 0 {
+                self.pending_.remove(&id);
+                self.fail(io::Error::from_raw_os_error(-res));
+                continue;
+            }
+            if res == 0 {
+                self.pending_.remove(&id);
+                self.fail(io::Error::new(io::ErrorKind::WriteZero, "write returned 0"));
+                continue;
+            }
+            let done = {
+                let w = self.pending_.get_mut(&id).unwrap();
+                w.written_ += res as usize;
+                w.written_ == w.data_.len()
+            };
+            if done {
+                self.pending_.remove(&id);
+            } else {
+                // short write, queue the rest
+                self.submit_write(id)?;
+            }
+        }
+        Ok(())
+    }
+
+    // The queued writes can no longer be reaped, but the kernel may still
+    // read from their buffers, so those are leaked rather than freed. The
+    // file fails from now on.
+    fn abandon_pending(&mut self, err: &Error) {
+        for (_, w) in self.pending_.drain() {
+            std::mem::forget(w.data_);
+        }
+        if self.error_.is_none() {
+            self.error_ = Some(err.clone());
+        }
+    }
+
+    fn fail(&mut self, err: io::Error) {
+        if self.error_.is_none() {
+            self.error_ = Some(io_error("While appending to file", &self.filename_, err));
+        }
+    }
+
+    // Wait for all queued writes and return the first error.
+    fn drain(&mut self) -> Result<(), Error> {
+        while !self.pending_.is_empty() {
+            self.wait_for_completions()?;
+        }
+        match self.error_ {
+            Some(ref e) => Err(e.clone()),
+            None => Ok(()),
+        }
+    }
+
+    fn fsync(&mut self) -> Result<(), Error> {
+        self.drain()?;
+        let entry = opcode::Fsync::new(types::Fd(self.fd_))
+            .build()
+            .user_data(u64::MAX);
+        unsafe {
+            // drain() left the submission queue empty
+            self.ring_.submission().push(&entry).unwrap();
+        }
+        loop {
+            match self.ring_.submit_and_wait(1) {
+                Ok(_) => break,
+                Err(ref e) if e.raw_os_error() == Some(libc::EINTR) => continue,
+                Err(e) => return Err(io_error("While fsync", &self.filename_, e)),
+            }
+        }
+        let res = self
+            .ring_
+            .completion()
+            .next()
+            .map(|cqe| cqe.result())
+            .unwrap_or(0);
+        if res < 0 {
+            return Err(io_error(
+                "While fsync",
+                &self.filename_,
+                io::Error::from_raw_os_error(-res),
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub struct UringWritableFile {
+    file_: PosixWritableFile,
+    writer_: RefCell<UringWriter>,
+    filesize_: usize,
+}
+
+impl UringWritableFile {
+    fn new(file: PosixWritableFile, fname: &str, ring: IoUring) -> UringWritableFile {
+        // Writes go to explicit offsets and may complete out of order, which
+        // O_APPEND (set when reopening) would turn into reordered appends.
+        unsafe {
+            let flags = libc::fcntl(file.fd(), libc::F_GETFL);
+            if flags != -1 && flags & libc::O_APPEND != 0 {
+                libc::fcntl(file.fd(), libc::F_SETFL, flags & !libc::O_APPEND);
+            }
+        }
+        let filesize = file.get_file_size();
+        UringWritableFile {
+            writer_: RefCell::new(UringWriter {
+                ring_: ring,
+                fd_: file.fd(),
+                filename_: fname.to_string(),
+                pending_: HashMap::new(),
+                next_id_: 0,
+                error_: None,
+            }),
+            file_: file,
+            filesize_: filesize,
+        }
+    }
+
+    fn drain(&self) -> Result<(), Error> {
+        self.writer_.borrow_mut().drain()
+    }
+}
+
+impl Drop for UringWritableFile {
+    fn drop(&mut self) {
+        // The kernel may still be reading from the queued buffers; drain
+        // either reaps them or leaks the ones it gives up on.
+        let _ = self.drain();
+    }
+}
+
+impl WritableFile for UringWritableFile {
+    fn append(&mut self, data: Vec<u8>) -> Result<(), Error> {
+        let mut writer = self.writer_.borrow_mut();
+        if let Some(ref e) = writer.error_ {
+            return Err(e.clone());
+        }
+        if self.file_.fd() < 0 {
+            return Err(io_error(
+                "While appending to file",
+                &writer.filename_,
+                io::Error::from_raw_os_error(libc::EBADF),
+            ));
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+        let offset = self.filesize_;
+        self.filesize_ += data.len();
+        writer.write(data, offset as u64)
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        self.writer_.borrow_mut().fsync()
+    }
+
+    fn close(&mut self) -> Result<(), Error> {
+        let mut s = self.drain();
+        if s.is_ok() && self.file_.get_preallocation_status().1 > 0 {
+            // The file trims its preallocated tail to the size it knows of,
+            // which the writes made here have not updated.
+            s = self.file_.truncate(self.filesize_);
+        }
+        let close = self.file_.close();
+        s.and(close)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.drain()
+    }
+
+    fn fcntl(&self) -> bool {
+        self.file_.fcntl()
+    }
+
+    fn truncate(&mut self, size: usize) -> Result<(), Error> {
+        self.drain()?;
+        self.file_.truncate(size)?;
+        self.filesize_ = size;
+        Ok(())
+    }
+
+    fn get_required_buffer_alignment(&self) -> usize {
+        self.file_.get_required_buffer_alignment()
+    }
+
+    // The queued writes have to reach the page cache before they can be
+    // written back; the posix file then honors strict_bytes_per_sync.
+    fn range_sync(&self, offset: i64, nbytes: i64) -> Result<(), Error> {
+        self.drain()?;
+        self.file_.range_sync(offset, nbytes)
+    }
+
+    fn allocate(&self, offset: i64, len: i64) -> Result<(), Error> {
+        self.file_.allocate(offset, len)
+    }
+
+    fn prepare_write(&mut self, offset: usize, len: usize) {
+        self.file_.prepare_write(offset, len)
+    }
+
+    fn set_preallocation_block_size(&mut self, size: usize) {
+        self.file_.set_preallocation_block_size(size)
+    }
+
+    fn get_preallocation_status(&self) -> (usize, usize) {
+        self.file_.get_preallocation_status()
+    }
+
+    fn positioned_append(&mut self, data: Vec<u8>, offset: usize) -> Result<(), Error> {
+        self.drain()?;
+        let end = offset + data.len();
+        self.file_.positioned_append(data, offset)?;
+        if end > self.filesize_ {
+            self.filesize_ = end;
+        }
+        Ok(())
+    }
+
+    fn get_file_size(&self) -> usize {
+        self.filesize_
+    }
+}
+
+pub struct UringRandomAccessFile {
+    file_: PosixRandomAccessFile,
+    filename_: String,
+    // None once a wait on the ring has failed; reads then use pread.
+    ring_: Mutex<Option<IoUring>>,
+}
+
+impl UringRandomAccessFile {
+    // Submit reqs as one batch and wait for all of them.
+    fn read_batch(&self, ring: &mut IoUring, reqs: &mut [ReadRequest]) -> Result<(), Error> {
+        for (i, req) in reqs.iter_mut().enumerate() {
+            req.result.clear();
+            req.result.resize(req.len, 0);
+            req.status = Ok(());
+            let entry = opcode::Read::new(
+                types::Fd(self.file_.fd()),
+                req.result.as_mut_ptr(),
+                entry_len(req.len),
+            )
+            .offset(req.offset)
+            .build()
+            .user_data(i as u64);
+            // reqs is at most QUEUE_DEPTH long
+            unsafe {
+                ring.submission().push(&entry).unwrap();
+            }
+        }
+        let mut done = vec![false; reqs.len()];
+        let mut remaining = reqs.len();
+        while remaining > 0 {
+            if let Err(e) = ring.submit_and_wait(1) {
+                if e.raw_os_error() != Some(libc::EINTR) {
+                    // The kernel may still fill the buffers of the reads in
+                    // flight, so they are leaked rather than freed.
+                    let err = io_error("While waiting for io_uring", &self.filename_, e);
+                    for (req, _) in reqs.iter_mut().zip(done).filter(|&(_, d)| !d) {
+                        std::mem::forget(std::mem::take(&mut req.result));
+                        req.status = Err(err.clone());
+                    }
+                    return Err(err);
+                }
+            }
+            let completed: Vec<(u64, i32)> = ring
+                .completion()
+                .map(|cqe| (cqe.user_data(), cqe.result()))
+                .collect();
+            remaining -= completed.len();
+            for (i, res) in completed {
+                done[i as usize] = true;
+                let req = &mut reqs[i as usize];
+                if res < 0 {
+                    req.result.clear();
+                    req.status = Err(io_error(
+                        &format!("While reading offset {} len {}", req.offset, req.len),
+                        &self.filename_,
+                        io::Error::from_raw_os_error(-res),
+                    ));
+                    continue;
+                }
+                let r = res as usize;
+                req.result.truncate(r);
+                if r > 0 && r < req.len {
+                    // A short read; finish it with pread, which stops at EOF.
+                    req.status = self.file_.read(
+                        req.offset + r as u64,
+                        req.len - r,
+                        &mut req.result,
+                        &mut Vec::new(),
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RandomAccessFile for UringRandomAccessFile {
+    fn read(
+        &self,
+        offset: u64,
+        n: usize,
+        result: &mut Vec<u8>,
+        scratch: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        // A single read gains nothing from the ring.
+        self.file_.read(offset, n, result, scratch)
+    }
+
+    fn multi_read(&self, reqs: &mut [ReadRequest]) -> Result<(), Error> {
+        let mut guard = self.ring_.lock().unwrap();
+        let ring = match *guard {
+            Some(ref mut ring) => ring,
+            None => return self.file_.multi_read(reqs),
+        };
+        for batch in reqs.chunks_mut(QUEUE_DEPTH as usize) {
+            if let Err(e) = self.read_batch(ring, batch) {
+                // Reads still in flight would complete into later batches.
+                *guard = None;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn prefetch(&self, offset: u64, n: usize) -> Result<(), Error> {
+        self.file_.prefetch(offset, n)
+    }
+
+    fn use_direct_io(&self) -> bool {
+        self.file_.use_direct_io()
+    }
+
+    fn get_required_buffer_alignment(&self) -> usize {
+        self.file_.get_required_buffer_alignment()
+    }
+}
+
+#[test]
+fn test_uring_writable_file() {
+    use crate::env::env_posix::PosixEnv;
+    use crate::env::{Env, EnvOptions};
+    use std::fs;
+
+    let env = PosixEnv::new();
+    let fname = format!("test_uring_writable_file_{}", std::process::id());
+    let options = EnvOptions {
+        use_mmap_writes: false,
+        ..Default::default()
+    };
+    let mut file = env.new_writable_file(&fname, options.clone()).unwrap();
+    let mut expected = Vec::new();
+    // More appends than the queue depth, so some wait for completions.
+    for i in 0..200u32 {
+        let chunk: Vec<u8> = (0..i * 7 + 1).map(|j| (i + j) as u8).collect();
+        expected.extend_from_slice(&chunk);
+        assert!(file.append(chunk).is_ok());
+    }
+    assert_eq!(file.get_file_size(), expected.len());
+    assert!(file.sync().is_ok());
+    assert!(fs::read(&fname).unwrap() == expected);
+    assert!(file.close().is_ok());
+
+    // reopened files append after the existing data
+    let mut file = env.reopen_writable_file(&fname, options).unwrap();
+    for i in 0..100u8 {
+        expected.extend_from_slice(&[i; 100]);
+        assert!(file.append(vec![i; 100]).is_ok());
+    }
+    // dropping waits for the queued writes
+    drop(file);
+    assert!(fs::read(&fname).unwrap() == expected);
+    assert!(env.delete_file(&fname).is_ok());
+}
+
+#[test]
+fn test_uring_multi_read() {
+    use crate::env::env_posix::PosixEnv;
+    use crate::env::{Env, EnvOptions};
+    use std::fs;
+
+    let env = PosixEnv::new();
+    let fname = format!("test_uring_multi_read_{}", std::process::id());
+    let data: Vec<u8> = (0..100000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(&fname, &data).unwrap();
+    let file = env
+        .new_random_access_file(&fname, EnvOptions::default())
+        .unwrap();
+
+    // More requests than the queue depth, including ones at and past EOF.
+    let mut reqs: Vec<ReadRequest> = (0..100u64)
+        .map(|i| ReadRequest::new(i * 997, 1000 + i as usize))
+        .collect();
+    reqs.push(ReadRequest::new(99990, 100));
+    reqs.push(ReadRequest::new(200000, 10));
+    assert!(file.multi_read(&mut reqs).is_ok());
+    for req in &reqs {
+        assert!(req.status.is_ok());
+        let start = (req.offset as usize).min(data.len());
+        let end = (start + req.len).min(data.len());
+        assert!(req.result == data[start..end].to_vec());
+    }
+    assert!(env.delete_file(&fname).is_ok());
+}
@@ -0,0 +1,543 @@
+use crate::env::k_default_page_size;
+use crate::env::{Directory, Env, EnvOptions, RandomAccessFile, SequentialFile, WritableFile};
+use crate::util::status::Error;
+use std::cmp::{max, min};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Carries ENOENT, as PosixEnv's errors do, so callers can match on
+// SubCode::PathNotFound regardless of the env.
+fn file_not_found(fname: &str) -> Error {
+    Error::io_error(fname, io::Error::from_raw_os_error(libc::ENOENT))
+}
+
+fn dir_prefix(dir: &str) -> String {
+    format!("{}/", dir.trim_end_matches('/'))
+}
+
+#[derive(Debug, Default)]
+struct MemFile {
+    data_: Vec<u8>,
+    // Last modification time, in seconds since the epoch of the env clock.
+    mtime_: u64,
+}
+
+// The clock is shared by the env and every file it hands out, so that
+// modification times follow sleep_for_microseconds deterministically.
+type Clock = Arc<AtomicU64>;
+
+fn now_seconds(clock: &Clock) -> u64 {
+    clock.load(Ordering::SeqCst) / 1_000_000
+}
+
+#[derive(Debug)]
+pub struct MemWritableFile {
+    file_: Arc<Mutex<MemFile>>,
+    clock_: Clock,
+    closed_: AtomicBool,
+}
+
+impl MemWritableFile {
+    // Fails like a write to a closed file descriptor would.
+    fn check_open(&self, context: &str) -> Result<(), Error> {
+        if self.closed_.load(Ordering::SeqCst) {
+            return Err(Error::io_error(
+                context,
+                io::Error::from_raw_os_error(libc::EBADF),
+            ));
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, data: &[u8], offset: usize) -> Result<(), Error> {
+        self.check_open("cannot append")?;
+        let mut file = self.file_.lock().unwrap();
+        let end = offset + data.len();
+        if file.data_.len() < end {
+            file.data_.resize(end, 0);
+        }
+        file.data_[offset..end].copy_from_slice(data);
+        file.mtime_ = now_seconds(&self.clock_);
+        Ok(())
+    }
+}
+
+impl WritableFile for MemWritableFile {
+    fn append(&mut self, data: Vec<u8>) -> Result<(), Error> {
+        let offset = self.get_file_size();
+        self.write_at(&data, offset)
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), Error> {
+        self.closed_.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn fcntl(&self) -> bool {
+        !self.closed_.load(Ordering::SeqCst)
+    }
+
+    fn truncate(&mut self, size: usize) -> Result<(), Error> {
+        self.check_open("cannot truncate")?;
+        let mut file = self.file_.lock().unwrap();
+        file.data_.resize(size, 0);
+        file.mtime_ = now_seconds(&self.clock_);
+        Ok(())
+    }
+
+    fn get_required_buffer_alignment(&self) -> usize {
+        k_default_page_size
+    }
+
+    fn range_sync(&self, _offset: i64, _nbytes: i64) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn positioned_append(&mut self, data: Vec<u8>, offset: usize) -> Result<(), Error> {
+        self.write_at(&data, offset)
+    }
+
+    fn get_file_size(&self) -> usize {
+        self.file_.lock().unwrap().data_.len()
+    }
+}
+
+#[derive(Debug)]
+pub struct MemSequentialFile {
+    file_: Arc<Mutex<MemFile>>,
+    pos_: usize,
+}
+
+impl SequentialFile for MemSequentialFile {
+    fn skip(&mut self, n: i64) -> Result<i64, Error> {
+        let size = self.file_.lock().unwrap().data_.len() as u64;
+        let pos = self.pos_ as u64;
+        let target = match pos.checked_add_signed(n) {
+            Some(target) => min(target, max(pos, size)),
+            None => {
+                return Err(Error::InvalidArgument(format!(
+                    "cannot skip {} bytes from offset {}",
+                    n, pos
+                )))
+            }
+        };
+        self.pos_ = target as usize;
+        Ok(target as i64 - pos as i64)
+    }
+
+    fn read(
+        &mut self,
+        n: usize,
+        result: &mut Vec<u8>,
+        _scratch: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        let file = self.file_.lock().unwrap();
+        let start = min(self.pos_, file.data_.len());
+        let end = min(start + n, file.data_.len());
+        result.extend_from_slice(&file.data_[start..end]);
+        self.pos_ = end;
+        Ok(())
+    }
+
+    fn positioned_read(
+        &self,
+        offset: u64,
+        n: usize,
+        result: &mut Vec<u8>,
+        _scratch: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        let file = self.file_.lock().unwrap();
+        let start = min(offset as usize, file.data_.len());
+        let end = min(start + n, file.data_.len());
+        result.extend_from_slice(&file.data_[start..end]);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct MemRandomAccessFile {
+    file_: Arc<Mutex<MemFile>>,
+}
+
+impl RandomAccessFile for MemRandomAccessFile {
+    fn read(
+        &self,
+        offset: u64,
+        n: usize,
+        result: &mut Vec<u8>,
+        _scratch: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        let file = self.file_.lock().unwrap();
+        let offset = offset as usize;
+        if offset > file.data_.len() {
+            return Err(Error::io_error(
+                "Offset greater than file size.",
+                io::Error::new(io::ErrorKind::InvalidInput, offset.to_string()),
+            ));
+        }
+        let end = min(offset + n, file.data_.len());
+        result.extend_from_slice(&file.data_[offset..end]);
+        Ok(())
+    }
+}
+
+// Directories are implicit in MemEnv and its entries are never lost, so
+// there is nothing to fsync.
+#[derive(Debug)]
+pub struct MemDirectory {}
+
+impl Directory for MemDirectory {
+    fn fsync(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+pub struct MemFileLock {
+    filename_: String,
+}
+
+// MemEnv keeps every file in memory, so tests built on top of it touch no
+// disk. Its clock starts at zero and only moves when sleep_for_microseconds
+// is called, which keeps modification times and timestamps deterministic.
+// Files don't need their directory to be created first; a directory exists
+// once it is created or holds a file.
+#[derive(Default)]
+pub struct MemEnv {
+    files_: Mutex<HashMap<String, Arc<Mutex<MemFile>>>>,
+    // Directories made by create_dir, without a trailing slash.
+    dirs_: Mutex<HashSet<String>>,
+    locked_files_: Mutex<HashSet<String>>,
+    clock_: Clock,
+}
+
+impl MemEnv {
+    pub fn new() -> MemEnv {
+        MemEnv::default()
+    }
+
+    // Whether dirname holds any file or created directory. The caller holds
+    // the dirs_ lock.
+    fn has_children(&self, dirs: &HashSet<String>, dirname: &str) -> bool {
+        let prefix = dir_prefix(dirname);
+        dirs.iter().any(|d| d.starts_with(&prefix))
+            || self
+                .files_
+                .lock()
+                .unwrap()
+                .keys()
+                .any(|f| f.starts_with(&prefix))
+    }
+
+    // Creates dirname, failing with EEXIST if a file or directory is
+    // already there unless if_missing is set and it is a directory.
+    fn make_dir(&self, dirname: &str, if_missing: bool) -> Result<(), Error> {
+        let dirname = dirname.trim_end_matches('/');
+        let mut dirs = self.dirs_.lock().unwrap();
+        let is_dir = dirs.contains(dirname) || self.has_children(&dirs, dirname);
+        if is_dir && if_missing {
+            return Ok(());
+        }
+        if is_dir || self.files_.lock().unwrap().contains_key(dirname) {
+            return Err(Error::io_error(
+                dirname,
+                io::Error::from_raw_os_error(libc::EEXIST),
+            ));
+        }
+        dirs.insert(dirname.to_string());
+        Ok(())
+    }
+
+    fn open_writable_file(&self, fname: &str, reopen: bool) -> MemWritableFile {
+        let mut files = self.files_.lock().unwrap();
+        if !reopen || !files.contains_key(fname) {
+            let file = MemFile {
+                data_: Vec::new(),
+                mtime_: now_seconds(&self.clock_),
+            };
+            files.insert(fname.to_string(), Arc::new(Mutex::new(file)));
+        }
+        MemWritableFile {
+            file_: files[fname].clone(),
+            clock_: self.clock_.clone(),
+            closed_: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Env for MemEnv {
+    type WritableFile = MemWritableFile;
+    type SequentialFile = MemSequentialFile;
+    type RandomAccessFile = MemRandomAccessFile;
+    type Directory = MemDirectory;
+    type FileLock = MemFileLock;
+
+    fn new_sequential_file(
+        &self,
+        fname: &str,
+        _options: EnvOptions,
+    ) -> Result<MemSequentialFile, Error> {
+        let files = self.files_.lock().unwrap();
+        match files.get(fname) {
+            Some(file) => Ok(MemSequentialFile {
+                file_: file.clone(),
+                pos_: 0,
+            }),
+            None => Err(file_not_found(fname)),
+        }
+    }
+
+    fn new_random_access_file(
+        &self,
+        fname: &str,
+        _options: EnvOptions,
+    ) -> Result<MemRandomAccessFile, Error> {
+        let files = self.files_.lock().unwrap();
+        match files.get(fname) {
+            Some(file) => Ok(MemRandomAccessFile {
+                file_: file.clone(),
+            }),
+            None => Err(file_not_found(fname)),
+        }
+    }
+
+    fn new_writable_file(
+        &self,
+        fname: &str,
+        _options: EnvOptions,
+    ) -> Result<MemWritableFile, Error> {
+        Ok(self.open_writable_file(fname, false))
+    }
+
+    fn reopen_writable_file(
+        &self,
+        fname: &str,
+        _options: EnvOptions,
+    ) -> Result<MemWritableFile, Error> {
+        Ok(self.open_writable_file(fname, true))
+    }
+
+    fn new_directory(&self, _name: &str) -> Result<MemDirectory, Error> {
+        Ok(MemDirectory {})
+    }
+
+    fn file_exists(&self, fname: &str) -> Result<(), Error> {
+        if self.files_.lock().unwrap().contains_key(fname) {
+            Ok(())
+        } else {
+            Err(Error::NotFound(fname.to_string()))
+        }
+    }
+
+    fn get_children(&self, dir: &str) -> Result<Vec<String>, Error> {
+        let prefix = dir_prefix(dir);
+        let dirs = self.dirs_.lock().unwrap();
+        let files = self.files_.lock().unwrap();
+        let mut children = HashSet::new();
+        for name in files.keys().chain(dirs.iter()) {
+            if name.starts_with(&prefix) {
+                let child = name[prefix.len()..].split('/').next().unwrap();
+                if !child.is_empty() {
+                    children.insert(child.to_string());
+                }
+            }
+        }
+        let mut result: Vec<String> = children.into_iter().collect();
+        result.sort();
+        Ok(result)
+    }
+
+    fn delete_file(&self, fname: &str) -> Result<(), Error> {
+        match self.files_.lock().unwrap().remove(fname) {
+            Some(_) => Ok(()),
+            None => Err(file_not_found(fname)),
+        }
+    }
+
+    fn create_dir(&self, dirname: &str) -> Result<(), Error> {
+        self.make_dir(dirname, false)
+    }
+
+    fn create_dir_if_missing(&self, dirname: &str) -> Result<(), Error> {
+        self.make_dir(dirname, true)
+    }
+
+    fn delete_dir(&self, dirname: &str) -> Result<(), Error> {
+        let dirname = dirname.trim_end_matches('/');
+        let mut dirs = self.dirs_.lock().unwrap();
+        if self.has_children(&dirs, dirname) {
+            return Err(Error::io_error(
+                dirname,
+                io::Error::from_raw_os_error(libc::ENOTEMPTY),
+            ));
+        }
+        if !dirs.remove(dirname) {
+            return Err(file_not_found(dirname));
+        }
+        Ok(())
+    }
+
+    fn get_file_size(&self, fname: &str) -> Result<u64, Error> {
+        match self.files_.lock().unwrap().get(fname) {
+            Some(file) => Ok(file.lock().unwrap().data_.len() as u64),
+            None => Err(file_not_found(fname)),
+        }
+    }
+
+    fn get_file_modification_time(&self, fname: &str) -> Result<u64, Error> {
+        match self.files_.lock().unwrap().get(fname) {
+            Some(file) => Ok(file.lock().unwrap().mtime_),
+            None => Err(file_not_found(fname)),
+        }
+    }
+
+    fn rename_file(&self, src: &str, target: &str) -> Result<(), Error> {
+        let mut files = self.files_.lock().unwrap();
+        match files.remove(src) {
+            Some(file) => {
+                files.insert(target.to_string(), file);
+                Ok(())
+            }
+            None => Err(file_not_found(src)),
+        }
+    }
+
+    fn lock_file(&self, fname: &str) -> Result<MemFileLock, Error> {
+        let mut locked_files = self.locked_files_.lock().unwrap();
+        if locked_files.contains(fname) {
+            return Err(Error::io_error(
+                format!("lock {}", fname),
+                io::Error::new(io::ErrorKind::WouldBlock, "lock is already held"),
+            ));
+        }
+        {
+            let mut files = self.files_.lock().unwrap();
+            if !files.contains_key(fname) {
+                let file = MemFile {
+                    data_: Vec::new(),
+                    mtime_: now_seconds(&self.clock_),
+                };
+                files.insert(fname.to_string(), Arc::new(Mutex::new(file)));
+            }
+        }
+        locked_files.insert(fname.to_string());
+        Ok(MemFileLock {
+            filename_: fname.to_string(),
+        })
+    }
+
+    fn unlock_file(&self, lock: MemFileLock) -> Result<(), Error> {
+        if !self.locked_files_.lock().unwrap().remove(&lock.filename_) {
+            return Err(Error::io_error(
+                format!("unlock {}", lock.filename_),
+                io::Error::other("file not locked"),
+            ));
+        }
+        Ok(())
+    }
+
+    fn now_micros(&self) -> u64 {
+        self.clock_.load(Ordering::SeqCst)
+    }
+
+    fn sleep_for_microseconds(&self, micros: u64) {
+        self.clock_.fetch_add(micros, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn test_mem_env() {
+    use crate::util::status::{Code, SubCode};
+
+    let env = MemEnv::new();
+    let mut file = env
+        .new_writable_file("/dir/f", EnvOptions::default())
+        .unwrap();
+    assert!(file.append(b"hello world".to_vec()).is_ok());
+    assert!(file.positioned_append(b"W".to_vec(), 6).is_ok());
+
+    assert_eq!(env.get_file_size("/dir/f").unwrap(), 11);
+
+    let mut reader = env
+        .new_sequential_file("/dir/f", EnvOptions::default())
+        .unwrap();
+    let mut result = Vec::new();
+    let mut scratch = Vec::new();
+    assert!(reader.skip(2).is_ok());
+    assert!(reader.read(4, &mut result, &mut scratch).is_ok());
+    assert_eq!(result, b"llo ".to_vec());
+    result.clear();
+    assert!(reader.read(100, &mut result, &mut scratch).is_ok());
+    assert_eq!(result, b"World".to_vec());
+
+    let random = env
+        .new_random_access_file("/dir/f", EnvOptions::default())
+        .unwrap();
+    result.clear();
+    assert!(random.read(6, 3, &mut result, &mut scratch).is_ok());
+    assert_eq!(result, b"Wor".to_vec());
+    assert!(random.read(12, 3, &mut result, &mut scratch).is_err());
+
+    // reopening keeps the contents, new_writable_file truncates them
+    let file = env
+        .reopen_writable_file("/dir/f", EnvOptions::default())
+        .unwrap();
+    assert_eq!(file.get_file_size(), 11);
+
+    env.sleep_for_microseconds(3_000_000);
+    assert_eq!(env.now_micros(), 3_000_000);
+    assert!(env
+        .new_writable_file("/dir/sub/g", EnvOptions::default())
+        .is_ok());
+    assert_eq!(env.get_file_modification_time("/dir/sub/g").unwrap(), 3);
+
+    let children = env.get_children("/dir").unwrap();
+    assert_eq!(children, vec!["f".to_string(), "sub".to_string()]);
+
+    assert!(env.rename_file("/dir/f", "/dir/h").is_ok());
+    assert_eq!(
+        env.file_exists("/dir/f").unwrap_err().code(),
+        Code::KNotFound
+    );
+    assert!(env.file_exists("/dir/h").is_ok());
+    assert!(env.delete_file("/dir/h").is_ok());
+    assert!(env.delete_file("/dir/h").is_err());
+    assert!(env
+        .new_sequential_file("/dir/h", EnvOptions::default())
+        .is_err());
+
+    // Directories fail like they do on a posix filesystem.
+    assert!(env.create_dir("/empty").is_ok());
+    assert!(env.create_dir("/empty").is_err());
+    assert!(env.create_dir_if_missing("/empty/").is_ok());
+    assert!(env.create_dir("/dir").is_err());
+    assert!(env.create_dir_if_missing("/dir/sub/g").is_err());
+    assert!(env.create_dir("/empty/nested").is_ok());
+    assert_eq!(
+        env.get_children("/empty").unwrap(),
+        vec!["nested".to_string()]
+    );
+    let e = env.delete_dir("/empty").unwrap_err();
+    assert_eq!(e.code(), Code::KIOError);
+    assert!(env.delete_dir("/empty/nested").is_ok());
+    assert!(env.delete_dir("/empty").is_ok());
+    assert_eq!(
+        env.delete_dir("/empty").unwrap_err().subcode(),
+        SubCode::PathNotFound
+    );
+    assert!(env.delete_dir("/dir/sub").is_err());
+
+    let lock = env.lock_file("/dir/LOCK").unwrap();
+    assert!(env.lock_file("/dir/LOCK").is_err());
+    assert!(env.unlock_file(lock).is_ok());
+    assert!(env.lock_file("/dir/LOCK").is_ok());
+}
@@ -1,11 +1,20 @@
+#[cfg(feature = "encryption")]
+pub mod env_encryption;
+pub mod env_posix;
+pub mod fault_injection_env;
 pub mod io_posix;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub mod io_uring;
+pub mod mem_env;
 
-use crate::env;
-use crate::util::status::State;
+use crate::util::rate_limiter::RateLimiter;
+use crate::util::statistics::Statistics;
+use crate::util::status::Error;
+use std::sync::Arc;
 
 pub const k_default_page_size: usize = 4 * 1024;
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum WALRecoveryMode {
     // Original levelDB recovery
     // We tolerate incomplete record in trailing data on all logs
@@ -29,18 +38,29 @@ pub enum WALRecoveryMode {
     kSkipAnyCorruptedRecords = 0x03,
 }
 
+// Priority for requesting bytes from a rate limiter. Writes at IOTotal are
+// not limited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IOPriority {
+    IOLow = 0,
+    IOHigh = 1,
+    IOTotal = 2,
+}
+
 #[derive(Debug, Clone)]
 pub struct EnvOptions {
     // If true, then use mmap to read data
     pub use_mmap_reads: bool,
 
-    // If true, then use mmap to write data
+    // If true, then use mmap to write data. Ignored when use_direct_writes
+    // is set.
     pub use_mmap_writes: bool,
 
     // If true, then use O_DIRECT for reading data
     pub use_direct_reads: bool,
 
-    // If true, then use O_DIRECT for writing data
+    // If true, then use O_DIRECT for writing data. This wins over
+    // use_mmap_writes, which is on by default.
     pub use_direct_writes: bool,
 
     // If false, fallocate() calls are bypassed
@@ -59,7 +79,32 @@ pub struct EnvOptions {
 
     pub writable_file_max_buffer_size: usize,
 
+    // If non-zero, WritableFileWriter asks the OS to write back the file
+    // incrementally, every bytes_per_sync bytes, instead of leaving it all
+    // to the final sync. Used for table and MANIFEST files.
     pub bytes_per_sync: usize,
+
+    // Like bytes_per_sync, for WAL and MANIFEST files. optimize_for_log_write
+    // turns it into the bytes_per_sync of the returned options.
+    pub wal_bytes_per_sync: usize,
+
+    // The size a WAL is expected to reach before the db moves on to a new
+    // one, usually the write buffer size. new_log_file preallocates blocks
+    // sized for it; 0 turns WAL preallocation off.
+    pub max_log_file_size: usize,
+
+    // If true, each incremental write back waits for the previous ones to
+    // finish, which bounds the amount of unsynced data at the cost of
+    // blocking the writer.
+    pub strict_bytes_per_sync: bool,
+
+    // If not None, writes through a WritableFileWriter with a priority other
+    // than IOTotal request their bytes from this limiter first.
+    pub rate_limiter: Option<Arc<dyn RateLimiter>>,
+
+    // If not None, readers and writers created with these options record
+    // their I/O into it.
+    pub statistics: Option<Arc<Statistics>>,
 }
 
 impl Default for EnvOptions {
@@ -68,47 +113,84 @@ impl Default for EnvOptions {
             use_mmap_reads: false,
             use_mmap_writes: true,
             use_direct_reads: false,
-            use_direct_writes: true,
+            use_direct_writes: false,
             allow_fallocate: true,
             set_fd_cloexec: true,
             fallocate_with_keep_size: true,
 
             writable_file_max_buffer_size: 1024 * 1024,
             bytes_per_sync: 0,
+            wal_bytes_per_sync: 0,
+            max_log_file_size: 64 << 20,
+            strict_bytes_per_sync: false,
+            rate_limiter: None,
+            statistics: None,
+        }
+    }
+}
+
+// The preallocation block size for a file expected to grow to
+// expected_file_size bytes: EnvOptions::max_log_file_size for a WAL, the
+// write buffer or target file size for a table. A little slack keeps a file
+// that ends up slightly larger from needing a second block.
+pub fn preallocation_block_size(expected_file_size: usize) -> usize {
+    expected_file_size + expected_file_size / 10
+}
+
+impl EnvOptions {
+    // Options for creating a WAL or MANIFEST file and the WritableFileWriter
+    // on top of it. Logs are small appends that are synced often, which the
+    // write() based file handles better than a mapping or direct I/O.
+    pub fn optimize_for_log_write(&self) -> EnvOptions {
+        EnvOptions {
+            bytes_per_sync: self.wal_bytes_per_sync,
+            use_mmap_writes: false,
+            use_direct_writes: false,
+            ..self.clone()
         }
     }
 }
 
-pub trait WritableFile: Sized {
-    fn new(filename: String, reopen: bool, preallocation_block_size: usize) -> Self;
-    fn append(&mut self, data: Vec<u8>) -> State;
-    fn sync(&self) -> State;
-    fn close(&self) -> State;
-    fn flush(&self) -> State;
+pub trait WritableFile {
+    fn append(&mut self, data: Vec<u8>) -> Result<(), Error>;
+    fn sync(&self) -> Result<(), Error>;
+    fn close(&mut self) -> Result<(), Error>;
+    fn flush(&self) -> Result<(), Error>;
     fn fcntl(&self) -> bool;
-    fn truncate(&mut self, size: usize) -> State;
+    fn truncate(&mut self, size: usize) -> Result<(), Error>;
     fn get_required_buffer_alignment(&self) -> usize;
 
     #[cfg(target_os = "linux")]
-    fn range_sync(&self, offset: i64, nbytes: i64) -> State;
+    fn range_sync(&self, offset: i64, nbytes: i64) -> Result<(), Error>;
 
     #[cfg(not(target_os = "linux"))]
-    fn range_sync(&self, _offset: i64, _nbytes: i64) -> State {
-        return State::ok();
+    fn range_sync(&self, _offset: i64, _nbytes: i64) -> Result<(), Error> {
+        Ok(())
     }
 
-    fn allocate(&self, _offset: i64, _len: i64) -> State {
-        return State::ok();
+    fn allocate(&self, _offset: i64, _len: i64) -> Result<(), Error> {
+        Ok(())
     }
 
     fn prepare_write(&mut self, _offset: usize, _len: usize) {}
 
-    fn positioned_append(&mut self, _data: Vec<u8>, _offset: usize) -> State {
-        return State::not_supported();
+    // Preallocate space in blocks of size bytes ahead of the writes, so the
+    // file is laid out contiguously and writes don't have to allocate. 0
+    // turns preallocation off.
+    fn set_preallocation_block_size(&mut self, _size: usize) {}
+
+    // The preallocation block size and the number of blocks preallocated so
+    // far.
+    fn get_preallocation_status(&self) -> (usize, usize) {
+        (0, 0)
+    }
+
+    fn positioned_append(&mut self, _data: Vec<u8>, _offset: usize) -> Result<(), Error> {
+        Err(Error::NotSupported("positioned_append".to_string()))
     }
 
-    fn fsync(&self) -> State {
-        return self.sync();
+    fn fsync(&self) -> Result<(), Error> {
+        self.sync()
     }
 
     fn get_file_size(&self) -> usize {
@@ -120,11 +202,372 @@ pub trait WritableFile: Sized {
     }
 }
 
-pub trait SequentialFile<RHS = Self>: Sized {
-    fn new(filename: String, options: env::EnvOptions, ptr: &mut RHS) -> State;
-    fn skip(&self, n: i64) -> State;
-    fn read(&mut self, n: usize, result: &mut Vec<u8>, scratch: &mut Vec<u8>) -> State;
+impl<W: WritableFile + ?Sized> WritableFile for Box<W> {
+    fn append(&mut self, data: Vec<u8>) -> Result<(), Error> {
+        (**self).append(data)
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        (**self).sync()
+    }
+
+    fn close(&mut self) -> Result<(), Error> {
+        (**self).close()
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        (**self).flush()
+    }
+
+    fn fcntl(&self) -> bool {
+        (**self).fcntl()
+    }
+
+    fn truncate(&mut self, size: usize) -> Result<(), Error> {
+        (**self).truncate(size)
+    }
+
+    fn get_required_buffer_alignment(&self) -> usize {
+        (**self).get_required_buffer_alignment()
+    }
+
+    fn range_sync(&self, offset: i64, nbytes: i64) -> Result<(), Error> {
+        (**self).range_sync(offset, nbytes)
+    }
+
+    fn allocate(&self, offset: i64, len: i64) -> Result<(), Error> {
+        (**self).allocate(offset, len)
+    }
+
+    fn prepare_write(&mut self, offset: usize, len: usize) {
+        (**self).prepare_write(offset, len)
+    }
+
+    fn set_preallocation_block_size(&mut self, size: usize) {
+        (**self).set_preallocation_block_size(size)
+    }
+
+    fn get_preallocation_status(&self) -> (usize, usize) {
+        (**self).get_preallocation_status()
+    }
+
+    fn positioned_append(&mut self, data: Vec<u8>, offset: usize) -> Result<(), Error> {
+        (**self).positioned_append(data, offset)
+    }
+
+    fn fsync(&self) -> Result<(), Error> {
+        (**self).fsync()
+    }
+
+    fn get_file_size(&self) -> usize {
+        (**self).get_file_size()
+    }
+
+    fn use_direct_io(&self) -> bool {
+        (**self).use_direct_io()
+    }
+}
+
+pub trait SequentialFile: Sized {
+    // Move the position by n bytes and return how far it actually moved,
+    // which is less than n when the skip stops at the end of the file.
+    fn skip(&mut self, n: i64) -> Result<i64, Error>;
+    fn read(&mut self, n: usize, result: &mut Vec<u8>, scratch: &mut Vec<u8>) -> Result<(), Error>;
+
+    // Read up to "n" bytes starting at "offset" without moving the position
+    // used by read and skip. For direct I/O files offset and n must be
+    // multiples of get_required_buffer_alignment().
+    fn positioned_read(
+        &self,
+        _offset: u64,
+        _n: usize,
+        _result: &mut Vec<u8>,
+        _scratch: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        Err(Error::NotSupported("positioned_read".to_string()))
+    }
+
     fn use_direct_io(&self) -> bool {
         false
     }
+
+    fn get_required_buffer_alignment(&self) -> usize {
+        k_default_page_size
+    }
+}
+
+// A read request for RandomAccessFile::multi_read. On return result holds
+// the bytes read and status the outcome of this particular request.
+#[derive(Debug, Clone)]
+pub struct ReadRequest {
+    pub offset: u64,
+    pub len: usize,
+    pub result: Vec<u8>,
+    pub status: Result<(), Error>,
+}
+
+impl ReadRequest {
+    pub fn new(offset: u64, len: usize) -> ReadRequest {
+        ReadRequest {
+            offset,
+            len,
+            result: Vec::new(),
+            status: Ok(()),
+        }
+    }
+}
+
+// A file abstraction for randomly reading the contents of a file.
+pub trait RandomAccessFile {
+    // Read up to "n" bytes from the file starting at "offset".
+    // The bytes read are appended to *result. Fewer than "n" bytes are
+    // returned only at the end of the file.
+    //
+    // Safe for concurrent use by multiple threads.
+    fn read(
+        &self,
+        offset: u64,
+        n: usize,
+        result: &mut Vec<u8>,
+        scratch: &mut Vec<u8>,
+    ) -> Result<(), Error>;
+
+    // Read a bunch of blocks as described by reqs. The per request status is
+    // stored in each ReadRequest; the returned result is only an error for
+    // errors that are not specific to a request.
+    fn multi_read(&self, reqs: &mut [ReadRequest]) -> Result<(), Error> {
+        let mut scratch = Vec::new();
+        for req in reqs.iter_mut() {
+            req.result.clear();
+            req.status = self.read(req.offset, req.len, &mut req.result, &mut scratch);
+        }
+        Ok(())
+    }
+
+    // Readahead the file starting from offset by n bytes for caching.
+    fn prefetch(&self, _offset: u64, _n: usize) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn use_direct_io(&self) -> bool {
+        false
+    }
+
+    fn get_required_buffer_alignment(&self) -> usize {
+        k_default_page_size
+    }
+}
+
+impl<F: RandomAccessFile + ?Sized> RandomAccessFile for Box<F> {
+    fn read(
+        &self,
+        offset: u64,
+        n: usize,
+        result: &mut Vec<u8>,
+        scratch: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        (**self).read(offset, n, result, scratch)
+    }
+
+    fn multi_read(&self, reqs: &mut [ReadRequest]) -> Result<(), Error> {
+        (**self).multi_read(reqs)
+    }
+
+    fn prefetch(&self, offset: u64, n: usize) -> Result<(), Error> {
+        (**self).prefetch(offset, n)
+    }
+
+    fn use_direct_io(&self) -> bool {
+        (**self).use_direct_io()
+    }
+
+    fn get_required_buffer_alignment(&self) -> usize {
+        (**self).get_required_buffer_alignment()
+    }
+}
+
+// A directory opened so that changes to its entries, i.e. files created,
+// renamed or deleted in it, can be made durable. A new file survives a
+// crash only once its data is synced and its directory fsynced.
+pub trait Directory {
+    fn fsync(&self) -> Result<(), Error>;
+
+    fn close(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<D: Directory + ?Sized> Directory for Box<D> {
+    fn fsync(&self) -> Result<(), Error> {
+        (**self).fsync()
+    }
+
+    fn close(&mut self) -> Result<(), Error> {
+        (**self).close()
+    }
+}
+
+// An Env is an interface used by the cibo implementation to access
+// operating system functionality like the filesystem etc. Callers
+// may wish to provide a custom Env object when opening a database to
+// get fine gain control; e.g., to run the WAL and table code against
+// MemEnv in tests without touching the disk.
+pub trait Env {
+    type WritableFile: WritableFile;
+    type SequentialFile: SequentialFile;
+    type RandomAccessFile: RandomAccessFile;
+    type Directory: Directory;
+    type FileLock;
+
+    // Create a brand new sequentially-readable file with the specified name.
+    // If the file does not exist, returns an error.
+    fn new_sequential_file(
+        &self,
+        fname: &str,
+        options: EnvOptions,
+    ) -> Result<Self::SequentialFile, Error>;
+
+    // Create a brand new random access read-only file with the
+    // specified name.  If the file does not exist, returns an error.
+    fn new_random_access_file(
+        &self,
+        fname: &str,
+        options: EnvOptions,
+    ) -> Result<Self::RandomAccessFile, Error>;
+
+    // Create an object that writes to a new file with the specified
+    // name. Deletes any existing file with the same name and creates a
+    // new file.
+    fn new_writable_file(
+        &self,
+        fname: &str,
+        options: EnvOptions,
+    ) -> Result<Self::WritableFile, Error>;
+
+    // Create an object that writes to a file with the specified name.
+    // Keeps any existing contents and appends to the end of the file,
+    // creating it if it does not exist.
+    fn reopen_writable_file(
+        &self,
+        fname: &str,
+        options: EnvOptions,
+    ) -> Result<Self::WritableFile, Error>;
+
+    // Open the directory with the specified name, for fsyncing its entries.
+    fn new_directory(&self, name: &str) -> Result<Self::Directory, Error>;
+
+    // Returns Ok if the named file exists.
+    //         NotFound if the named file does not exist,
+    //                  the calling process does not have permission to
+    //                  determine whether this file exists, or if the path
+    //                  is invalid.
+    //         IOError if an IO Error was encountered
+    fn file_exists(&self, fname: &str) -> Result<(), Error>;
+
+    // The names of the children of the specified directory, relative to
+    // "dir".
+    fn get_children(&self, dir: &str) -> Result<Vec<String>, Error>;
+
+    // Delete the named file.
+    fn delete_file(&self, fname: &str) -> Result<(), Error>;
+
+    // Create the specified directory. Returns error if directory exists.
+    fn create_dir(&self, dirname: &str) -> Result<(), Error>;
+
+    // Creates directory if missing. Return Ok if it exists, or successful in
+    // Creating.
+    fn create_dir_if_missing(&self, dirname: &str) -> Result<(), Error>;
+
+    // Delete the specified directory.
+    fn delete_dir(&self, dirname: &str) -> Result<(), Error>;
+
+    // The size of fname.
+    fn get_file_size(&self, fname: &str) -> Result<u64, Error>;
+
+    // The last modification time of fname, in seconds since the epoch.
+    fn get_file_modification_time(&self, fname: &str) -> Result<u64, Error>;
+
+    // Rename file src to target.
+    fn rename_file(&self, src: &str, target: &str) -> Result<(), Error>;
+
+    // Lock the specified file.  Used to prevent concurrent access to
+    // the same db by multiple processes.
+    //
+    // On success, returns the acquired lock.
+    // The caller should call unlock_file(lock) to release the lock.
+    // If the process exits, the lock will be automatically released.
+    //
+    // If somebody else already holds the lock, finishes immediately
+    // with a failure.  I.e., this call does not wait for existing locks
+    // to go away.
+    //
+    // May create the named file if it does not already exist.
+    fn lock_file(&self, fname: &str) -> Result<Self::FileLock, Error>;
+
+    // Release the lock acquired by a previous successful call to lock_file.
+    fn unlock_file(&self, lock: Self::FileLock) -> Result<(), Error>;
+
+    // Returns the number of micro-seconds since some fixed point in time.
+    // It is often used as system time such as in get_file_modification_time
+    // and in the DB's own timestamps.
+    fn now_micros(&self) -> u64;
+
+    // Returns the number of nano-seconds since some fixed point in time. Only
+    // useful for computing deltas of time in one run.
+    fn now_nanos(&self) -> u64 {
+        self.now_micros() * 1000
+    }
+
+    // Sleep/delay the thread for the prescribed number of micro-seconds.
+    fn sleep_for_microseconds(&self, micros: u64);
+}
+
+// Behaviour every Env must agree on, so tests written against MemEnv hold
+// for PosixEnv too. dir must not exist yet.
+#[cfg(test)]
+fn check_env_file_semantics<E: Env>(env: &E, dir: &str) {
+    use crate::util::status::Code;
+
+    assert!(env.create_dir_if_missing(dir).is_ok());
+    let fname = format!("{}/f", dir);
+    let mut file = env
+        .new_writable_file(&fname, EnvOptions::default())
+        .unwrap();
+    assert!(file.append(b"0123456789abc".to_vec()).is_ok());
+    assert!(file.truncate(10).is_ok());
+    assert!(file.close().is_ok());
+    // a closed file can no longer be changed
+    assert_eq!(file.truncate(5).unwrap_err().code(), Code::KIOError);
+    assert_eq!(env.get_file_size(&fname).unwrap(), 10);
+
+    let mut file = env
+        .new_sequential_file(&fname, EnvOptions::default())
+        .unwrap();
+    let mut result = Vec::new();
+    let mut scratch = Vec::new();
+    assert_eq!(file.skip(2).unwrap(), 2);
+    assert!(file.read(3, &mut result, &mut scratch).is_ok());
+    assert_eq!(result, b"234".to_vec());
+    assert_eq!(file.skip(-3).unwrap(), -3);
+    // skipping before the start fails and leaves the position alone
+    let e = file.skip(-3).unwrap_err();
+    assert_eq!(e.code(), Code::KInvalidArgument);
+    result.clear();
+    assert!(file.read(2, &mut result, &mut scratch).is_ok());
+    assert_eq!(result, b"23".to_vec());
+    // skipping past the end stops at the end
+    assert_eq!(file.skip(100).unwrap(), 6);
+    result.clear();
+    assert!(file.read(1, &mut result, &mut scratch).is_ok());
+    assert!(result.is_empty());
+
+    assert!(env.delete_file(&fname).is_ok());
+    assert!(env.delete_dir(dir).is_ok());
+}
+
+#[test]
+fn test_env_file_semantics() {
+    check_env_file_semantics(&mem_env::MemEnv::new(), "/dir");
+    let dir = std::env::temp_dir().join(format!("test_env_file_semantics_{}", std::process::id()));
+    check_env_file_semantics(&env_posix::PosixEnv::new(), dir.to_str().unwrap());
 }
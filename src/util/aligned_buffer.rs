@@ -9,7 +9,7 @@ pub fn truncate_to_page_boundary(page_size: usize, s: usize) -> usize {
 }
 
 #[inline]
-fn round_up(x: usize, y: usize) -> usize {
+pub fn round_up(x: usize, y: usize) -> usize {
     return ((x + y - 1) / y) * y;
 }
 #[inline]
@@ -64,26 +64,29 @@ impl AlignedBuffer {
         }
 
         let new_capacity = round_up(requested_cacacity, self.alignment_);
-        let mut new_buf: Vec<u8> = vec![0; new_capacity + 1];
-        //let new_bufstart_offset = self.buf_.ptr().align_offset(self.alignment_);
-        //let new_bufstart;
+        // Over-allocate so that the start of the usable region can be moved
+        // up to the next alignment boundary, as O_DIRECT I/O requires.
+        let mut new_buf: Vec<u8> = vec![0; new_capacity + self.alignment_];
+        let new_bufstart_offset = new_buf.as_ptr().align_offset(self.alignment_);
+        let new_bufstart;
         unsafe {
-            //new_bufstart = self.buf_.ptr().offset(new_bufstart_offset as isize);
+            new_bufstart = new_buf.as_mut_ptr().add(new_bufstart_offset);
             if copy_data {
-                //ptr::write()
-                ptr::copy_nonoverlapping(new_buf.as_ptr(), self.bufstart_, self.cursize_);
+                if self.cursize_ > 0 {
+                    ptr::copy_nonoverlapping(self.bufstart_, new_bufstart, self.cursize_);
+                }
             } else {
                 self.cursize_ = 0;
             }
         }
 
-        self.bufstart_ = new_buf.as_mut_ptr();
+        self.bufstart_ = new_bufstart;
         self.capacity_ = new_capacity;
         self.buf_ = new_buf;
     }
 
     pub fn append(&mut self, src: Vec<u8>, append_size: usize) -> usize {
-        assert!(self.capacity_ > self.cursize_);
+        assert!(self.capacity_ >= self.cursize_);
         let buffer_remaining = self.capacity_ - self.cursize_;
         let to_copy = min(append_size, buffer_remaining);
         if to_copy > 0 {
@@ -99,27 +102,15 @@ impl AlignedBuffer {
         to_copy
     }
 
-    pub fn read(&mut self, offset: *mut u8, read_size: usize) -> Vec<u8> {
+    // Copy read_size bytes starting offset bytes into the buffer. Anything
+    // past the current size reads as zeros.
+    pub fn read(&self, offset: usize, read_size: usize) -> Vec<u8> {
         let mut result = vec![0; read_size];
-        let mut to_read = 0;
-        unsafe {
-            if offset.offset_from(self.buf_.as_ptr()) < self.cursize_ as isize {
-                to_read = min(
-                    self.cursize_ - offset.offset_from(self.buf_.as_ptr()) as usize,
-                    read_size,
-                );
-            }
-        }
-
+        let to_read = min(self.cursize_.saturating_sub(offset), read_size);
         if to_read > 0 {
-            unsafe {
-                ptr::copy_nonoverlapping(offset, result.as_mut_ptr(), to_read);
-            }
+            let start = self.bufstart_ as usize - self.buf_.as_ptr() as usize + offset;
+            result[..to_read].copy_from_slice(&self.buf_[start..start + to_read]);
         }
-        //unsafe {
-        //    self.bufstart_ = self.bufstart_.offset(read_size as isize);
-        //}
-        //self.cursize_ = self.cursize_ - read_size;
         result
     }
 
@@ -157,8 +148,8 @@ impl AlignedBuffer {
         if tail_size > 0 {
             unsafe {
                 ptr::copy(
-                    self.bufstart_,
                     self.bufstart_.offset(tail_offset as isize),
+                    self.bufstart_,
                     tail_size,
                 );
             }
@@ -223,13 +214,9 @@ fn test_aligned_buffer() {
         String::from("abc").into_bytes(),
         String::from("abc").into_bytes().len(),
     );
-    let offset;
-    unsafe {
-        offset = buf.buffer_start().offset(1);
-        let result = buf.read(offset, _appended - 1);
-        assert_eq!(result.len(), 2);
-        assert_eq!(String::from_utf8_unchecked(result), String::from("bc"));
-    }
+    let result = buf.read(1, _appended - 1);
+    assert_eq!(result.len(), 2);
+    assert_eq!(String::from_utf8(result).unwrap(), String::from("bc"));
 }
 
 #[test]
@@ -238,19 +225,13 @@ fn test_aligned_buffer2() {
     buf.alignment(4);
     buf.allocate_new_buffer(100, false);
     buf.append(vec![1, 2, 3, 4, 5, 6], vec![1, 2, 3, 4, 5, 6].len());
-    let mut offset;
-
-    offset = buf.buffer_start();
-    let result = buf.read(offset, 2);
+    let result = buf.read(0, 2);
     assert_eq!(result.len(), 2);
     assert_eq!(result, vec![1, 2]);
 
-    unsafe {
-        offset = buf.buffer_start();
-        let result = buf.read(offset.offset(2), 3);
-        assert_eq!(result.len(), 3);
-        assert_eq!(result, vec![3, 4, 5]);
-    }
+    let result = buf.read(2, 3);
+    assert_eq!(result.len(), 3);
+    assert_eq!(result, vec![3, 4, 5]);
 }
 
 #[test]
@@ -261,19 +242,63 @@ fn test_aligned_buffer3() {
     let appended = buf.append(vec![1, 2, 3, 4, 5, 6], 6);
     assert_eq!(appended, 6);
 
-    let mut offset;
-
-    offset = buf.buffer_start();
-    let result = buf.read(offset, 6);
+    let result = buf.read(0, 6);
     assert_eq!(result.len(), 6);
     assert_eq!(result, vec![1, 2, 3, 4, 5, 6]);
 
     let appended = buf.append(vec![1, 2, 3, 4, 5, 6, 7], 7);
     assert_eq!(appended, 7);
-    unsafe {
-        offset = buf.buffer_start();
-        let result = buf.read(offset.offset(6), 7);
-        assert_eq!(result.len(), 7);
-        assert_eq!(result, vec![1, 2, 3, 4, 5, 6, 7]);
+    let result = buf.read(6, 7);
+    assert_eq!(result.len(), 7);
+    assert_eq!(result, vec![1, 2, 3, 4, 5, 6, 7]);
+    // reads past the current size are zero filled
+    assert_eq!(buf.read(10, 5), vec![5, 6, 7, 0, 0]);
+    assert_eq!(buf.read(20, 2), vec![0, 0]);
+}
+
+#[test]
+fn test_aligned_buffer_start_is_aligned() {
+    for &alignment in &[512, 4096] {
+        let mut buf: AlignedBuffer = Default::default();
+        buf.alignment(alignment);
+        buf.allocate_new_buffer(3 * alignment, false);
+        assert_eq!(buf.buffer_start() as usize % alignment, 0);
+        assert_eq!(buf.get_capacity(), 3 * alignment);
+        assert_eq!(buf.append(vec![7; 10], 10), 10);
+        assert_eq!(buf.read(0, 20), {
+            let mut v = vec![7; 10];
+            v.resize(20, 0);
+            v
+        });
     }
 }
+
+#[test]
+fn test_aligned_buffer_grow_and_refit() {
+    let mut buf: AlignedBuffer = Default::default();
+    buf.alignment(512);
+    buf.allocate_new_buffer(512, false);
+    let data: Vec<u8> = (0..512).map(|i| (i % 251) as u8).collect();
+    assert_eq!(buf.append(data.clone(), 512), 512);
+    // A full buffer accepts nothing more.
+    assert_eq!(buf.append(vec![1], 1), 0);
+
+    // Growing keeps the contents.
+    buf.allocate_new_buffer(2048, true);
+    assert_eq!(buf.buffer_start() as usize % 512, 0);
+    assert_eq!(buf.get_capacity(), 2048);
+    assert_eq!(buf.get_current_size(), 512);
+    assert_eq!(buf.append(data[..100].to_vec(), 100), 100);
+    let mut expected = data.clone();
+    expected.extend_from_slice(&data[..100]);
+    assert_eq!(buf.read(0, 612), expected);
+
+    // Downsizing below the current size is ignored.
+    buf.allocate_new_buffer(512, true);
+    assert_eq!(buf.get_capacity(), 2048);
+
+    // The tail after the first 512 bytes moves to the front.
+    buf.refit_tail(512, 100);
+    assert_eq!(buf.get_current_size(), 100);
+    assert_eq!(buf.read(0, 100), data[..100].to_vec());
+}
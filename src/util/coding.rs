@@ -1,42 +1,343 @@
-use std::mem;
-use std::ptr::copy_nonoverlapping;
+// Encodings shared by the on-disk formats.
+//
+// Fixed-width integers are always stored little-endian, whatever the byte
+// order of the machine, so files can move between architectures. Varints
+// store 7 bits per byte, low bits first, with the high bit set on every
+// byte but the last. Signed varints are zigzag encoded first so small
+// negative numbers stay short.
+//
+// The put_* functions append to a Vec<u8>. The get_* functions read from
+// the front of a &[u8] cursor and advance it past what they read; they
+// return None, leaving the cursor alone, if the input is truncated or
+// malformed.
+
+pub const MAX_VARINT32_LENGTH: usize = 5;
+pub const MAX_VARINT64_LENGTH: usize = 10;
 
 pub fn encode_fixed32(value: u32) -> [u8; 4] {
-    if cfg!(target_endian = "little") {
-        unsafe { mem::transmute(value.to_le()) }
-    } else {
-        unsafe { mem::transmute(value.to_be()) }
-    }
+    value.to_le_bytes()
 }
 
 pub fn encode_fixed64(value: u64) -> [u8; 8] {
-    if cfg!(target_endian = "little") {
-        unsafe { mem::transmute(value.to_le()) }
-    } else {
-        unsafe { mem::transmute(value.to_be()) }
-    }
+    value.to_le_bytes()
 }
 
 pub fn decode_fixed32(value: [u8; 4]) -> u32 {
-    let mut result: u32 = 0;
-    unsafe {
-        copy_nonoverlapping(value.as_ptr(), &mut result as *mut u32 as *mut u8, 4);
+    u32::from_le_bytes(value)
+}
+
+pub fn decode_fixed64(value: [u8; 8]) -> u64 {
+    u64::from_le_bytes(value)
+}
+
+pub fn put_fixed32(dst: &mut Vec<u8>, value: u32) {
+    dst.extend_from_slice(&encode_fixed32(value));
+}
+
+pub fn put_fixed64(dst: &mut Vec<u8>, value: u64) {
+    dst.extend_from_slice(&encode_fixed64(value));
+}
+
+pub fn get_fixed32(input: &mut &[u8]) -> Option<u32> {
+    if input.len() < 4 {
+        return None;
     }
-    if cfg!(target_endian = "little") {
-        result.to_le()
-    } else {
-        result.to_be()
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&input[..4]);
+    *input = &input[4..];
+    Some(decode_fixed32(buf))
+}
+
+pub fn get_fixed64(input: &mut &[u8]) -> Option<u64> {
+    if input.len() < 8 {
+        return None;
     }
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&input[..8]);
+    *input = &input[8..];
+    Some(decode_fixed64(buf))
 }
 
-pub fn decode_fixed64(value: [u8; 8]) -> u64 {
-    let mut result: u64 = 0;
-    unsafe {
-        copy_nonoverlapping(value.as_ptr(), &mut result as *mut u64 as *mut u8, 8);
-    }
-    if cfg!(target_endian = "little") {
-        result.to_le()
-    } else {
-        result.to_be()
+// Number of bytes the varint encoding of value takes.
+pub fn varint_length(mut value: u64) -> usize {
+    let mut len = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        len += 1;
+    }
+    len
+}
+
+pub fn put_varint32(dst: &mut Vec<u8>, value: u32) {
+    put_varint64(dst, u64::from(value));
+}
+
+pub fn put_varint64(dst: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        dst.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    dst.push(value as u8);
+}
+
+// Decode a varint of at most max_len bytes from the front of input and
+// return it with the number of bytes it took.
+fn decode_varint(input: &[u8], max_len: usize) -> Option<(u64, usize)> {
+    let mut result = 0u64;
+    for (i, &byte) in input.iter().take(max_len).enumerate() {
+        result |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((result, i + 1));
+        }
+    }
+    None
+}
+
+pub fn get_varint32(input: &mut &[u8]) -> Option<u32> {
+    let (value, len) = decode_varint(input, MAX_VARINT32_LENGTH)?;
+    if value > u64::from(u32::MAX) {
+        return None;
+    }
+    *input = &input[len..];
+    Some(value as u32)
+}
+
+pub fn get_varint64(input: &mut &[u8]) -> Option<u64> {
+    let (value, len) = decode_varint(input, MAX_VARINT64_LENGTH)?;
+    // The tenth byte holds only the top bit.
+    if len == MAX_VARINT64_LENGTH && input[len - 1] > 1 {
+        return None;
+    }
+    *input = &input[len..];
+    Some(value)
+}
+
+// Map signed to unsigned so that values near zero, of either sign, get
+// small codes: 0, -1, 1, -2, ... become 0, 1, 2, 3, ...
+pub fn zigzag_encode(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+pub fn zigzag_decode(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+pub fn put_varsigned64(dst: &mut Vec<u8>, value: i64) {
+    put_varint64(dst, zigzag_encode(value));
+}
+
+pub fn get_varsigned64(input: &mut &[u8]) -> Option<i64> {
+    get_varint64(input).map(zigzag_decode)
+}
+
+// value preceded by its length as a varint32.
+pub fn put_length_prefixed_slice(dst: &mut Vec<u8>, value: &[u8]) {
+    assert!(value.len() <= u32::MAX as usize);
+    put_varint32(dst, value.len() as u32);
+    dst.extend_from_slice(value);
+}
+
+pub fn get_length_prefixed_slice<'a>(input: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let mut cursor = *input;
+    let len = get_varint32(&mut cursor)? as usize;
+    if cursor.len() < len {
+        return None;
+    }
+    let (value, rest) = cursor.split_at(len);
+    *input = rest;
+    Some(value)
+}
+
+#[test]
+fn test_fixed_encodings() {
+    // The byte order doesn't depend on the machine.
+    assert_eq!(encode_fixed32(0x0403_0201), [1, 2, 3, 4]);
+    assert_eq!(
+        encode_fixed64(0x0807_0605_0403_0201),
+        [1, 2, 3, 4, 5, 6, 7, 8]
+    );
+    assert_eq!(decode_fixed32([1, 2, 3, 4]), 0x0403_0201);
+
+    let mut dst = Vec::new();
+    for v in 0..100000u32 {
+        put_fixed32(&mut dst, v);
+        put_fixed64(&mut dst, u64::from(v) << 32 | u64::from(v));
+    }
+    let mut input = &dst[..];
+    for v in 0..100000u32 {
+        assert_eq!(get_fixed32(&mut input), Some(v));
+        assert_eq!(
+            get_fixed64(&mut input),
+            Some(u64::from(v) << 32 | u64::from(v))
+        );
+    }
+    assert!(input.is_empty());
+    assert_eq!(get_fixed32(&mut &[1u8, 2, 3][..]), None);
+    assert_eq!(get_fixed64(&mut &[0u8; 7][..]), None);
+}
+
+#[test]
+fn test_varint_encodings() {
+    let mut dst = Vec::new();
+    put_varint32(&mut dst, 300);
+    assert_eq!(dst, vec![0xac, 0x02]);
+
+    let values: Vec<u64> = (0..64)
+        .flat_map(|shift| {
+            let v = 1u64 << shift;
+            vec![v - 1, v, v + 1]
+        })
+        .chain(vec![u64::MAX])
+        .collect();
+    let mut dst = Vec::new();
+    for &v in &values {
+        put_varint64(&mut dst, v);
+        put_varint32(&mut dst, v as u32);
+    }
+    let mut input = &dst[..];
+    for &v in &values {
+        let before = input.len();
+        assert_eq!(get_varint64(&mut input), Some(v));
+        assert_eq!(before - input.len(), varint_length(v));
+        assert_eq!(get_varint32(&mut input), Some(v as u32));
+    }
+    assert!(input.is_empty());
+    assert_eq!(varint_length(u64::MAX), MAX_VARINT64_LENGTH);
+    assert_eq!(varint_length(u64::from(u32::MAX)), MAX_VARINT32_LENGTH);
+
+    // Truncated and overlong input is rejected and leaves the cursor alone.
+    let mut dst = Vec::new();
+    put_varint64(&mut dst, u64::MAX);
+    for len in 0..dst.len() {
+        let mut input = &dst[..len];
+        assert_eq!(get_varint64(&mut input), None);
+        assert_eq!(input.len(), len);
+    }
+    let mut input = &dst[..];
+    assert_eq!(get_varint32(&mut input), None);
+    assert_eq!(input.len(), dst.len());
+    let mut overflow = vec![0xff; 9];
+    overflow.push(0x02);
+    assert_eq!(get_varint64(&mut &overflow[..]), None);
+    assert_eq!(get_varint32(&mut &[0xff, 0xff, 0xff, 0xff, 0x10][..]), None);
+
+    for &(v, code) in &[
+        (0i64, 0u64),
+        (-1, 1),
+        (1, 2),
+        (-2, 3),
+        (i64::MAX, u64::MAX - 1),
+        (i64::MIN, u64::MAX),
+    ] {
+        assert_eq!(zigzag_encode(v), code);
+        assert_eq!(zigzag_decode(code), v);
+    }
+}
+
+#[test]
+fn test_length_prefixed_slice() {
+    let mut dst = Vec::new();
+    put_length_prefixed_slice(&mut dst, b"");
+    put_length_prefixed_slice(&mut dst, b"foo");
+    put_length_prefixed_slice(&mut dst, &[7u8; 200]);
+    let mut input = &dst[..];
+    assert_eq!(get_length_prefixed_slice(&mut input), Some(&b""[..]));
+    assert_eq!(get_length_prefixed_slice(&mut input), Some(&b"foo"[..]));
+    assert_eq!(get_length_prefixed_slice(&mut input), Some(&[7u8; 200][..]));
+    assert!(input.is_empty());
+
+    // The length says more bytes follow than there are.
+    let mut input = &dst[1..4];
+    assert_eq!(get_length_prefixed_slice(&mut input), None);
+    assert_eq!(input.len(), 3);
+}
+
+#[test]
+fn test_coding_fuzz() {
+    use rand::{Rng, SeedableRng, StdRng};
+
+    // Random values of random widths, encoded with a random mix of the
+    // encodings, decode back to the same values.
+    #[derive(Debug, PartialEq)]
+    enum Value {
+        Fixed32(u32),
+        Fixed64(u64),
+        Varint32(u32),
+        Varint64(u64),
+        Varsigned64(i64),
+        Slice(Vec<u8>),
+    }
+
+    for seed in 0..20usize {
+        let mut rng: StdRng = SeedableRng::from_seed(&[seed][..]);
+        let mut values = Vec::new();
+        let mut dst = Vec::new();
+        for _ in 0..1000 {
+            let bits = rng.gen_range(0, 65);
+            let raw = if bits == 0 {
+                0
+            } else {
+                rng.gen::<u64>() >> (64 - bits)
+            };
+            let value = match rng.gen_range(0, 6) {
+                0 => Value::Fixed32(raw as u32),
+                1 => Value::Fixed64(raw),
+                2 => Value::Varint32(raw as u32),
+                3 => Value::Varint64(raw),
+                4 => Value::Varsigned64(if rng.gen() {
+                    raw as i64
+                } else {
+                    (raw as i64).wrapping_neg()
+                }),
+                _ => {
+                    let len = rng.gen_range(0, 300);
+                    Value::Slice((0..len).map(|_| rng.gen()).collect())
+                }
+            };
+            match value {
+                Value::Fixed32(v) => put_fixed32(&mut dst, v),
+                Value::Fixed64(v) => put_fixed64(&mut dst, v),
+                Value::Varint32(v) => put_varint32(&mut dst, v),
+                Value::Varint64(v) => put_varint64(&mut dst, v),
+                Value::Varsigned64(v) => put_varsigned64(&mut dst, v),
+                Value::Slice(ref v) => put_length_prefixed_slice(&mut dst, v),
+            }
+            values.push(value);
+        }
+
+        let mut input = &dst[..];
+        for value in &values {
+            let decoded = match *value {
+                Value::Fixed32(_) => get_fixed32(&mut input).map(Value::Fixed32),
+                Value::Fixed64(_) => get_fixed64(&mut input).map(Value::Fixed64),
+                Value::Varint32(_) => get_varint32(&mut input).map(Value::Varint32),
+                Value::Varint64(_) => get_varint64(&mut input).map(Value::Varint64),
+                Value::Varsigned64(_) => get_varsigned64(&mut input).map(Value::Varsigned64),
+                Value::Slice(_) => {
+                    get_length_prefixed_slice(&mut input).map(|s| Value::Slice(s.to_vec()))
+                }
+            };
+            assert_eq!(decoded.as_ref(), Some(value));
+        }
+        assert!(input.is_empty());
+
+        // Decoding random bytes never panics and never reads past the end.
+        let garbage: Vec<u8> = (0..rng.gen_range(0, 64)).map(|_| rng.gen()).collect();
+        let mut input = &garbage[..];
+        while !input.is_empty() {
+            let before = input.len();
+            let decoded = match rng.gen_range(0, 4) {
+                0 => get_varint32(&mut input).is_some(),
+                1 => get_varint64(&mut input).is_some(),
+                2 => get_varsigned64(&mut input).is_some(),
+                _ => get_length_prefixed_slice(&mut input).is_some(),
+            };
+            if decoded {
+                assert!(input.len() < before);
+            } else {
+                assert_eq!(input.len(), before);
+                input = &input[1..];
+            }
+        }
     }
 }
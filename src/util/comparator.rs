@@ -0,0 +1,231 @@
+use crate::util::status::Error;
+use std::cmp::{min, Ordering};
+
+// A Comparator object provides a total order across slices that are
+// used as keys in an sstable or a database. A Comparator implementation
+// must be thread-safe since it may be invoked concurrently from multiple
+// threads.
+pub trait Comparator: Send + Sync {
+    // The name of the comparator. Used to check for comparator
+    // mismatches (i.e., a DB created with one comparator is
+    // accessed using a different comparator).
+    //
+    // The client of this package should switch to a new name whenever
+    // the comparator implementation changes in a way that will cause
+    // the relative ordering of any two keys to change.
+    fn name(&self) -> &'static str;
+
+    // Three-way comparison.
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+
+    fn equal(&self, a: &[u8], b: &[u8]) -> bool {
+        self.compare(a, b) == Ordering::Equal
+    }
+
+    // If *start < limit, changes *start to a short string in [start,limit).
+    // Simple comparator implementations may return with *start unchanged,
+    // i.e., an implementation of this method that does nothing is correct.
+    fn find_shortest_separator(&self, start: &mut Vec<u8>, limit: &[u8]);
+
+    // Changes *key to a short string >= *key.
+    // Simple comparator implementations may return with *key unchanged,
+    // i.e., an implementation of this method that does nothing is correct.
+    fn find_short_successor(&self, key: &mut Vec<u8>);
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BytewiseComparator;
+
+impl Comparator for BytewiseComparator {
+    fn name(&self) -> &'static str {
+        "leveldb.BytewiseComparator"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+
+    fn find_shortest_separator(&self, start: &mut Vec<u8>, limit: &[u8]) {
+        // Find length of common prefix
+        let min_length = min(start.len(), limit.len());
+        let mut diff_index = 0;
+        while diff_index < min_length && start[diff_index] == limit[diff_index] {
+            diff_index += 1;
+        }
+
+        if diff_index >= min_length {
+            // Do not shorten if one string is a prefix of the other
+            return;
+        }
+
+        let start_byte = start[diff_index];
+        let limit_byte = limit[diff_index];
+        if start_byte >= limit_byte {
+            // Cannot shorten since limit is smaller than start or start is
+            // already the shortest possible.
+            return;
+        }
+
+        if diff_index < limit.len() - 1 || start_byte + 1 < limit_byte {
+            start[diff_index] += 1;
+            start.truncate(diff_index + 1);
+        } else {
+            //     v
+            // A A 1 A A A
+            // A A 2
+            //
+            // Incrementing the current byte will make start bigger than limit, we
+            // will skip this byte, and find the first non 0xFF byte in start and
+            // increment it.
+            diff_index += 1;
+            while diff_index < start.len() {
+                // Keep moving until we find the first non 0xFF byte to
+                // increment it
+                if start[diff_index] < 0xff {
+                    start[diff_index] += 1;
+                    start.truncate(diff_index + 1);
+                    break;
+                }
+                diff_index += 1;
+            }
+        }
+        assert!(self.compare(start, limit) == Ordering::Less);
+    }
+
+    fn find_short_successor(&self, key: &mut Vec<u8>) {
+        // Find first character that can be incremented
+        let n = key.len();
+        for i in 0..n {
+            if key[i] != 0xff {
+                key[i] += 1;
+                key.truncate(i + 1);
+                return;
+            }
+        }
+        // *key is a run of 0xffs.  Leave it alone.
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ReverseBytewiseComparator;
+
+impl Comparator for ReverseBytewiseComparator {
+    fn name(&self) -> &'static str {
+        "rocksdb.ReverseBytewiseComparator"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        b.cmp(a)
+    }
+
+    fn find_shortest_separator(&self, start: &mut Vec<u8>, limit: &[u8]) {
+        // Find length of common prefix
+        let min_length = min(start.len(), limit.len());
+        let mut diff_index = 0;
+        while diff_index < min_length && start[diff_index] == limit[diff_index] {
+            diff_index += 1;
+        }
+
+        assert!(diff_index <= min_length);
+        if diff_index == min_length {
+            // Do not shorten if one string is a prefix of the other
+            //
+            // We could handle cases like:
+            //     V
+            // A A 2 X Y
+            // A A 2
+            // in a similar way as BytewiseComparator::find_shortest_separator().
+            // We keep it simple by not implementing it. We can come back to it
+            // later when needed.
+        } else {
+            let start_byte = start[diff_index];
+            let limit_byte = limit[diff_index];
+            if start_byte > limit_byte && diff_index < start.len() - 1 {
+                // Case like
+                //     V
+                // A A 3 A A
+                // A A 1 B B
+                //
+                // or
+                //     v
+                // A A 2 A A
+                // A A 1 B B
+                // In this case "AA2" will be good.
+                start.truncate(diff_index + 1);
+                assert!(self.compare(start, limit) == Ordering::Less);
+            }
+        }
+    }
+
+    fn find_short_successor(&self, _key: &mut Vec<u8>) {
+        // Don't do anything for simplicity.
+    }
+}
+
+// Checks the comparator a DB is opened with against the name recorded in
+// its descriptor when it was created.
+pub fn check_comparator_name(cmp: &dyn Comparator, recorded: &str) -> Result<(), Error> {
+    if cmp.name() != recorded {
+        return Err(Error::InvalidArgument(format!(
+            "{} does not match existing comparator {}",
+            cmp.name(),
+            recorded
+        )));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_bytewise_comparator() {
+    let cmp = BytewiseComparator;
+    assert_eq!(cmp.compare(b"abc", b"abd"), Ordering::Less);
+    assert_eq!(cmp.compare(b"abc", b"ab"), Ordering::Greater);
+    assert!(cmp.equal(b"abc", b"abc"));
+
+    let mut start = b"abcdefg".to_vec();
+    cmp.find_shortest_separator(&mut start, b"abzzz");
+    assert_eq!(start, b"abd".to_vec());
+
+    // prefix of limit, left unchanged
+    let mut start = b"abc".to_vec();
+    cmp.find_shortest_separator(&mut start, b"abcd");
+    assert_eq!(start, b"abc".to_vec());
+
+    // incrementing the differing byte would pass limit
+    let mut start = vec![b'a', b'a', 1, b'a', 0xff, b'a'];
+    cmp.find_shortest_separator(&mut start, &[b'a', b'a', 2]);
+    assert_eq!(start, vec![b'a', b'a', 1, b'b']);
+
+    let mut key = vec![0xff, 0xff, b'a', b'b'];
+    cmp.find_short_successor(&mut key);
+    assert_eq!(key, vec![0xff, 0xff, b'b']);
+
+    let mut key = vec![0xff, 0xff];
+    cmp.find_short_successor(&mut key);
+    assert_eq!(key, vec![0xff, 0xff]);
+}
+
+#[test]
+fn test_reverse_bytewise_comparator() {
+    let cmp = ReverseBytewiseComparator;
+    assert_eq!(cmp.compare(b"abc", b"abd"), Ordering::Greater);
+    assert_eq!(cmp.compare(b"ab", b"abc"), Ordering::Greater);
+
+    let mut start = b"aa3aa".to_vec();
+    cmp.find_shortest_separator(&mut start, b"aa1bb");
+    assert_eq!(start, b"aa3".to_vec());
+
+    let mut key = b"abc".to_vec();
+    cmp.find_short_successor(&mut key);
+    assert_eq!(key, b"abc".to_vec());
+}
+
+#[test]
+fn test_check_comparator_name() {
+    use crate::util::status::Code;
+
+    assert!(check_comparator_name(&BytewiseComparator, "leveldb.BytewiseComparator").is_ok());
+    let err = check_comparator_name(&ReverseBytewiseComparator, "leveldb.BytewiseComparator")
+        .unwrap_err();
+    assert_eq!(err.code(), Code::KInvalidArgument);
+}
@@ -0,0 +1,411 @@
+// Wrappers that keep a running checksum over everything written to or read
+// from a file, so a checksum taken when a file is finalized can be checked
+// again when the whole file is read back.
+
+use crate::env::{SequentialFile, WritableFile};
+use crate::util::hash::{crc32, crc64_update};
+use crate::util::status::Error;
+use std::cmp::min;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumType {
+    Crc32,
+    Crc64,
+}
+
+impl ChecksumType {
+    // The name stored along with a checksum, so a reader knows how to
+    // recompute it.
+    pub fn name(self) -> &'static str {
+        match self {
+            ChecksumType::Crc32 => "FileChecksumCrc32",
+            ChecksumType::Crc64 => "FileChecksumCrc64",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ChecksumType> {
+        match name {
+            "FileChecksumCrc32" => Some(ChecksumType::Crc32),
+            "FileChecksumCrc64" => Some(ChecksumType::Crc64),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RunningChecksum {
+    type_: ChecksumType,
+    value_: u64,
+}
+
+impl RunningChecksum {
+    fn new(checksum_type: ChecksumType) -> RunningChecksum {
+        RunningChecksum {
+            type_: checksum_type,
+            value_: 0,
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.value_ = match self.type_ {
+            ChecksumType::Crc32 => crc32(self.value_ as u32, data) as u64,
+            ChecksumType::Crc64 => crc64_update(self.value_, data),
+        };
+    }
+}
+
+// Checksums every byte written through append and positioned_append.
+//
+// Direct I/O writers pad their last block and rewrite it at the same offset
+// on the next write, so the bytes of the latest positioned write are held
+// back until a later write starts past them or truncate cuts off the
+// padding.
+#[derive(Debug)]
+pub struct ChecksumWritableFile<W: WritableFile> {
+    file_: W,
+    checksum_: RunningChecksum,
+    // Bytes before this offset are in checksum_.
+    checksummed_: usize,
+    // The bytes from checksummed_ on that may still be rewritten.
+    pending_: Vec<u8>,
+}
+
+impl<W: WritableFile> ChecksumWritableFile<W> {
+    pub fn new(file: W, checksum_type: ChecksumType) -> ChecksumWritableFile<W> {
+        ChecksumWritableFile {
+            file_: file,
+            checksum_: RunningChecksum::new(checksum_type),
+            checksummed_: 0,
+            pending_: Vec::new(),
+        }
+    }
+
+    pub fn checksum_type(&self) -> ChecksumType {
+        self.checksum_.type_
+    }
+
+    // The checksum of the file as written so far. Once the file is closed
+    // this is the checksum of the whole file.
+    pub fn get_checksum(&self) -> u64 {
+        let mut checksum = self.checksum_;
+        checksum.update(&self.pending_);
+        checksum.value_
+    }
+
+    fn commit_to(&mut self, offset: usize) {
+        let n = min(offset - self.checksummed_, self.pending_.len());
+        self.checksum_.update(&self.pending_[..n]);
+        self.checksummed_ += n;
+        self.pending_.clear();
+    }
+}
+
+impl<W: WritableFile> WritableFile for ChecksumWritableFile<W> {
+    fn append(&mut self, data: Vec<u8>) -> Result<(), Error> {
+        let end = self.checksummed_ + self.pending_.len();
+        self.commit_to(end);
+        self.checksum_.update(&data);
+        self.checksummed_ += data.len();
+        self.file_.append(data)
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        self.file_.sync()
+    }
+
+    fn close(&mut self) -> Result<(), Error> {
+        self.file_.close()
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.file_.flush()
+    }
+
+    fn fcntl(&self) -> bool {
+        self.file_.fcntl()
+    }
+
+    fn truncate(&mut self, size: usize) -> Result<(), Error> {
+        if size < self.checksummed_ {
+            return Err(Error::NotSupported(
+                "checksum file: cannot truncate checksummed data".to_string(),
+            ));
+        }
+        self.file_.truncate(size)?;
+        self.pending_.truncate(size - self.checksummed_);
+        Ok(())
+    }
+
+    fn get_required_buffer_alignment(&self) -> usize {
+        self.file_.get_required_buffer_alignment()
+    }
+
+    fn range_sync(&self, offset: i64, nbytes: i64) -> Result<(), Error> {
+        self.file_.range_sync(offset, nbytes)
+    }
+
+    fn allocate(&self, offset: i64, len: i64) -> Result<(), Error> {
+        self.file_.allocate(offset, len)
+    }
+
+    fn prepare_write(&mut self, offset: usize, len: usize) {
+        self.file_.prepare_write(offset, len)
+    }
+
+    fn set_preallocation_block_size(&mut self, size: usize) {
+        self.file_.set_preallocation_block_size(size)
+    }
+
+    fn get_preallocation_status(&self) -> (usize, usize) {
+        self.file_.get_preallocation_status()
+    }
+
+    fn positioned_append(&mut self, data: Vec<u8>, offset: usize) -> Result<(), Error> {
+        if offset < self.checksummed_ || offset > self.checksummed_ + self.pending_.len() {
+            return Err(Error::NotSupported(format!(
+                "checksum file: cannot checksum a write at offset {}",
+                offset
+            )));
+        }
+        self.file_.positioned_append(data.clone(), offset)?;
+        self.commit_to(offset);
+        self.pending_ = data;
+        Ok(())
+    }
+
+    fn fsync(&self) -> Result<(), Error> {
+        self.file_.fsync()
+    }
+
+    fn get_file_size(&self) -> usize {
+        self.file_.get_file_size()
+    }
+
+    fn use_direct_io(&self) -> bool {
+        self.file_.use_direct_io()
+    }
+}
+
+// Checksums every byte returned by read. When an expected checksum is given
+// and the file is read from start to end without skipping, the read that
+// hits the end of the file fails with Corruption if the checksums differ.
+// That is the first read returning less than was asked for, as callers like
+// log_reader stop reading after it.
+#[derive(Debug)]
+pub struct ChecksumSequentialFile<S: SequentialFile> {
+    file_: S,
+    checksum_: RunningChecksum,
+    expected_: Option<u64>,
+    // Set once a skip makes the running checksum cover only part of the
+    // file.
+    skipped_: bool,
+}
+
+impl<S: SequentialFile> ChecksumSequentialFile<S> {
+    pub fn new(
+        file: S,
+        checksum_type: ChecksumType,
+        expected: Option<u64>,
+    ) -> ChecksumSequentialFile<S> {
+        ChecksumSequentialFile {
+            file_: file,
+            checksum_: RunningChecksum::new(checksum_type),
+            expected_: expected,
+            skipped_: false,
+        }
+    }
+
+    // The checksum of the bytes read so far.
+    pub fn get_checksum(&self) -> u64 {
+        self.checksum_.value_
+    }
+}
+
+impl<S: SequentialFile> SequentialFile for ChecksumSequentialFile<S> {
+    fn skip(&mut self, n: i64) -> Result<i64, Error> {
+        let moved = self.file_.skip(n)?;
+        if moved != 0 {
+            self.skipped_ = true;
+        }
+        Ok(moved)
+    }
+
+    fn read(&mut self, n: usize, result: &mut Vec<u8>, scratch: &mut Vec<u8>) -> Result<(), Error> {
+        let start = result.len();
+        self.file_.read(n, result, scratch)?;
+        self.checksum_.update(&result[start..]);
+        if result.len() - start < n && !self.skipped_ {
+            if let Some(expected) = self.expected_ {
+                if expected != self.checksum_.value_ {
+                    return Err(Error::Corruption(format!(
+                        "file checksum mismatch: {} expected {:#x}, got {:#x}",
+                        self.checksum_.type_.name(),
+                        expected,
+                        self.checksum_.value_
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn positioned_read(
+        &self,
+        offset: u64,
+        n: usize,
+        result: &mut Vec<u8>,
+        scratch: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        self.file_.positioned_read(offset, n, result, scratch)
+    }
+
+    fn use_direct_io(&self) -> bool {
+        self.file_.use_direct_io()
+    }
+
+    fn get_required_buffer_alignment(&self) -> usize {
+        self.file_.get_required_buffer_alignment()
+    }
+}
+
+#[test]
+fn test_checksum_files() {
+    use crate::env::mem_env::MemEnv;
+    use crate::env::{Env, EnvOptions};
+    use crate::util::file_reader_writer::{SequentialFileReader, WritableFileWriter};
+    use crate::util::hash::crc64;
+    use crate::util::status::Code;
+
+    let env = MemEnv::new();
+    let data: Vec<u8> = (0..100 * 1024u32).map(|i| (i % 251) as u8).collect();
+    for &checksum_type in &[ChecksumType::Crc32, ChecksumType::Crc64] {
+        assert_eq!(
+            ChecksumType::from_name(checksum_type.name()),
+            Some(checksum_type)
+        );
+        let expected = match checksum_type {
+            ChecksumType::Crc32 => crc32(0, &data) as u64,
+            ChecksumType::Crc64 => crc64(&data),
+        };
+
+        let file = env
+            .new_writable_file("/dir/f", EnvOptions::default())
+            .unwrap();
+        let file = ChecksumWritableFile::new(file, checksum_type);
+        let mut writer = WritableFileWriter::new(file, EnvOptions::default());
+        for chunk in data.chunks(7000) {
+            assert!(writer.append(chunk.to_vec()).is_ok());
+        }
+        assert!(writer.close().is_ok());
+        assert_eq!(writer.writable_file().get_checksum(), expected);
+
+        let read_all = |expected: Option<u64>| -> Result<(), Error> {
+            let file = env
+                .new_sequential_file("/dir/f", EnvOptions::default())
+                .unwrap();
+            let file = ChecksumSequentialFile::new(file, checksum_type, expected);
+            let mut reader = SequentialFileReader::new(file, EnvOptions::default());
+            let mut scratch = Vec::new();
+            loop {
+                let mut result = Vec::new();
+                reader.read(4096, &mut result, &mut scratch)?;
+                if result.is_empty() {
+                    return Ok(());
+                }
+            }
+        };
+        assert!(read_all(None).is_ok());
+        assert!(read_all(Some(expected)).is_ok());
+        assert_eq!(
+            read_all(Some(expected ^ 1)).unwrap_err().code(),
+            Code::KCorruption
+        );
+    }
+}
+
+#[test]
+fn test_checksum_writable_file_positioned() {
+    use crate::env::mem_env::MemEnv;
+    use crate::env::{Env, EnvOptions};
+    use crate::util::status::Code;
+
+    // The write pattern of a direct I/O writer: each write starts at the
+    // last aligned offset and pads the tail, which a later write or the
+    // final truncate replaces.
+    let env = MemEnv::new();
+    let file = env
+        .new_writable_file("/dir/f", EnvOptions::default())
+        .unwrap();
+    let mut file = ChecksumWritableFile::new(file, ChecksumType::Crc64);
+    let mut expected = vec![1u8; 600];
+    let mut block = expected.clone();
+    block.resize(1024, 0);
+    assert!(file.positioned_append(block, 0).is_ok());
+    expected.resize(1000, 2);
+    let block = expected[512..].to_vec();
+    assert!(file.positioned_append(block, 512).is_ok());
+    assert_eq!(
+        file.positioned_append(vec![0; 512], 0).unwrap_err().code(),
+        Code::KNotSupported
+    );
+    assert!(file.truncate(1000).is_ok());
+    assert!(file.close().is_ok());
+    assert_eq!(file.get_checksum(), crate::util::hash::crc64(&expected));
+}
+
+#[test]
+fn test_checksum_sequential_file_log_reader() {
+    use crate::db::log_reader::Reader;
+    use crate::db::log_writer::Writer;
+    use crate::env::mem_env::MemEnv;
+    use crate::env::{Env, EnvOptions, WALRecoveryMode};
+    use crate::util::file_reader_writer::{SequentialFileReader, WritableFileWriter};
+
+    // A WAL of three and a half blocks, so log_reader's last read is short
+    // and it never reads again. Eight records fill a block exactly.
+    let env = MemEnv::new();
+    let records: Vec<Vec<u8>> = (0..28u8).map(|i| vec![i; 4096 - 7]).collect();
+    let file = env.new_writable_file("wal", EnvOptions::default()).unwrap();
+    let file = ChecksumWritableFile::new(file, ChecksumType::Crc32);
+    let mut wal = Writer::new(
+        WritableFileWriter::new(file, EnvOptions::default()),
+        0,
+        false,
+        true,
+    );
+    for record in &records {
+        assert!(wal.add_record(record.clone()).is_ok());
+    }
+    assert!(wal.file().close().is_ok());
+    let expected = wal.file().writable_file().get_checksum();
+    drop(wal);
+
+    let read_all = |expected: u64| -> Vec<Vec<u8>> {
+        let file = env
+            .new_sequential_file("wal", EnvOptions::default())
+            .unwrap();
+        let file = ChecksumSequentialFile::new(file, ChecksumType::Crc32, Some(expected));
+        let mut reader = Reader::new(
+            SequentialFileReader::new(file, EnvOptions::default()),
+            0,
+            0,
+            true,
+        );
+        let mut result = Vec::new();
+        let mut record = Vec::new();
+        let mut scratch = Vec::new();
+        while reader.readRecord(
+            &mut record,
+            &mut scratch,
+            WALRecoveryMode::kAbsoluteConsistency,
+        ) {
+            result.push(record.clone());
+            record.clear();
+        }
+        result
+    };
+    assert_eq!(read_all(expected), records);
+    // The mismatch fails the last, short read, so the records in the last
+    // block are lost.
+    assert_eq!(read_all(expected ^ 1), records[..24].to_vec());
+}
@@ -1,12 +1,15 @@
-
 use crate::env::EnvOptions;
+use crate::env::IOPriority;
 use crate::env::{SequentialFile, WritableFile};
-use crate::util::aligned_buffer::truncate_to_page_boundary;
 use crate::util::aligned_buffer::AlignedBuffer;
-use crate::util::status::Code;
-use crate::util::status::State;
-use std::cmp::min;
-use std::sync::atomic::AtomicIsize;
+use crate::util::aligned_buffer::{round_up, truncate_to_page_boundary};
+use crate::util::rate_limiter::RateLimiter;
+use crate::util::statistics::{record_tick, Histograms, Statistics, StopWatch, Tickers};
+use crate::util::status::Error;
+use std::cmp::{max, min};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub struct WritableFileWriter<T: WritableFile> {
@@ -19,15 +22,15 @@ pub struct WritableFileWriter<T: WritableFile> {
     last_sync_size_: usize,
     #[cfg(not(feature = "CIBO_LITE"))]
     next_write_offset_: usize,
-    // uint64_t                bytes_per_sync_;
-    // RateLimiter*            rate_limiter_;
-    // Statistics* stats_;
+    rate_limiter_: Option<Arc<dyn RateLimiter>>,
+    io_priority_: IOPriority,
+    stats_: Option<Arc<Statistics>>,
 }
 
 impl<T: WritableFile> WritableFileWriter<T> {
     pub fn new(writable_file: T, options: EnvOptions) -> WritableFileWriter<T> {
         let mut buf: AlignedBuffer = Default::default();
-        buf.alignment(4 * 1024);
+        buf.alignment(writable_file.get_required_buffer_alignment());
         buf.allocate_new_buffer(65536, false);
         WritableFileWriter {
             writable_file_: writable_file,
@@ -39,13 +42,43 @@ impl<T: WritableFile> WritableFileWriter<T> {
             last_sync_size_: 0,
             #[cfg(not(feature = "CIBO_LITE"))]
             next_write_offset_: 0,
+            rate_limiter_: options.rate_limiter,
+            io_priority_: IOPriority::IOTotal,
+            stats_: options.statistics,
         }
     }
 
-    pub fn append(&mut self, slice: Vec<u8>) -> State {
-        let mut s: State = State::ok();
+    pub fn stats(&self) -> Option<&Statistics> {
+        self.stats_.as_deref()
+    }
+
+    pub fn writable_file(&self) -> &T {
+        &self.writable_file_
+    }
+
+    // Background writers (flush, compaction) set IOLow so the rate limiter
+    // keeps them from starving foreground writes.
+    pub fn set_io_priority(&mut self, pri: IOPriority) {
+        self.io_priority_ = pri;
+    }
+
+    pub fn get_io_priority(&self) -> IOPriority {
+        self.io_priority_
+    }
+
+    // Number of bytes, at most left, that may be written now, blocking on the
+    // rate limiter if there is one.
+    fn request_token(&self, left: usize, alignment: usize) -> usize {
+        match self.rate_limiter_ {
+            Some(ref rate_limiter) => {
+                rate_limiter.request_token(left, alignment, self.io_priority_)
+            }
+            None => left,
+        }
+    }
+
+    pub fn append(&mut self, slice: Vec<u8>) -> Result<(), Error> {
         let mut src = 0;
-        let _ptr = slice.as_slice();
         let mut left = slice.len();
         self.pending_sync_ = true;
         {
@@ -72,12 +105,8 @@ impl<T: WritableFile> WritableFileWriter<T> {
         if !self.writable_file_.use_direct_io()
             && (self.buf_.get_capacity() - self.buf_.get_current_size() < left)
         {
-            let s: State;
             if self.buf_.get_current_size() > 0 {
-                s = self.flush();
-                if !s.is_ok() {
-                    return s;
-                }
+                self.flush()?;
             }
             assert!(self.buf_.get_current_size() == 0);
         }
@@ -91,53 +120,37 @@ impl<T: WritableFile> WritableFileWriter<T> {
                 left -= appended;
                 src += appended;
                 if left > 0 {
-                    s = self.flush();
-                    if !s.is_ok() {
-                        break;
-                    }
+                    self.flush()?;
                 }
             }
         } else {
             assert!(self.buf_.get_current_size() == 0);
-            s = self.write_buffered(slice[src..].to_vec(), left);
+            self.write_buffered(slice[src..].to_vec(), left)?;
         }
 
-        if s.is_ok() {
-            self.filesize_ += slice.len();
-        }
-        State::ok()
+        self.filesize_ += slice.len();
+        Ok(())
     }
 
     fn get_file_size(&self) -> usize {
         return self.filesize_;
     }
 
-    pub fn flush(&mut self) -> State {
-        let mut s: State = State::new(Code::KCorruption, String::from(""), String::from(""));
+    pub fn flush(&mut self) -> Result<(), Error> {
         if self.buf_.get_current_size() > 0 {
             if self.writable_file_.use_direct_io() {
-                if cfg!(not(feature = "CIBO_LITE")) {
-                    s = self.write_direct();
-                }
+                #[cfg(not(feature = "CIBO_LITE"))]
+                self.write_direct()?;
             } else {
                 let buf_len = self.buf_.get_current_size();
-                let buf_start = self.buf_.buffer_start();
-                let read_result = self.buf_.read(buf_start, buf_len);
-                s = self.write_buffered(read_result, buf_len);
-            }
-            if !s.is_ok() {
-                return s;
+                let read_result = self.buf_.read(0, buf_len);
+                self.write_buffered(read_result, buf_len)?;
             }
         }
-        s = self.writable_file_.flush();
-        if !s.is_ok() {
-            return s;
-        }
+        self.writable_file_.flush()?;
 
-        // sync OS cache to disk for every bytes_per_sync_
-        // TODO: give log file and sst file different options (log
-        // files could be potentially cached in OS for their whole
-        // life time, thus we might not want to flush at all).
+        // sync OS cache to disk for every bytes_per_sync_. WAL files get
+        // their own value through EnvOptions::optimize_for_log_write.
 
         // We try to avoid sync to the last 1MB of data. For two reasons:
         // (1) avoid rewrite the same page that is modified later.
@@ -145,7 +158,10 @@ impl<T: WritableFile> WritableFileWriter<T> {
         //     the page.
         // Xfs does neighbor page flushing outside of the specified ranges. We
         // need to make sure sync range is far from the write offset.
-        if !self.writable_file_.use_direct_io() && self.bytes_per_sync_ > 0 {
+        // Direct I/O files are included: their data bypasses the page cache,
+        // so the call is cheap, and bytes_per_sync then behaves the same
+        // however the file was opened.
+        if self.bytes_per_sync_ > 0 {
             let k_bytes_not_sync_range: usize = 1024 * 1024;
             let k_bytes_align_when_sync: usize = 4 * 1024;
             if self.filesize_ > k_bytes_not_sync_range {
@@ -156,77 +172,84 @@ impl<T: WritableFile> WritableFileWriter<T> {
                     && offset_sync_to - self.last_sync_size_ >= self.bytes_per_sync_
                 {
                     let last_sync_size_ = self.last_sync_size_;
-                    s = self.range_sync(
+                    self.range_sync(
                         last_sync_size_ as i64,
                         (offset_sync_to - last_sync_size_) as i64,
-                    );
+                    )?;
                     self.last_sync_size_ = offset_sync_to;
                 }
             }
         }
-        s
+        Ok(())
     }
 
-    fn range_sync(&mut self, offset: i64, nbytes: i64) -> State {
+    pub fn sync(&mut self, use_fsync: bool) -> Result<(), Error> {
+        self.flush()?;
+        if self.pending_sync_ {
+            let _sw = StopWatch::new(self.stats_.as_deref(), Histograms::SyncMicros);
+            record_tick(self.stats_.as_deref(), Tickers::Syncs, 1);
+            if use_fsync {
+                self.writable_file_.fsync()?;
+            } else {
+                self.writable_file_.sync()?;
+            }
+        }
+        self.pending_sync_ = false;
+        Ok(())
+    }
+
+    fn range_sync(&mut self, offset: i64, nbytes: i64) -> Result<(), Error> {
         return self.writable_file_.range_sync(offset, nbytes);
     }
 
-    fn write_buffered(&mut self, data: Vec<u8>, size: usize) -> State {
-        let mut s: State;
-        assert!(self.writable_file_.use_direct_io());
+    fn write_buffered(&mut self, data: Vec<u8>, size: usize) -> Result<(), Error> {
+        assert!(!self.writable_file_.use_direct_io());
         let mut src = 0;
         let mut left = size;
         while left > 0 {
-            let allowed;
-
-            // if (rate_limiter_ != nullptr) {
-            // allowed = rate_limiter_->RequestToken(
-            // left, 0 /* alignment */, writable_file_->GetIOPriority(), stats_,
-            // RateLimiter::OpType::kWrite);
-            // } else {
-            allowed = left;
-            // }
-            s = self.writable_file_.append(data[src..src + left].to_vec());
-            if !s.is_ok() {
-                return s;
+            let allowed = self.request_token(left, 0);
+            {
+                let _sw = StopWatch::new(self.stats_.as_deref(), Histograms::AppendMicros);
+                self.writable_file_
+                    .append(data[src..src + allowed].to_vec())?;
             }
+            record_tick(
+                self.stats_.as_deref(),
+                Tickers::BytesWritten,
+                allowed as u64,
+            );
 
             left -= allowed;
             src += allowed;
         }
         self.buf_.size(0);
-        State::ok()
+        Ok(())
     }
 
-    pub fn close(&mut self) -> State {
-        let mut s: State = State::ok();
+    pub fn close(&mut self) -> Result<(), Error> {
         if !self.writable_file_.fcntl() {
-            s = State::new(
-                Code::KIOError,
-                "writeable_file_ has closed".to_string(),
-                "".to_string(),
-            );
-            return s;
+            return Err(Error::io_error(
+                "writeable_file_ has closed",
+                io::Error::from_raw_os_error(libc::EBADF),
+            ));
         }
 
-        //s = self.flush();
-        let mut interim: State;
+        // Keep the first error, but always close the file.
+        let mut s = self.flush();
         if self.writable_file_.use_direct_io() {
-            interim = self.writable_file_.truncate(self.filesize_);
-            if interim.is_ok() {
-                interim = self.writable_file_.sync();
-            }
-            if !interim.is_ok() && s.is_ok() {
-                s = interim;
-            }
+            let interim = self
+                .writable_file_
+                .truncate(self.filesize_)
+                .and_then(|()| self.writable_file_.sync());
+            s = s.and(interim);
         }
-        s
+        let interim = self.writable_file_.close();
+        s.and(interim)
     }
 
     #[cfg(not(feature = "CIBO_LITE"))]
-    fn write_direct(&mut self) -> State {
+    fn write_direct(&mut self) -> Result<(), Error> {
         assert!(self.writable_file_.use_direct_io());
-        let mut s: State = State::ok();
         let alignment: usize = self.buf_.get_alignment();
         assert!((self.next_write_offset_ % alignment) == 0);
         let file_advance = truncate_to_page_boundary(alignment, self.buf_.get_current_size());
@@ -234,36 +257,35 @@ impl<T: WritableFile> WritableFileWriter<T> {
         let leftover_tail = self.buf_.get_current_size() - file_advance;
         self.buf_.pad_to_aligment_with(0);
 
-        let src = self.buf_.buffer_start();
+        let mut src = 0;
         let mut write_offset = self.next_write_offset_;
         let mut left = self.buf_.get_current_size();
-        unsafe {
-            while left > 0 {
-                //rate_limiter
-                let size = left;
-                let mut write_context = vec![0; size];
-                write_context = self.buf_.read(src, size);
-
-                s = self
-                    .writable_file_
-                    .positioned_append(write_context, write_offset);
-                if !s.is_ok() {
-                    self.buf_.size(file_advance + leftover_tail);
-                    return s;
-                }
-                left -= size;
-
-                let _src = src.offset(size as isize);
+        while left > 0 {
+            // The limiter hands out whole alignment units so every chunk
+            // stays a valid direct write.
+            let size = self.request_token(left, alignment);
+            let write_context = self.buf_.read(src, size);
 
-                write_offset += size;
-                assert!((self.next_write_offset_ % alignment) == 0);
+            let s = {
+                let _sw = StopWatch::new(self.stats_.as_deref(), Histograms::AppendMicros);
+                self.writable_file_
+                    .positioned_append(write_context, write_offset)
+            };
+            if s.is_err() {
+                self.buf_.size(file_advance + leftover_tail);
+                return s;
             }
+            record_tick(self.stats_.as_deref(), Tickers::BytesWritten, size as u64);
+            left -= size;
+
+            src += size;
+
+            write_offset += size;
+            assert!(write_offset.is_multiple_of(alignment));
         }
-        if s.is_ok() {
-            self.buf_.refit_tail(file_advance, leftover_tail);
-            self.next_write_offset_ = file_advance;
-        }
-        s
+        self.buf_.refit_tail(file_advance, leftover_tail);
+        self.next_write_offset_ += file_advance;
+        Ok(())
     }
 }
 
@@ -274,25 +296,470 @@ impl<T: WritableFile> Drop for WritableFileWriter<T> {
 #[derive(Debug)]
 pub struct SequentialFileReader<T: SequentialFile> {
     file_: T,
-    offset_: AtomicIsize,
-    // uint64_t                bytes_per_sync_;
-    // RateLimiter*            rate_limiter_;
-    // Statistics* stats_;
+    offset_: AtomicU64,
+    stats_: Option<Arc<Statistics>>,
 }
 
 impl<T: SequentialFile> SequentialFileReader<T> {
-    pub fn new(file: T) -> SequentialFileReader<T> {
+    pub fn new(file: T, options: EnvOptions) -> SequentialFileReader<T> {
         SequentialFileReader {
             file_: file,
-            offset_: AtomicIsize::new(0),
+            offset_: AtomicU64::new(0),
+            stats_: options.statistics,
+        }
+    }
+
+    // Reads through a ReadaheadSequentialFile of readahead_size bytes.
+    pub fn with_readahead(
+        file: T,
+        readahead_size: usize,
+        options: EnvOptions,
+    ) -> SequentialFileReader<ReadaheadSequentialFile<T>> {
+        SequentialFileReader::new(ReadaheadSequentialFile::new(file, readahead_size), options)
+    }
+
+    pub fn stats(&self) -> Option<&Statistics> {
+        self.stats_.as_deref()
+    }
+
+    // Logical position in the file: the bytes returned by read plus the
+    // bytes skipped.
+    pub fn offset(&self) -> u64 {
+        self.offset_.load(Ordering::Relaxed)
+    }
+
+    pub fn skip(&mut self, n: i64) -> Result<(), Error> {
+        let moved = self.file_.skip(n)?;
+        let offset = self.offset().saturating_add_signed(moved);
+        self.offset_.store(offset, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn read(
+        &mut self,
+        n: usize,
+        result: &mut Vec<u8>,
+        scratch: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        let len = result.len();
+        {
+            let _sw = StopWatch::new(self.stats_.as_deref(), Histograms::ReadMicros);
+            self.file_.read(n, result, scratch)?;
+        }
+        let read = result.len().saturating_sub(len);
+        self.offset_.fetch_add(read as u64, Ordering::Relaxed);
+        record_tick(self.stats_.as_deref(), Tickers::BytesRead, read as u64);
+        Ok(())
+    }
+}
+
+// Reads ahead from the wrapped file in large chunks, a multiple of its
+// required alignment, and serves smaller reads, such as the block sized
+// reads of log_reader, from memory.
+#[derive(Debug)]
+pub struct ReadaheadSequentialFile<T: SequentialFile> {
+    file_: T,
+    readahead_size_: usize,
+    buffer_: Vec<u8>,
+    // Position in buffer_ of the next byte to return.
+    buffer_pos_: usize,
+}
+
+impl<T: SequentialFile> ReadaheadSequentialFile<T> {
+    pub fn new(file: T, readahead_size: usize) -> ReadaheadSequentialFile<T> {
+        let alignment = file.get_required_buffer_alignment();
+        ReadaheadSequentialFile {
+            file_: file,
+            readahead_size_: round_up(max(readahead_size, 1), alignment),
+            buffer_: Vec::new(),
+            buffer_pos_: 0,
+        }
+    }
+
+    fn buffered(&self) -> usize {
+        self.buffer_.len() - self.buffer_pos_
+    }
+}
+
+impl<T: SequentialFile> SequentialFile for ReadaheadSequentialFile<T> {
+    fn skip(&mut self, n: i64) -> Result<i64, Error> {
+        let buffered = self.buffered();
+        if n >= 0 && n as usize <= buffered {
+            self.buffer_pos_ += n as usize;
+            return Ok(n);
+        }
+        // The file is positioned past the buffered bytes.
+        let moved = self.file_.skip(n.saturating_sub(buffered as i64))?;
+        self.buffer_.clear();
+        self.buffer_pos_ = 0;
+        Ok(moved + buffered as i64)
+    }
+
+    fn read(&mut self, n: usize, result: &mut Vec<u8>, scratch: &mut Vec<u8>) -> Result<(), Error> {
+        let mut left = n;
+        loop {
+            let copy = min(self.buffered(), left);
+            result.extend_from_slice(&self.buffer_[self.buffer_pos_..self.buffer_pos_ + copy]);
+            self.buffer_pos_ += copy;
+            left -= copy;
+            if left == 0 {
+                return Ok(());
+            }
+
+            self.buffer_.clear();
+            self.buffer_pos_ = 0;
+            if left >= self.readahead_size_ {
+                // Nothing to gain from buffering a read this large.
+                return self.file_.read(left, result, scratch);
+            }
+            let s = self
+                .file_
+                .read(self.readahead_size_, &mut self.buffer_, scratch);
+            if s.is_err() {
+                self.buffer_.clear();
+                return s;
+            }
+            if self.buffer_.is_empty() {
+                // end of file
+                return Ok(());
+            }
+        }
+    }
+
+    fn positioned_read(
+        &self,
+        offset: u64,
+        n: usize,
+        result: &mut Vec<u8>,
+        scratch: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        self.file_.positioned_read(offset, n, result, scratch)
+    }
+
+    fn use_direct_io(&self) -> bool {
+        self.file_.use_direct_io()
+    }
+
+    fn get_required_buffer_alignment(&self) -> usize {
+        self.file_.get_required_buffer_alignment()
+    }
+}
+
+#[test]
+fn test_writable_file_writer_rate_limiter() {
+    use crate::env::mem_env::MemEnv;
+    use crate::env::Env;
+    use crate::util::rate_limiter::GenericRateLimiter;
+
+    let env = MemEnv::new();
+    // 1KB per 1ms refill period
+    let rate_limiter = Arc::new(GenericRateLimiter::new(1024 * 1000, 1000, 10));
+    let options = EnvOptions {
+        rate_limiter: Some(rate_limiter.clone()),
+        ..Default::default()
+    };
+    let data: Vec<u8> = (0..100 * 1024u32).map(|i| (i % 251) as u8).collect();
+
+    let file = env
+        .new_writable_file("/dir/unlimited", options.clone())
+        .unwrap();
+    let mut writer = WritableFileWriter::new(file, options.clone());
+    assert_eq!(writer.get_io_priority(), IOPriority::IOTotal);
+    assert!(writer.append(data.clone()).is_ok());
+    assert!(writer.close().is_ok());
+    assert_eq!(rate_limiter.get_total_requests(IOPriority::IOTotal), 0);
+
+    let file = env
+        .new_writable_file("/dir/limited", options.clone())
+        .unwrap();
+    let mut writer = WritableFileWriter::new(file, options);
+    writer.set_io_priority(IOPriority::IOLow);
+    assert!(writer.append(data.clone()).is_ok());
+    assert!(writer.close().is_ok());
+    // Written in single burst sized chunks.
+    assert_eq!(rate_limiter.get_total_requests(IOPriority::IOLow), 100);
+    assert_eq!(
+        rate_limiter.get_total_bytes_through(IOPriority::IOLow),
+        data.len() as i64
+    );
+
+    let mut file = env
+        .new_sequential_file("/dir/limited", EnvOptions::default())
+        .unwrap();
+    let mut result = Vec::new();
+    let mut scratch = Vec::new();
+    assert!(file.read(data.len(), &mut result, &mut scratch).is_ok());
+    assert!(result == data);
+}
+
+#[test]
+fn test_sequential_file_reader_readahead() {
+    use crate::env::fault_injection_env::{FaultInjectionEnv, FaultOp};
+    use crate::env::mem_env::MemEnv;
+    use crate::env::Env;
+
+    let env = FaultInjectionEnv::new(MemEnv::new());
+    let data: Vec<u8> = (0..100 * 1024u32).map(|i| (i % 251) as u8).collect();
+    let mut file = env
+        .new_writable_file("/dir/f", EnvOptions::default())
+        .unwrap();
+    assert!(file.append(data.clone()).is_ok());
+    assert!(file.close().is_ok());
+
+    let file = env
+        .new_sequential_file("/dir/f", EnvOptions::default())
+        .unwrap();
+    let mut reader = SequentialFileReader::with_readahead(file, 30000, EnvOptions::default());
+    let reads = env.call_count(FaultOp::Read);
+    let mut expected = Vec::new();
+    let mut result = Vec::new();
+    let mut scratch = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        if pos % 3 == 0 {
+            assert!(reader.skip(1000).is_ok());
+            pos += 1000;
+        } else {
+            let len = result.len();
+            assert!(reader.read(7000, &mut result, &mut scratch).is_ok());
+            expected.extend_from_slice(&data[min(pos, data.len())..min(pos + 7000, data.len())]);
+            assert_eq!(result.len() - len, min(pos + 7000, data.len()) - pos);
+            pos += 7000;
+        }
+        assert_eq!(reader.offset(), min(pos, data.len()) as u64);
+        if pos > data.len() {
+            break;
         }
     }
+    assert!(result == expected);
+    // 100KB in readahead reads of 32KB, rounded up from 30000, plus one at
+    // the end of the file.
+    assert_eq!(env.call_count(FaultOp::Read) - reads, 5);
+
+    // Reads larger than the readahead size go straight to the file.
+    let file = env
+        .new_sequential_file("/dir/f", EnvOptions::default())
+        .unwrap();
+    let mut reader = SequentialFileReader::with_readahead(file, 4096, EnvOptions::default());
+    let reads = env.call_count(FaultOp::Read);
+    let mut result = Vec::new();
+    assert!(reader.read(100, &mut result, &mut scratch).is_ok());
+    assert!(reader.read(data.len(), &mut result, &mut scratch).is_ok());
+    assert!(result == data);
+    assert_eq!(reader.offset(), data.len() as u64);
+    assert_eq!(env.call_count(FaultOp::Read) - reads, 2);
+}
 
-    pub fn skip(&self, n: i64) -> State {
-        self.file_.skip(n)
+// Writes 10000 bytes to fname and checks that skipping past the end leaves
+// the reader offset at the end of the file, with and without readahead.
+#[cfg(test)]
+fn check_skip_past_eof<E: crate::env::Env>(env: &E, fname: &str, options: EnvOptions) {
+    fn check<T: SequentialFile>(mut reader: SequentialFileReader<T>, data: &[u8]) {
+        let mut result = Vec::new();
+        let mut scratch = Vec::new();
+        assert!(reader.read(100, &mut result, &mut scratch).is_ok());
+        assert!(reader.skip(1000).is_ok());
+        assert_eq!(reader.offset(), 1100);
+        assert!(reader.skip(data.len() as i64).is_ok());
+        assert_eq!(reader.offset(), data.len() as u64);
+        assert!(reader.skip(i64::MAX).is_ok());
+        assert_eq!(reader.offset(), data.len() as u64);
+        result.clear();
+        assert!(reader.read(10, &mut result, &mut scratch).is_ok());
+        assert!(result.is_empty());
+        assert_eq!(reader.offset(), data.len() as u64);
     }
 
-    pub fn read(&mut self, n: usize, result: &mut Vec<u8>, scratch: &mut Vec<u8>) -> State {
-        self.file_.read(n, result, scratch)
+    let data: Vec<u8> = (0..10000u32).map(|i| (i % 251) as u8).collect();
+    let write_options = EnvOptions {
+        use_mmap_writes: false,
+        ..Default::default()
+    };
+    let mut file = env.new_writable_file(fname, write_options).unwrap();
+    assert!(file.append(data.clone()).is_ok());
+    assert!(file.close().is_ok());
+
+    let file = env.new_sequential_file(fname, options.clone()).unwrap();
+    check(SequentialFileReader::new(file, options.clone()), &data);
+    let file = env.new_sequential_file(fname, options.clone()).unwrap();
+    check(
+        SequentialFileReader::with_readahead(file, 4096, options),
+        &data,
+    );
+    assert!(env.delete_file(fname).is_ok());
+}
+
+#[test]
+fn test_sequential_file_reader_skip_past_eof() {
+    use crate::env::env_posix::PosixEnv;
+    use crate::env::mem_env::MemEnv;
+
+    check_skip_past_eof(&MemEnv::new(), "/dir/f", EnvOptions::default());
+
+    let path = std::env::temp_dir().join(format!("test_skip_past_eof_{}", std::process::id()));
+    let fname = path.to_str().unwrap();
+    for &use_direct_reads in &[false, true] {
+        let options = EnvOptions {
+            use_direct_reads,
+            ..Default::default()
+        };
+        check_skip_past_eof(&PosixEnv::new(), fname, options);
+    }
+}
+
+// Append random sized chunks, several MiB in total, through a
+// WritableFileWriter with random flushes and syncs, and compare the file
+// with what was written. The file goes in the temp directory so a failed
+// run doesn't leave it in the source tree.
+#[cfg(test)]
+fn check_random_writes(name: &str, options: EnvOptions, seed: usize) {
+    use crate::env::env_posix::PosixEnv;
+    use crate::env::Env;
+    use rand::{Rng, SeedableRng, StdRng};
+    use std::fs;
+
+    let path = std::env::temp_dir().join(format!("{}_{}", name, std::process::id()));
+    let fname = path.to_str().unwrap();
+    let env = PosixEnv::new();
+    // Without O_DIRECT support the file falls back to buffered writes.
+    let file = env.new_writable_file(fname, options.clone()).unwrap();
+    assert!(options.use_direct_writes || !file.use_direct_io());
+    let mut writer = WritableFileWriter::new(file, options);
+
+    let mut rng: StdRng = SeedableRng::from_seed(&[seed][..]);
+    let mut expected = Vec::new();
+    while expected.len() < 8 << 20 {
+        let len = match rng.gen_range(0, 10) {
+            0..=5 => rng.gen_range(1, 200),
+            6..=8 => rng.gen_range(1, 64 << 10),
+            _ => rng.gen_range(1, 3 << 20),
+        };
+        let mut chunk = vec![0u8; len];
+        rng.fill_bytes(&mut chunk);
+        expected.extend_from_slice(&chunk);
+        assert!(writer.append(chunk).is_ok());
+        match rng.gen_range(0, 32) {
+            0..=3 => assert!(writer.flush().is_ok()),
+            4 => assert!(writer.sync(false).is_ok()),
+            _ => {}
+        }
+    }
+    assert!(writer.close().is_ok());
+    assert_eq!(fs::metadata(fname).unwrap().len(), expected.len() as u64);
+    assert!(fs::read(fname).unwrap() == expected);
+    assert!(env.delete_file(fname).is_ok());
+}
+
+#[test]
+fn test_writable_file_writer_random_writes() {
+    for seed in 1..3 {
+        for &max_buffer_size in &[64 << 10, 1 << 20] {
+            let mut options = EnvOptions {
+                use_mmap_writes: false,
+                writable_file_max_buffer_size: max_buffer_size,
+                ..Default::default()
+            };
+            if seed == 2 {
+                options.bytes_per_sync = 256 << 10;
+                options.strict_bytes_per_sync = max_buffer_size == 1 << 20;
+            }
+            // With the io-uring feature the buffered file is io_uring backed.
+            check_random_writes("test_random_writes", options.clone(), seed);
+            options.use_mmap_writes = true;
+            check_random_writes("test_random_mmap_writes", options.clone(), seed);
+            options.use_mmap_writes = false;
+            options.use_direct_writes = true;
+            check_random_writes("test_random_direct_writes", options, seed);
+        }
+    }
+}
+
+#[test]
+fn test_writable_file_writer_bytes_per_sync() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Records the ranges the writer asks to sync.
+    struct RangeSyncFile {
+        data_: Vec<u8>,
+        use_direct_io_: bool,
+        ranges_: Rc<RefCell<Vec<(i64, i64)>>>,
+    }
+
+    impl WritableFile for RangeSyncFile {
+        fn append(&mut self, data: Vec<u8>) -> Result<(), Error> {
+            self.data_.extend_from_slice(&data);
+            Ok(())
+        }
+        fn positioned_append(&mut self, data: Vec<u8>, offset: usize) -> Result<(), Error> {
+            self.data_.resize(offset, 0);
+            self.data_.extend_from_slice(&data);
+            Ok(())
+        }
+        fn sync(&self) -> Result<(), Error> {
+            Ok(())
+        }
+        fn close(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+        fn flush(&self) -> Result<(), Error> {
+            Ok(())
+        }
+        fn fcntl(&self) -> bool {
+            true
+        }
+        fn truncate(&mut self, size: usize) -> Result<(), Error> {
+            self.data_.truncate(size);
+            Ok(())
+        }
+        fn get_required_buffer_alignment(&self) -> usize {
+            512
+        }
+        fn range_sync(&self, offset: i64, nbytes: i64) -> Result<(), Error> {
+            self.ranges_.borrow_mut().push((offset, nbytes));
+            Ok(())
+        }
+        fn use_direct_io(&self) -> bool {
+            self.use_direct_io_
+        }
+    }
+
+    let options = EnvOptions {
+        writable_file_max_buffer_size: 64 << 10,
+        bytes_per_sync: 1 << 20,
+        wal_bytes_per_sync: 512 << 10,
+        ..Default::default()
+    };
+    let wal_options = options.optimize_for_log_write();
+    assert_eq!(wal_options.bytes_per_sync, 512 << 10);
+
+    for &(use_direct_io, options, bytes_per_sync) in &[
+        (false, &options, 1 << 20),
+        (true, &options, 1 << 20),
+        (false, &wal_options, 512 << 10),
+    ] {
+        let ranges = Rc::new(RefCell::new(Vec::new()));
+        let file = RangeSyncFile {
+            data_: Vec::new(),
+            use_direct_io_: use_direct_io,
+            ranges_: ranges.clone(),
+        };
+        let mut writer = WritableFileWriter::new(file, options.clone());
+        for _ in 0..100 {
+            assert!(writer.append(vec![1; 50 << 10]).is_ok());
+            assert!(writer.flush().is_ok());
+        }
+        let ranges = ranges.borrow();
+        // The ranges follow each other from the start of the file, each at
+        // least bytes_per_sync long, and stay 1MB away from the end.
+        assert!(ranges.len() >= 3);
+        let mut next = 0;
+        for &(offset, nbytes) in ranges.iter() {
+            assert_eq!(offset, next);
+            assert!(nbytes >= bytes_per_sync);
+            next = offset + nbytes;
+        }
+        assert!(next <= (100 * (50 << 10) - (1 << 20)) as i64);
+        assert!(next > 100 * (50 << 10) - (1 << 20) - 2 * bytes_per_sync);
     }
 }
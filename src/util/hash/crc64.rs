@@ -171,7 +171,11 @@ const CRC64TAB: [u64; 256] = [
 ];
 
 pub fn crc64(s: &[u8]) -> u64 {
-    let mut crc = 0;
+    crc64_update(0, s)
+}
+
+// Continue the checksum crc of earlier data over s.
+pub fn crc64_update(mut crc: u64, s: &[u8]) -> u64 {
     for byte in s {
         crc = CRC64TAB[((crc as u8) ^ byte) as usize] ^ (crc >> 8);
     }
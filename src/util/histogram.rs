@@ -0,0 +1,208 @@
+use std::cmp::{max, min};
+use std::sync::OnceLock;
+
+// Bucket upper limits: 1, 2, then each roughly 1.5 times the previous one,
+// rounded down to two significant digits, up to u64::MAX.
+fn bucket_limits() -> &'static [u64] {
+    static LIMITS: OnceLock<Vec<u64>> = OnceLock::new();
+    LIMITS.get_or_init(|| {
+        let mut limits = vec![1, 2];
+        let mut bucket = 2u64;
+        while bucket <= u64::MAX / 3 * 2 {
+            bucket += bucket / 2;
+            let mut pow_of_ten = 1;
+            while bucket / 10 / pow_of_ten > 9 {
+                pow_of_ten *= 10;
+            }
+            bucket = bucket / pow_of_ten * pow_of_ten;
+            limits.push(bucket);
+        }
+        limits.push(u64::MAX);
+        limits
+    })
+}
+
+fn bucket_index(value: u64) -> usize {
+    bucket_limits().partition_point(|&limit| limit < value)
+}
+
+// A point in time view of a histogram.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HistogramData {
+    pub median: f64,
+    pub percentile95: f64,
+    pub percentile99: f64,
+    pub average: f64,
+    pub standard_deviation: f64,
+    pub max: u64,
+    pub min: u64,
+    pub count: u64,
+    pub sum: u64,
+}
+
+// Distribution of recorded values over exponentially sized buckets.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    min_: u64,
+    max_: u64,
+    num_: u64,
+    sum_: u64,
+    sum_squares_: u128,
+    buckets_: Vec<u64>,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram::new()
+    }
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            min_: u64::MAX,
+            max_: 0,
+            num_: 0,
+            sum_: 0,
+            sum_squares_: 0,
+            buckets_: vec![0; bucket_limits().len()],
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Histogram::new();
+    }
+
+    pub fn add(&mut self, value: u64) {
+        self.buckets_[bucket_index(value)] += 1;
+        self.min_ = min(self.min_, value);
+        self.max_ = max(self.max_, value);
+        self.num_ += 1;
+        self.sum_ = self.sum_.saturating_add(value);
+        self.sum_squares_ = self
+            .sum_squares_
+            .saturating_add(value as u128 * value as u128);
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (b, o) in self.buckets_.iter_mut().zip(other.buckets_.iter()) {
+            *b += o;
+        }
+        self.min_ = min(self.min_, other.min_);
+        self.max_ = max(self.max_, other.max_);
+        self.num_ += other.num_;
+        self.sum_ = self.sum_.saturating_add(other.sum_);
+        self.sum_squares_ = self.sum_squares_.saturating_add(other.sum_squares_);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.num_
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum_
+    }
+
+    pub fn min(&self) -> u64 {
+        if self.num_ == 0 {
+            0
+        } else {
+            self.min_
+        }
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max_
+    }
+
+    pub fn average(&self) -> f64 {
+        if self.num_ == 0 {
+            return 0.0;
+        }
+        self.sum_ as f64 / self.num_ as f64
+    }
+
+    pub fn standard_deviation(&self) -> f64 {
+        if self.num_ == 0 {
+            return 0.0;
+        }
+        let num = self.num_ as f64;
+        let variance =
+            (self.sum_squares_ as f64 * num - self.sum_ as f64 * self.sum_ as f64) / (num * num);
+        variance.max(0.0).sqrt()
+    }
+
+    // The value below which p percent of the recorded values fall,
+    // interpolated linearly within the bucket it lands in.
+    pub fn percentile(&self, p: f64) -> f64 {
+        if self.num_ == 0 {
+            return 0.0;
+        }
+        let limits = bucket_limits();
+        let threshold = self.num_ as f64 * (p / 100.0);
+        let mut cumulative = 0u64;
+        for (b, &n) in self.buckets_.iter().enumerate() {
+            cumulative += n;
+            if cumulative as f64 >= threshold && n > 0 {
+                // Scale linearly within this bucket
+                let left_point = if b == 0 { 0 } else { limits[b - 1] };
+                let right_point = limits[b];
+                let left_sum = cumulative - n;
+                let pos = (threshold - left_sum as f64) / n as f64;
+                let r = left_point as f64 + (right_point - left_point) as f64 * pos;
+                return r.max(self.min() as f64).min(self.max_ as f64);
+            }
+        }
+        self.max_ as f64
+    }
+
+    pub fn data(&self) -> HistogramData {
+        HistogramData {
+            median: self.percentile(50.0),
+            percentile95: self.percentile(95.0),
+            percentile99: self.percentile(99.0),
+            average: self.average(),
+            standard_deviation: self.standard_deviation(),
+            max: self.max(),
+            min: self.min(),
+            count: self.count(),
+            sum: self.sum(),
+        }
+    }
+}
+
+#[test]
+fn test_histogram() {
+    assert_eq!(&bucket_limits()[..8], &[1, 2, 3, 4, 6, 9, 13, 19]);
+    assert_eq!(*bucket_limits().last().unwrap(), u64::MAX);
+    assert_eq!(bucket_index(0), 0);
+    assert_eq!(bucket_index(2), 1);
+    assert_eq!(bucket_index(5), 4);
+    assert_eq!(bucket_index(u64::MAX), bucket_limits().len() - 1);
+
+    let mut h = Histogram::new();
+    assert_eq!(h.data(), HistogramData::default());
+    for i in 1..=100 {
+        h.add(i);
+    }
+    let data = h.data();
+    assert_eq!(data.count, 100);
+    assert_eq!(data.sum, 5050);
+    assert_eq!(data.min, 1);
+    assert_eq!(data.max, 100);
+    assert_eq!(data.average, 50.5);
+    assert!((data.standard_deviation - 28.866).abs() < 0.01);
+    // Bucket interpolation is only accurate to the bucket width.
+    assert!((data.median - 50.0).abs() < 5.0);
+    assert!((data.percentile99 - 99.0).abs() < 5.0);
+    assert!(data.percentile95 <= data.percentile99);
+
+    let mut other = Histogram::new();
+    other.add(1000);
+    h.merge(&other);
+    assert_eq!(h.count(), 101);
+    assert_eq!(h.max(), 1000);
+    h.clear();
+    assert_eq!(h.count(), 0);
+    assert_eq!(h.min(), 0);
+}
@@ -1,7 +1,12 @@
 pub mod aligned_buffer;
 pub mod coding;
+pub mod comparator;
+pub mod file_checksum;
 pub mod file_reader_writer;
 pub mod flock;
 pub mod hash;
+pub mod histogram;
+pub mod rate_limiter;
+pub mod statistics;
 pub mod status;
 pub mod sys;
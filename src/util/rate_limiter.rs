@@ -0,0 +1,412 @@
+use crate::env::IOPriority;
+use crate::util::aligned_buffer::truncate_to_page_boundary;
+use std::cmp::{max, min};
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+// Controls the write rate of background I/O (flush and compaction) so that
+// it cannot starve foreground writes such as the WAL.
+pub trait RateLimiter: Debug + Send + Sync {
+    // Change the rate; takes effect from the next refill period.
+    fn set_bytes_per_second(&self, bytes_per_second: i64);
+
+    // Request for token to write bytes. If this request can not be satisfied,
+    // the call is blocked. A request larger than get_single_burst_bytes() is
+    // granted over several refill periods.
+    fn request(&self, bytes: i64, pri: IOPriority);
+
+    // Max bytes that can be granted in a single burst.
+    fn get_single_burst_bytes(&self) -> i64;
+
+    // Total bytes that go through the rate limiter, for pri or, with
+    // IOPriority::IOTotal, for all priorities.
+    fn get_total_bytes_through(&self, pri: IOPriority) -> i64;
+
+    // Total number of requests that go through the rate limiter.
+    fn get_total_requests(&self, pri: IOPriority) -> i64;
+
+    fn get_bytes_per_second(&self) -> i64;
+
+    // Request for a token to write up to bytes, blocking until it is granted,
+    // and return how many bytes may be written. The grant is capped at the
+    // single burst size and, when alignment is non-zero, rounded down to a
+    // multiple of it (but never below one alignment unit, which may take
+    // more than one refill period to grant). IOTotal requests are not
+    // limited.
+    fn request_token(&self, bytes: usize, alignment: usize, pri: IOPriority) -> usize {
+        if pri == IOPriority::IOTotal {
+            return bytes;
+        }
+        let mut bytes = min(bytes, self.get_single_burst_bytes() as usize);
+        if alignment > 0 {
+            // Direct I/O can't write less than one alignment unit, even when
+            // that is more than a burst.
+            bytes = max(alignment, truncate_to_page_boundary(alignment, bytes));
+        }
+        self.request(bytes as i64, pri);
+        bytes
+    }
+}
+
+// Never grant less than this per refill period, however low the rate.
+const MIN_REFILL_BYTES_PER_PERIOD: i64 = 100;
+
+#[derive(Debug)]
+struct Req {
+    id_: u64,
+    // bytes still to be granted
+    bytes_: i64,
+}
+
+#[derive(Debug)]
+struct RateLimiterState {
+    refill_bytes_per_period_: i64,
+    available_bytes_: i64,
+    next_refill_: Instant,
+    // Requests waiting for tokens, by priority (IOLow, IOHigh).
+    queues_: [VecDeque<Req>; 2],
+    // Waiting requests that have been granted and not yet picked up.
+    granted_: Vec<u64>,
+    next_id_: u64,
+    refills_: u64,
+    total_requests_: [i64; 2],
+    total_bytes_through_: [i64; 2],
+}
+
+// Where the limiter reads the time and waits for the next refill. Tests
+// use a clock that jumps to the end of every wait instead of sleeping.
+trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn wait_timeout<'a>(
+        &self,
+        cv: &Condvar,
+        state: MutexGuard<'a, RateLimiterState>,
+        timeout: Duration,
+    ) -> MutexGuard<'a, RateLimiterState>;
+}
+
+#[derive(Debug)]
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wait_timeout<'a>(
+        &self,
+        cv: &Condvar,
+        state: MutexGuard<'a, RateLimiterState>,
+        timeout: Duration,
+    ) -> MutexGuard<'a, RateLimiterState> {
+        cv.wait_timeout(state, timeout).unwrap().0
+    }
+}
+
+// A token bucket refilled every refill period. Requests that can't be
+// served from the bucket queue up by priority; at each refill high priority
+// requests are served first, except every fairness-th refill, which serves
+// low priority first so that it is never starved completely.
+#[derive(Debug)]
+pub struct GenericRateLimiter {
+    refill_period_: Duration,
+    fairness_: u64,
+    rate_bytes_per_sec_: Mutex<i64>,
+    state_: Mutex<RateLimiterState>,
+    cv_: Condvar,
+    clock_: Arc<dyn Clock>,
+}
+
+fn calculate_refill_bytes_per_period(rate_bytes_per_sec: i64, refill_period: Duration) -> i64 {
+    max(
+        MIN_REFILL_BYTES_PER_PERIOD,
+        (rate_bytes_per_sec as i128 * refill_period.as_micros() as i128 / 1_000_000) as i64,
+    )
+}
+
+impl GenericRateLimiter {
+    // rate_bytes_per_sec: the total write rate to allow. refill_period_us:
+    // how often tokens are refilled; a shorter period smooths writes at some
+    // CPU cost. fairness: low priority requests are served first once every
+    // fairness refills.
+    pub fn new(
+        rate_bytes_per_sec: i64,
+        refill_period_us: u64,
+        fairness: u64,
+    ) -> GenericRateLimiter {
+        GenericRateLimiter::with_clock(
+            rate_bytes_per_sec,
+            refill_period_us,
+            fairness,
+            Arc::new(SystemClock),
+        )
+    }
+
+    fn with_clock(
+        rate_bytes_per_sec: i64,
+        refill_period_us: u64,
+        fairness: u64,
+        clock: Arc<dyn Clock>,
+    ) -> GenericRateLimiter {
+        assert!(rate_bytes_per_sec > 0);
+        assert!(refill_period_us > 0);
+        assert!(fairness > 0);
+        let refill_period = Duration::from_micros(refill_period_us);
+        GenericRateLimiter {
+            refill_period_: refill_period,
+            fairness_: fairness,
+            rate_bytes_per_sec_: Mutex::new(rate_bytes_per_sec),
+            state_: Mutex::new(RateLimiterState {
+                refill_bytes_per_period_: calculate_refill_bytes_per_period(
+                    rate_bytes_per_sec,
+                    refill_period,
+                ),
+                available_bytes_: 0,
+                next_refill_: clock.now(),
+                queues_: [VecDeque::new(), VecDeque::new()],
+                granted_: Vec::new(),
+                next_id_: 0,
+                refills_: 0,
+                total_requests_: [0; 2],
+                total_bytes_through_: [0; 2],
+            }),
+            cv_: Condvar::new(),
+            clock_: clock,
+        }
+    }
+
+    fn refill_bytes_and_grant_requests(&self, state: &mut RateLimiterState, now: Instant) {
+        state.next_refill_ = now + self.refill_period_;
+        let refill_bytes_per_period = state.refill_bytes_per_period_;
+        if state.available_bytes_ < refill_bytes_per_period {
+            state.available_bytes_ += refill_bytes_per_period;
+        }
+        state.refills_ += 1;
+        let order = if state.refills_.is_multiple_of(self.fairness_) {
+            [IOPriority::IOLow, IOPriority::IOHigh]
+        } else {
+            [IOPriority::IOHigh, IOPriority::IOLow]
+        };
+        for &pri in &order {
+            let pri = pri as usize;
+            while let Some(mut req) = state.queues_[pri].pop_front() {
+                if state.available_bytes_ < req.bytes_ {
+                    // Grant partially; the rest waits for the next refill,
+                    // or several if the request is larger than a burst.
+                    req.bytes_ -= state.available_bytes_;
+                    state.available_bytes_ = 0;
+                    state.queues_[pri].push_front(req);
+                    break;
+                }
+                state.available_bytes_ -= req.bytes_;
+                state.granted_.push(req.id_);
+            }
+        }
+    }
+}
+
+impl RateLimiter for GenericRateLimiter {
+    fn set_bytes_per_second(&self, bytes_per_second: i64) {
+        assert!(bytes_per_second > 0);
+        *self.rate_bytes_per_sec_.lock().unwrap() = bytes_per_second;
+        self.state_.lock().unwrap().refill_bytes_per_period_ =
+            calculate_refill_bytes_per_period(bytes_per_second, self.refill_period_);
+    }
+
+    fn request(&self, bytes: i64, pri: IOPriority) {
+        assert!(pri != IOPriority::IOTotal);
+        let mut state = self.state_.lock().unwrap();
+        let p = pri as usize;
+        state.total_requests_[p] += 1;
+        state.total_bytes_through_[p] += bytes;
+
+        let now = self.clock_.now();
+        if now >= state.next_refill_ && state.queues_[0].is_empty() && state.queues_[1].is_empty() {
+            self.refill_bytes_and_grant_requests(&mut state, now);
+        }
+        if state.available_bytes_ >= bytes && state.queues_[p].is_empty() {
+            // Fast path
+            state.available_bytes_ -= bytes;
+            return;
+        }
+
+        let id = state.next_id_;
+        state.next_id_ += 1;
+        state.queues_[p].push_back(Req {
+            id_: id,
+            bytes_: bytes,
+        });
+        loop {
+            if let Some(i) = state.granted_.iter().position(|&g| g == id) {
+                state.granted_.swap_remove(i);
+                return;
+            }
+            let now = self.clock_.now();
+            if now >= state.next_refill_ {
+                self.refill_bytes_and_grant_requests(&mut state, now);
+                self.cv_.notify_all();
+                continue;
+            }
+            let timeout = state.next_refill_ - now;
+            state = self.clock_.wait_timeout(&self.cv_, state, timeout);
+        }
+    }
+
+    fn get_single_burst_bytes(&self) -> i64 {
+        self.state_.lock().unwrap().refill_bytes_per_period_
+    }
+
+    fn get_total_bytes_through(&self, pri: IOPriority) -> i64 {
+        let state = self.state_.lock().unwrap();
+        match pri {
+            IOPriority::IOTotal => state.total_bytes_through_.iter().sum(),
+            _ => state.total_bytes_through_[pri as usize],
+        }
+    }
+
+    fn get_total_requests(&self, pri: IOPriority) -> i64 {
+        let state = self.state_.lock().unwrap();
+        match pri {
+            IOPriority::IOTotal => state.total_requests_.iter().sum(),
+            _ => state.total_requests_[pri as usize],
+        }
+    }
+
+    fn get_bytes_per_second(&self) -> i64 {
+        *self.rate_bytes_per_sec_.lock().unwrap()
+    }
+}
+
+// A clock that only moves when a request waits for a refill, and then
+// straight to the end of the wait.
+#[cfg(test)]
+#[derive(Debug)]
+struct MockClock {
+    start_: Instant,
+    elapsed_: Mutex<Duration>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    fn new() -> MockClock {
+        MockClock {
+            start_: Instant::now(),
+            elapsed_: Mutex::new(Duration::default()),
+        }
+    }
+
+    fn elapsed(&self) -> Duration {
+        *self.elapsed_.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start_ + self.elapsed()
+    }
+
+    fn wait_timeout<'a>(
+        &self,
+        _cv: &Condvar,
+        state: MutexGuard<'a, RateLimiterState>,
+        timeout: Duration,
+    ) -> MutexGuard<'a, RateLimiterState> {
+        *self.elapsed_.lock().unwrap() += timeout;
+        state
+    }
+}
+
+#[test]
+fn test_rate_limiter_rate() {
+    // 100KB/s refilled every 10ms: 1KB per period.
+    let clock = Arc::new(MockClock::new());
+    let limiter = GenericRateLimiter::with_clock(100 * 1024, 10 * 1000, 10, clock.clone());
+    assert_eq!(limiter.get_single_burst_bytes(), 1024);
+    assert_eq!(limiter.request_token(5000, 0, IOPriority::IOTotal), 5000);
+    assert_eq!(limiter.request_token(5000, 0, IOPriority::IOHigh), 1024);
+    assert_eq!(limiter.request_token(5000, 512, IOPriority::IOLow), 1024);
+    assert_eq!(limiter.request_token(700, 512, IOPriority::IOLow), 512);
+    for _ in 0..20 {
+        limiter.request(1024, IOPriority::IOLow);
+    }
+    // 22.5KB were limited. The first 1KB was there from the start, the rest
+    // took a refill per KB.
+    assert_eq!(clock.elapsed(), Duration::from_millis(220));
+    assert_eq!(limiter.get_total_requests(IOPriority::IOLow), 22);
+    assert_eq!(limiter.get_total_requests(IOPriority::IOHigh), 1);
+    assert_eq!(limiter.get_total_requests(IOPriority::IOTotal), 23);
+    assert_eq!(
+        limiter.get_total_bytes_through(IOPriority::IOTotal),
+        22 * 1024 + 512
+    );
+
+    limiter.set_bytes_per_second(200 * 1024);
+    assert_eq!(limiter.get_bytes_per_second(), 200 * 1024);
+    assert_eq!(limiter.get_single_burst_bytes(), 2048);
+    limiter.request(2048, IOPriority::IOLow);
+    assert_eq!(clock.elapsed(), Duration::from_millis(230));
+}
+
+#[test]
+fn test_rate_limiter_oversized_request() {
+    // 100KB/s refilled every 10ms: 1KB per period, less than one 4KB
+    // direct I/O unit.
+    let clock = Arc::new(MockClock::new());
+    let limiter = GenericRateLimiter::with_clock(100 * 1024, 10 * 1000, 10, clock.clone());
+    // A 4KB unit is granted 1KB per refill.
+    assert_eq!(limiter.request_token(8192, 4096, IOPriority::IOLow), 4096);
+    assert_eq!(clock.elapsed(), Duration::from_millis(40));
+    assert_eq!(limiter.request_token(100, 4096, IOPriority::IOHigh), 4096);
+    assert_eq!(clock.elapsed(), Duration::from_millis(80));
+    assert_eq!(limiter.get_total_bytes_through(IOPriority::IOTotal), 8192);
+
+    limiter.request(2500, IOPriority::IOLow);
+    assert_eq!(clock.elapsed(), Duration::from_millis(110));
+    assert_eq!(limiter.get_total_requests(IOPriority::IOTotal), 3);
+}
+
+#[test]
+fn test_rate_limiter_priority() {
+    // 1000 bytes per period, so one 1000 byte request is granted per refill.
+    // Every third refill serves low priority first.
+    let clock = Arc::new(MockClock::new());
+    let limiter = GenericRateLimiter::with_clock(100 * 1000, 10 * 1000, 3, clock.clone());
+    let mut state = limiter.state_.lock().unwrap();
+    for id in 0..8 {
+        let pri = if id < 4 {
+            IOPriority::IOLow
+        } else {
+            IOPriority::IOHigh
+        };
+        state.queues_[pri as usize].push_back(Req {
+            id_: id,
+            bytes_: if id == 5 { 2500 } else { 1000 },
+        });
+    }
+    let mut granted = Vec::new();
+    for _ in 0..10 {
+        limiter.refill_bytes_and_grant_requests(&mut state, clock.now());
+        granted.push(state.granted_.drain(..).collect::<Vec<_>>());
+    }
+    // High priority requests go first, except on every third refill. The
+    // 2500 byte request is granted over three refills, and what is left of
+    // a refill goes to the next request in line.
+    let expected: Vec<Vec<u64>> = vec![
+        vec![4],
+        vec![],
+        vec![0],
+        vec![],
+        vec![5],
+        vec![1],
+        vec![6],
+        vec![7],
+        vec![2],
+        vec![3],
+    ];
+    assert_eq!(granted, expected);
+    assert_eq!(state.available_bytes_, 500);
+}
@@ -0,0 +1,248 @@
+// Counters (tickers) and latency histograms for the storage layer. A single
+// Statistics is shared, through EnvOptions, by every reader and writer that
+// should report into it.
+
+use crate::util::histogram::{Histogram, HistogramData};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Tickers {
+    // Bytes written to files through WritableFileWriter.
+    BytesWritten = 0,
+    // Bytes read from files through SequentialFileReader.
+    BytesRead,
+    // Number of sync or fsync calls on written files.
+    Syncs,
+    // Number of records added to WAL files.
+    WalRecords,
+    // Number of times the WAL reader dropped corrupted data.
+    CorruptionDrops,
+    // Number of bytes the WAL reader dropped.
+    CorruptionDroppedBytes,
+    TickerEnumMax,
+}
+
+pub const TICKERS_NAME_MAP: &[(Tickers, &str)] = &[
+    (Tickers::BytesWritten, "cibo.bytes.written"),
+    (Tickers::BytesRead, "cibo.bytes.read"),
+    (Tickers::Syncs, "cibo.syncs"),
+    (Tickers::WalRecords, "cibo.wal.records"),
+    (Tickers::CorruptionDrops, "cibo.corruption.drops"),
+    (
+        Tickers::CorruptionDroppedBytes,
+        "cibo.corruption.dropped.bytes",
+    ),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Histograms {
+    // Time spent appending to files.
+    AppendMicros = 0,
+    // Time spent in sync or fsync.
+    SyncMicros,
+    // Time spent reading files.
+    ReadMicros,
+    HistogramEnumMax,
+}
+
+pub const HISTOGRAMS_NAME_MAP: &[(Histograms, &str)] = &[
+    (Histograms::AppendMicros, "cibo.append.micros"),
+    (Histograms::SyncMicros, "cibo.sync.micros"),
+    (Histograms::ReadMicros, "cibo.read.micros"),
+];
+
+impl Tickers {
+    pub fn name(self) -> &'static str {
+        TICKERS_NAME_MAP[self as usize].1
+    }
+}
+
+impl Histograms {
+    pub fn name(self) -> &'static str {
+        HISTOGRAMS_NAME_MAP[self as usize].1
+    }
+}
+
+#[derive(Debug)]
+pub struct Statistics {
+    tickers_: Vec<AtomicU64>,
+    histograms_: Vec<Mutex<Histogram>>,
+}
+
+impl Default for Statistics {
+    fn default() -> Statistics {
+        Statistics::new()
+    }
+}
+
+impl Statistics {
+    pub fn new() -> Statistics {
+        Statistics {
+            tickers_: (0..Tickers::TickerEnumMax as usize)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            histograms_: (0..Histograms::HistogramEnumMax as usize)
+                .map(|_| Mutex::new(Histogram::new()))
+                .collect(),
+        }
+    }
+
+    pub fn record_tick(&self, ticker: Tickers, count: u64) {
+        self.tickers_[ticker as usize].fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn set_ticker_count(&self, ticker: Tickers, count: u64) {
+        self.tickers_[ticker as usize].store(count, Ordering::Relaxed);
+    }
+
+    pub fn get_ticker_count(&self, ticker: Tickers) -> u64 {
+        self.tickers_[ticker as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn measure_time(&self, histogram: Histograms, micros: u64) {
+        self.histograms_[histogram as usize]
+            .lock()
+            .unwrap()
+            .add(micros);
+    }
+
+    pub fn histogram_data(&self, histogram: Histograms) -> HistogramData {
+        self.histograms_[histogram as usize].lock().unwrap().data()
+    }
+
+    // Zero all tickers and histograms.
+    pub fn reset(&self) {
+        for t in &self.tickers_ {
+            t.store(0, Ordering::Relaxed);
+        }
+        for h in &self.histograms_ {
+            h.lock().unwrap().clear();
+        }
+    }
+
+    pub fn snapshot(&self) -> StatisticsSnapshot {
+        StatisticsSnapshot {
+            tickers: TICKERS_NAME_MAP
+                .iter()
+                .map(|&(t, _)| (t, self.get_ticker_count(t)))
+                .collect(),
+            histograms: HISTOGRAMS_NAME_MAP
+                .iter()
+                .map(|&(h, _)| (h, self.histogram_data(h)))
+                .collect(),
+        }
+    }
+}
+
+// The values of all tickers and histograms at one point in time, in
+// TICKERS_NAME_MAP and HISTOGRAMS_NAME_MAP order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatisticsSnapshot {
+    pub tickers: Vec<(Tickers, u64)>,
+    pub histograms: Vec<(Histograms, HistogramData)>,
+}
+
+impl StatisticsSnapshot {
+    pub fn ticker(&self, ticker: Tickers) -> u64 {
+        self.tickers[ticker as usize].1
+    }
+
+    pub fn histogram(&self, histogram: Histograms) -> &HistogramData {
+        &self.histograms[histogram as usize].1
+    }
+}
+
+impl fmt::Display for StatisticsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(t, count) in &self.tickers {
+            writeln!(f, "{} COUNT : {}", t.name(), count)?;
+        }
+        for (h, data) in &self.histograms {
+            writeln!(
+                f,
+                "{} P50 : {:.6} P95 : {:.6} P99 : {:.6} P100 : {} COUNT : {} SUM : {}",
+                h.name(),
+                data.median,
+                data.percentile95,
+                data.percentile99,
+                data.max,
+                data.count,
+                data.sum
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub fn record_tick(statistics: Option<&Statistics>, ticker: Tickers, count: u64) {
+    if let Some(statistics) = statistics {
+        statistics.record_tick(ticker, count);
+    }
+}
+
+// Records the time from its creation to its drop into a histogram, if
+// there is a Statistics to record into.
+pub struct StopWatch<'a> {
+    statistics_: Option<&'a Statistics>,
+    histogram_: Histograms,
+    start_: Option<Instant>,
+}
+
+impl<'a> StopWatch<'a> {
+    pub fn new(statistics: Option<&'a Statistics>, histogram: Histograms) -> StopWatch<'a> {
+        StopWatch {
+            statistics_: statistics,
+            histogram_: histogram,
+            start_: statistics.map(|_| Instant::now()),
+        }
+    }
+}
+
+impl<'a> Drop for StopWatch<'a> {
+    fn drop(&mut self) {
+        if let (Some(statistics), Some(start)) = (self.statistics_, self.start_) {
+            statistics.measure_time(self.histogram_, start.elapsed().as_micros() as u64);
+        }
+    }
+}
+
+#[test]
+fn test_statistics() {
+    for (i, &(t, _)) in TICKERS_NAME_MAP.iter().enumerate() {
+        assert_eq!(t as usize, i);
+    }
+    for (i, &(h, _)) in HISTOGRAMS_NAME_MAP.iter().enumerate() {
+        assert_eq!(h as usize, i);
+    }
+
+    let stats = Statistics::new();
+    stats.record_tick(Tickers::BytesWritten, 100);
+    stats.record_tick(Tickers::BytesWritten, 23);
+    record_tick(Some(&stats), Tickers::Syncs, 1);
+    record_tick(None, Tickers::Syncs, 1);
+    stats.measure_time(Histograms::SyncMicros, 10);
+    stats.measure_time(Histograms::SyncMicros, 30);
+    {
+        let _sw = StopWatch::new(Some(&stats), Histograms::ReadMicros);
+    }
+
+    let snapshot = stats.snapshot();
+    assert_eq!(snapshot.ticker(Tickers::BytesWritten), 123);
+    assert_eq!(snapshot.ticker(Tickers::Syncs), 1);
+    assert_eq!(snapshot.ticker(Tickers::BytesRead), 0);
+    assert_eq!(snapshot.histogram(Histograms::SyncMicros).count, 2);
+    assert_eq!(snapshot.histogram(Histograms::SyncMicros).sum, 40);
+    assert_eq!(snapshot.histogram(Histograms::ReadMicros).count, 1);
+    let text = snapshot.to_string();
+    assert!(text.contains("cibo.bytes.written COUNT : 123\n"));
+    assert!(text.contains("cibo.sync.micros P50 : "));
+
+    stats.reset();
+    assert_eq!(stats.get_ticker_count(Tickers::BytesWritten), 0);
+    assert_eq!(stats.histogram_data(Histograms::SyncMicros).count, 0);
+    stats.set_ticker_count(Tickers::WalRecords, 7);
+    assert_eq!(stats.get_ticker_count(Tickers::WalRecords), 7);
+}
@@ -1,54 +1,189 @@
-use std::mem;
-use std::str;
+use std::error;
+use std::fmt;
+use std::io;
 
-use crate::util::coding::encode_fixed32;
-
-#[derive(Clone, Copy, Debug, PartialEq)]
+// The kind of an Error, for callers that only need to tell errors apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Code {
-    KOk = 0,
     KNotFound = 1,
     KCorruption = 2,
     KNotSupported = 3,
     KInvalidArgument = 4,
     KIOError = 5,
+    KBusy = 6,
+    KTimedOut = 7,
+    KAborted = 8,
+}
+
+// A finer reason for an error, for the cases where the code alone doesn't
+// say how to handle it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubCode {
+    None,
+    // The device or a quota is out of space. Writes may succeed again once
+    // space is freed.
+    NoSpace,
+    PathNotFound,
+}
+
+// How badly an error hurts the DB, from least to most severe. Soft errors
+// stop background work but still allow writes, hard errors make the DB read
+// only until it is resumed, and fatal and unrecoverable errors can't be
+// resumed from; an unrecoverable error may have lost data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    NoError,
+    SoftError,
+    HardError,
+    FatalError,
+    UnrecoverableError,
 }
 
-#[derive(Debug, Clone)]
-pub struct State {
-    state_: Vec<u8>,
+#[derive(Debug)]
+pub enum Error {
+    NotFound(String),
+    Corruption(String),
+    NotSupported(String),
+    InvalidArgument(String),
+    // context says what was being done, e.g. "While open a file for
+    // appending: /db/000012.log".
+    IOError { context: String, source: io::Error },
+    Busy(String),
+    TimedOut(String),
+    Aborted(String),
 }
 
-impl State {
-    pub fn new(code: Code, msg1: String, msg2: String) -> State {
-        let msg = format!("{}: {}", msg1, msg2);
-        let size = mem::size_of_val(&msg);
-        let mut state: Vec<u8> = Vec::with_capacity(size + 5);
-        state.extend(encode_fixed32(size as u32).iter().cloned());
-        state.extend([code as u8].iter().cloned());
-        state.append(&mut msg.into_bytes());
-        State { state_: state }
+impl Error {
+    pub fn io_error<S: Into<String>>(context: S, source: io::Error) -> Error {
+        Error::IOError {
+            context: context.into(),
+            source,
+        }
+    }
+
+    pub fn code(&self) -> Code {
+        match *self {
+            Error::NotFound(_) => Code::KNotFound,
+            Error::Corruption(_) => Code::KCorruption,
+            Error::NotSupported(_) => Code::KNotSupported,
+            Error::InvalidArgument(_) => Code::KInvalidArgument,
+            Error::IOError { .. } => Code::KIOError,
+            Error::Busy(_) => Code::KBusy,
+            Error::TimedOut(_) => Code::KTimedOut,
+            Error::Aborted(_) => Code::KAborted,
+        }
+    }
+
+    pub fn subcode(&self) -> SubCode {
+        match *self {
+            Error::IOError { ref source, .. } => match source.raw_os_error() {
+                Some(libc::ENOSPC) | Some(libc::EDQUOT) => SubCode::NoSpace,
+                Some(libc::ENOENT) => SubCode::PathNotFound,
+                _ => SubCode::None,
+            },
+            _ => SubCode::None,
+        }
     }
+}
 
-    pub fn ok() -> State {
-        State::new(Code::KOk, "".to_string(), "".to_string())
+// io::Error isn't Clone; the copy keeps the OS error code when there is one
+// and otherwise the kind and message.
+impl Clone for Error {
+    fn clone(&self) -> Error {
+        match *self {
+            Error::NotFound(ref msg) => Error::NotFound(msg.clone()),
+            Error::Corruption(ref msg) => Error::Corruption(msg.clone()),
+            Error::NotSupported(ref msg) => Error::NotSupported(msg.clone()),
+            Error::InvalidArgument(ref msg) => Error::InvalidArgument(msg.clone()),
+            Error::IOError {
+                ref context,
+                ref source,
+            } => Error::IOError {
+                context: context.clone(),
+                source: match source.raw_os_error() {
+                    Some(errno) => io::Error::from_raw_os_error(errno),
+                    None => io::Error::new(source.kind(), source.to_string()),
+                },
+            },
+            Error::Busy(ref msg) => Error::Busy(msg.clone()),
+            Error::TimedOut(ref msg) => Error::TimedOut(msg.clone()),
+            Error::Aborted(ref msg) => Error::Aborted(msg.clone()),
+        }
     }
+}
 
-    pub fn not_supported() -> State {
-        State::new(Code::KNotSupported, "".to_string(), "".to_string())
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NotFound(ref msg) => write!(f, "NotFound: {}", msg),
+            Error::Corruption(ref msg) => write!(f, "Corruption: {}", msg),
+            Error::NotSupported(ref msg) => write!(f, "Not implemented: {}", msg),
+            Error::InvalidArgument(ref msg) => write!(f, "Invalid argument: {}", msg),
+            Error::IOError {
+                ref context,
+                ref source,
+            } => {
+                if context.is_empty() {
+                    write!(f, "IO error: {}", source)
+                } else {
+                    write!(f, "IO error: {}: {}", context, source)
+                }
+            }
+            Error::Busy(ref msg) => write!(f, "Resource busy: {}", msg),
+            Error::TimedOut(ref msg) => write!(f, "Operation timed out: {}", msg),
+            Error::Aborted(ref msg) => write!(f, "Operation aborted: {}", msg),
+        }
     }
+}
 
-    pub fn is_ok(&self) -> bool {
-        self.state_[4] as u8 == Code::KOk as u8
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::IOError { ref source, .. } => Some(source),
+            _ => None,
+        }
     }
+}
 
-    pub fn to_string<'a>(s: &'a State) -> &'a str {
-        str::from_utf8(&s.state_[5..]).unwrap()
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::io_error("", e)
     }
 }
 
 #[test]
-fn test_state() {
-    let s = State::new(Code::KOk, String::from("a"), String::from("b"));
-    assert_eq!(true, s.is_ok());
-    assert_eq!(&String::from("a: b"), State::to_string(&s))
+fn test_error() {
+    use std::error::Error as StdError;
+
+    let e = Error::Corruption("bad block".to_string());
+    assert_eq!(e.code(), Code::KCorruption);
+    assert_eq!(e.to_string(), "Corruption: bad block");
+    assert!(e.source().is_none());
+
+    let e = Error::io_error(
+        "While open a file: /db/x",
+        io::Error::from_raw_os_error(libc::ENOENT),
+    );
+    assert_eq!(e.code(), Code::KIOError);
+    assert!(e
+        .to_string()
+        .starts_with("IO error: While open a file: /db/x: "));
+    assert_eq!(e.subcode(), SubCode::PathNotFound);
+    let source = e.source().unwrap();
+    assert!(source.is::<io::Error>());
+    match e.clone() {
+        Error::IOError { source, .. } => assert_eq!(source.raw_os_error(), Some(libc::ENOENT)),
+        _ => panic!("clone changed the variant"),
+    }
+
+    let e: Error = io::Error::other("oops").into();
+    assert_eq!(e.to_string(), "IO error: oops");
+    assert_eq!(e.clone().to_string(), "IO error: oops");
+    assert_eq!(e.subcode(), SubCode::None);
+
+    let e: Error = io::Error::from_raw_os_error(libc::ENOSPC).into();
+    assert_eq!(e.clone().subcode(), SubCode::NoSpace);
+    assert_eq!(Error::NotFound("x".to_string()).subcode(), SubCode::None);
+    assert!(Severity::SoftError < Severity::HardError);
+    assert!(Severity::FatalError < Severity::UnrecoverableError);
 }